@@ -0,0 +1,113 @@
+/*!
+A blocking API for applications that are not async.
+
+[BlockingMailer] runs the service on a Tokio runtime with its own thread.
+```no_run
+use any_mail::{blocking::BlockingMailer, smtp::SMTPService};
+# fn example(settings: any_mail::smtp::SMTPServiceSettings, email: any_mail::SimpleEmail) -> anyhow::Result<()> {
+let mailer = BlockingMailer::<SMTPService>::init(settings)?;
+let response = mailer.send_and_wait(email)?;
+mailer.shutdown();
+# Ok(())
+# }
+```
+*/
+use std::{fmt::Debug, io, sync::Arc, time::Duration};
+
+use thiserror::Error;
+use tokio::runtime::{Builder, Runtime};
+use tracing::warn;
+
+use crate::{email_types::MessageId, Email, EmailAccess, MailService, ServiceState};
+#[cfg(feature = "lettre")]
+use crate::{smtp::SMTPError, worker::Backend, EmailSettingsType, WorkerAccess};
+
+/// How long [BlockingMailer::shutdown] waits for the emails that are being sent and then for the runtime to stop
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+#[derive(Debug, Error)]
+pub enum BlockingError<E> {
+    #[error("Unable to start the runtime: {0}")]
+    Runtime(io::Error),
+    #[error(transparent)]
+    Service(E),
+}
+/// Error of the [EmailAccess] of the service
+pub type AccessError<S> = <<S as MailService>::Access as EmailAccess>::Error;
+/// Owns a runtime thread and the access to the service
+pub struct BlockingMailer<S: MailService> {
+    access: S::Access,
+    runtime: Runtime,
+}
+impl<S: MailService> Debug for BlockingMailer<S>
+where
+    S::Access: Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlockingMailer")
+            .field("access", &self.access)
+            .finish_non_exhaustive()
+    }
+}
+impl<S: MailService> BlockingMailer<S> {
+    /// Starts the runtime and the service
+    pub fn init(settings: S::Settings) -> Result<Self, BlockingError<S::Error>> {
+        Self::init_with_state(settings, ServiceState::new())
+    }
+    /// Starts the runtime and the service with the state of the application
+    pub fn init_with_state(
+        settings: S::Settings,
+        service_state: Arc<ServiceState>,
+    ) -> Result<Self, BlockingError<S::Error>> {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("any_mail")
+            .enable_all()
+            .build()
+            .map_err(BlockingError::Runtime)?;
+        let access = runtime
+            .block_on(S::init_with_state(settings, service_state))
+            .map_err(BlockingError::Service)?;
+        Ok(Self { access, runtime })
+    }
+    /// The async access. Everything that does not wait can be called on it
+    pub fn access(&self) -> &S::Access {
+        &self.access
+    }
+    /// Pushes an email to the queue. Returns the Message-ID it is sent with
+    pub fn send(&self, email: impl Email) -> Result<MessageId, AccessError<S>> {
+        let _runtime = self.runtime.enter();
+        self.access.send(email)
+    }
+    /// Stops the worker and waits for the emails that are being sent. Then stops the runtime.
+    ///
+    /// Emails still in the queue are not sent unless they are in the spool
+    pub fn shutdown(self) {
+        let Self { access, runtime } = self;
+        let service_state = access.get_app_state();
+        service_state.shutdown();
+        let joined = runtime
+            .block_on(async { tokio::time::timeout(SHUTDOWN_TIMEOUT, service_state.join()).await });
+        if joined.is_err() {
+            warn!("Timed out waiting for the emails that are being sent");
+        }
+        // The connections of the service are closed on the runtime
+        let entered = runtime.enter();
+        drop(access);
+        drop(entered);
+        runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+    }
+}
+#[cfg(feature = "lettre")]
+impl<S> BlockingMailer<S>
+where
+    S: Backend + MailService<Access = WorkerAccess<S>>,
+    S::Settings: EmailSettingsType,
+    <S as Backend>::Error: From<SMTPError> + Sync,
+{
+    /// Pushes an email to the queue and blocks until the worker delivers it.
+    ///
+    /// Returns what the service answered. Only services with a worker can wait for the delivery
+    pub fn send_and_wait(&self, email: impl Email) -> Result<S::Response, <S as Backend>::Error> {
+        self.runtime.block_on(self.access.send_and_wait(email))
+    }
+}
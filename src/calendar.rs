@@ -0,0 +1,257 @@
+/*!
+Meeting invitations as iCalendar (RFC 5545) parts. Following iTIP (RFC 5546).
+
+Set [EmailBody::calendar](crate::template::EmailBody::calendar) and the email gets a `text/calendar` alternative and an `invite.ics` attachment.
+
+To update an event send it again with the same [CalendarEvent::uid] and a higher [CalendarEvent::sequence].
+To cancel it use [Calendar::cancel] with the same uid and a higher sequence.
+*/
+use chrono::{DateTime, FixedOffset, Utc};
+use strum::Display;
+
+use crate::email_types::Mailbox;
+/// The iTIP method of the invitation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Display)]
+#[strum(serialize_all = "UPPERCASE")]
+pub enum CalendarMethod {
+    /// Only informs. No replies are expected
+    Publish,
+    /// Invites the attendees or updates the event
+    #[default]
+    Request,
+    /// Cancels the event
+    Cancel,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum AttendeeRole {
+    Chair,
+    #[default]
+    Required,
+    Optional,
+    /// Included for information only
+    NonParticipant,
+}
+impl AttendeeRole {
+    fn as_ical(&self) -> &'static str {
+        match self {
+            AttendeeRole::Chair => "CHAIR",
+            AttendeeRole::Required => "REQ-PARTICIPANT",
+            AttendeeRole::Optional => "OPT-PARTICIPANT",
+            AttendeeRole::NonParticipant => "NON-PARTICIPANT",
+        }
+    }
+}
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Attendee {
+    pub mailbox: Mailbox,
+    pub role: AttendeeRole,
+    /// Whether a reply is asked for
+    pub rsvp: bool,
+}
+impl From<Mailbox> for Attendee {
+    fn from(mailbox: Mailbox) -> Self {
+        Self {
+            mailbox,
+            role: AttendeeRole::default(),
+            rsvp: true,
+        }
+    }
+}
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CalendarEvent {
+    /// Identifies the event across updates. Such as `{id}@{your domain}`
+    pub uid: String,
+    /// Starts at 0. Increase it every time the event is changed
+    pub sequence: u32,
+    pub organizer: Mailbox,
+    pub attendees: Vec<Attendee>,
+    /// Written in UTC so no time zone definitions are needed
+    pub start: DateTime<FixedOffset>,
+    pub end: DateTime<FixedOffset>,
+    pub summary: String,
+    pub description: Option<String>,
+    pub location: Option<String>,
+}
+/// A calendar object with a single event
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Calendar {
+    pub method: CalendarMethod,
+    pub event: CalendarEvent,
+}
+impl Calendar {
+    pub fn request(event: CalendarEvent) -> Self {
+        Self {
+            method: CalendarMethod::Request,
+            event,
+        }
+    }
+    pub fn cancel(event: CalendarEvent) -> Self {
+        Self {
+            method: CalendarMethod::Cancel,
+            event,
+        }
+    }
+    pub fn publish(event: CalendarEvent) -> Self {
+        Self {
+            method: CalendarMethod::Publish,
+            event,
+        }
+    }
+    /// The iCalendar object. Lines end with CRLF and are folded at 73 octets
+    pub fn to_ics(&self) -> String {
+        self.to_ics_at(Utc::now())
+    }
+    fn to_ics_at(&self, stamp: DateTime<Utc>) -> String {
+        let event = &self.event;
+        let mut lines = vec![
+            "BEGIN:VCALENDAR".to_owned(),
+            "PRODID:-//any_mail//EN".to_owned(),
+            "VERSION:2.0".to_owned(),
+            format!("METHOD:{}", self.method),
+            "BEGIN:VEVENT".to_owned(),
+            format!("UID:{}", escape_text(&event.uid)),
+            format!("SEQUENCE:{}", event.sequence),
+            format!("DTSTAMP:{}", format_time(&stamp)),
+            format!("DTSTART:{}", format_time(&event.start.with_timezone(&Utc))),
+            format!("DTEND:{}", format_time(&event.end.with_timezone(&Utc))),
+            format!("SUMMARY:{}", escape_text(&event.summary)),
+        ];
+        if let Some(description) = &event.description {
+            lines.push(format!("DESCRIPTION:{}", escape_text(description)));
+        }
+        if let Some(location) = &event.location {
+            lines.push(format!("LOCATION:{}", escape_text(location)));
+        }
+        lines.push(format!(
+            "ORGANIZER{}:mailto:{}",
+            common_name(&event.organizer),
+            event.organizer.email
+        ));
+        for attendee in &event.attendees {
+            lines.push(format!(
+                "ATTENDEE{};ROLE={};PARTSTAT=NEEDS-ACTION;RSVP={}:mailto:{}",
+                common_name(&attendee.mailbox),
+                attendee.role.as_ical(),
+                if attendee.rsvp { "TRUE" } else { "FALSE" },
+                attendee.mailbox.email
+            ));
+        }
+        let status = match self.method {
+            CalendarMethod::Cancel => "CANCELLED",
+            _ => "CONFIRMED",
+        };
+        lines.push(format!("STATUS:{status}"));
+        lines.push("END:VEVENT".to_owned());
+        lines.push("END:VCALENDAR".to_owned());
+
+        let mut ics = String::new();
+        for line in lines {
+            fold(&mut ics, &line);
+        }
+        ics
+    }
+}
+fn format_time(time: &DateTime<Utc>) -> String {
+    time.format("%Y%m%dT%H%M%SZ").to_string()
+}
+/// `;CN="{name}"` if the mailbox has a name
+fn common_name(mailbox: &Mailbox) -> String {
+    match &mailbox.name {
+        Some(name) => format!(";CN=\"{}\"", name.replace(['"', '\r', '\n'], "")),
+        None => String::new(),
+    }
+}
+fn escape_text(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ';' => escaped.push_str("\\;"),
+            ',' => escaped.push_str("\\,"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+/// The limit is 75 octets. Two less lets the part be sent as 7bit without lettre breaking the lines up again
+const LINE_LENGTH: usize = 73;
+/// Writes the line, folding it so no line is longer than [LINE_LENGTH]
+fn fold(out: &mut String, line: &str) {
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > LINE_LENGTH {
+            out.push_str("\r\n ");
+            length = 1;
+        }
+        out.push(c);
+        length += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    fn event() -> CalendarEvent {
+        let offset = FixedOffset::east_opt(2 * 3600).unwrap();
+        CalendarEvent {
+            uid: "meeting-1@example.com".to_owned(),
+            sequence: 0,
+            organizer: Mailbox::new(
+                Some("Organizer".to_owned()),
+                "organizer@example.com".try_into().unwrap(),
+            ),
+            attendees: vec![Mailbox::try_from("user@example.com").unwrap().into()],
+            start: offset.with_ymd_and_hms(2026, 10, 20, 15, 0, 0).unwrap(),
+            end: offset.with_ymd_and_hms(2026, 10, 20, 16, 0, 0).unwrap(),
+            summary: "Planning, part 1; the long one".to_owned(),
+            description: Some("Line one\nLine two ".repeat(8)),
+            location: None,
+        }
+    }
+    #[test]
+    fn test_request() {
+        let stamp = Utc.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap();
+        let ics = Calendar::request(event()).to_ics_at(stamp);
+        assert!(ics.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert!(ics.ends_with("END:VCALENDAR\r\n"));
+        assert!(ics.contains("\r\nMETHOD:REQUEST\r\n"));
+        assert!(ics.contains("\r\nDTSTAMP:20261018T120000Z\r\n"));
+        // Converted to UTC
+        assert!(ics.contains("\r\nDTSTART:20261020T130000Z\r\n"));
+        assert!(ics.contains("\r\nDTEND:20261020T140000Z\r\n"));
+        assert!(ics.contains("\r\nSUMMARY:Planning\\, part 1\\; the long one\r\n"));
+        assert!(ics.contains("\r\nORGANIZER;CN=\"Organizer\":mailto:organizer@example.com\r\n"));
+        assert!(ics.contains("\r\nSTATUS:CONFIRMED\r\n"));
+        for line in ics.split("\r\n") {
+            assert!(line.len() <= LINE_LENGTH, "{line}");
+        }
+        // Unfolding gives back the long lines
+        let unfolded = ics.replace("\r\n ", "");
+        assert!(unfolded.contains(
+            "\r\nATTENDEE;ROLE=REQ-PARTICIPANT;PARTSTAT=NEEDS-ACTION;RSVP=TRUE:mailto:user@example.com\r\n"
+        ));
+        assert!(unfolded.contains(&format!("DESCRIPTION:{}", "Line one\\nLine two ".repeat(8))));
+    }
+    #[test]
+    fn test_cancel() {
+        let mut event = event();
+        event.sequence = 1;
+        let ics = Calendar::cancel(event).to_ics();
+        assert!(ics.contains("\r\nMETHOD:CANCEL\r\n"));
+        assert!(ics.contains("\r\nSEQUENCE:1\r\n"));
+        assert!(ics.contains("\r\nSTATUS:CANCELLED\r\n"));
+    }
+    #[test]
+    fn test_fold_multibyte() {
+        let mut out = String::new();
+        fold(&mut out, &"é".repeat(60));
+        for line in out.split("\r\n") {
+            assert!(line.len() <= LINE_LENGTH);
+        }
+        assert_eq!(out.replace("\r\n ", ""), format!("{}\r\n", "é".repeat(60)));
+    }
+}
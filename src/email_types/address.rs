@@ -18,6 +18,10 @@ pub struct Address {
 }
 
 impl Address {
+    /// Creates an Address without validating it
+    ///
+    /// # Safety
+    /// The value must contain an `@`
     pub unsafe fn new_unchecked(serialized: impl Into<String>) -> Self {
         let serialized = serialized.into();
         let at_start = serialized.find('@').unwrap();
@@ -26,6 +30,10 @@ impl Address {
             at_start,
         }
     }
+    /// The part after the `@`
+    pub fn domain(&self) -> &str {
+        &self.serialized[self.at_start + 1..]
+    }
 }
 impl Display for Address {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -59,7 +67,7 @@ impl<'de> serde::Deserialize<'de> for Address {
         D: serde::Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        Ok(Address::try_from(s.as_str()).map_err(serde::de::Error::custom)?)
+        Address::try_from(s.as_str()).map_err(serde::de::Error::custom)
     }
 }
 impl TryFrom<String> for Address {
@@ -67,17 +75,17 @@ impl TryFrom<String> for Address {
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
         let at_start = value.find('@').ok_or(AddressError::MissingAt)?;
-        return Ok(Self {
+        Ok(Self {
             serialized: value.to_string(),
             at_start,
-        });
+        })
     }
 }
 impl TryFrom<&str> for Address {
     type Error = AddressError;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        return value.to_owned().try_into();
+        value.to_owned().try_into()
     }
 }
 impl TryFrom<(&str, &str)> for Address {
@@ -33,7 +33,7 @@ impl<'de> Deserialize<'de> for Mailbox {
         D: serde::Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        Ok(Mailbox::try_from(s.as_str()).map_err(serde::de::Error::custom)?)
+        Mailbox::try_from(s.as_str()).map_err(serde::de::Error::custom)
     }
 }
 impl Mailbox {
@@ -0,0 +1,108 @@
+use std::{
+    collections::hash_map::RandomState,
+    fmt::{Display, Formatter},
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// The `Message-ID` of an email. Always wrapped in `<` and `>`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub struct MessageId(String);
+
+/// Differs between processes so two hosts sending at the same time do not collide
+static PROCESS: OnceLock<u64> = OnceLock::new();
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+impl MessageId {
+    /// Adds the angle brackets if they are missing
+    pub fn new(id: impl Into<String>) -> Self {
+        let id = id.into();
+        let id = id.trim();
+        if id.starts_with('<') && id.ends_with('>') {
+            Self(id.to_owned())
+        } else {
+            Self(format!("<{id}>"))
+        }
+    }
+    /// A new unique id. `<{time}.{process}.{counter}@{domain}>`
+    pub fn generate(domain: &str) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let counter = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let process = PROCESS.get_or_init(|| {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u32(std::process::id());
+            hasher.finish()
+        });
+        Self(format!("<{time:x}.{process:x}.{counter:x}@{domain}>"))
+    }
+    /// The id with the angle brackets
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+    /// The domain after the `@`. None if the id has no `@`
+    pub fn domain(&self) -> Option<&str> {
+        self.0
+            .trim_end_matches('>')
+            .rsplit_once('@')
+            .map(|(_, domain)| domain)
+    }
+}
+impl Display for MessageId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+impl AsRef<str> for MessageId {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+impl From<String> for MessageId {
+    fn from(value: String) -> Self {
+        Self::new(value)
+    }
+}
+impl From<&str> for MessageId {
+    fn from(value: &str) -> Self {
+        Self::new(value)
+    }
+}
+impl From<MessageId> for String {
+    fn from(value: MessageId) -> Self {
+        value.0
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_generate() {
+        let first = MessageId::generate("example.com");
+        let second = MessageId::generate("example.com");
+        assert_ne!(first, second);
+        assert!(first.as_str().starts_with('<'));
+        assert!(first.as_str().ends_with("@example.com>"));
+        assert_eq!(first.domain(), Some("example.com"));
+    }
+    #[test]
+    fn test_new() {
+        assert_eq!(
+            MessageId::new("abc@example.com").as_str(),
+            "<abc@example.com>"
+        );
+        assert_eq!(
+            MessageId::new("<abc@example.com>").as_str(),
+            "<abc@example.com>"
+        );
+    }
+}
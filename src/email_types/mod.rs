@@ -1,6 +1,9 @@
 mod address;
 mod mailbox;
+mod message_id;
 #[doc(inline)]
 pub use address::*;
 #[doc(inline)]
 pub use mailbox::*;
+#[doc(inline)]
+pub use message_id::*;
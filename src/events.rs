@@ -0,0 +1,192 @@
+/*!
+Events for every step an email goes through.
+
+Register a handler with [EmailAccess::on_event](crate::EmailAccess::on_event) or get a channel of events with [EmailAccess::subscribe](crate::EmailAccess::subscribe).
+The receiver can be turned into a `Stream` with `into_stream`.
+
+Handlers are called on the worker. They should return quickly.
+A subscriber holds up to [SUBSCRIBER_CAPACITY] events. Events are dropped for a subscriber that falls behind.
+*/
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+use std::sync::Arc;
+use std::time::SystemTime;
+
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+use parking_lot::RwLock;
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+use tracing::debug;
+
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+use crate::shared::{bounded_channel, Sender, TrySendError};
+use crate::{
+    email_types::{Address, MessageId},
+    shared::Receiver,
+};
+/// How many events a subscriber can fall behind before events are dropped for it
+pub const SUBSCRIBER_CAPACITY: usize = 1024;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// It expired before it was sent
+    Expired,
+    /// It was scheduled and then cancelled
+    Cancelled,
+    /// The service shut down before it was sent and there is no spool to keep it in
+    Shutdown,
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventKind {
+    /// Pushed to the queue or scheduled
+    Queued,
+    /// Held back by the rate limit
+    Deferred,
+    /// Handed to the server
+    Sending,
+    Delivered {
+        /// What the server answered
+        response: String,
+    },
+    /// Failed with a transient error. It is queued again once the backoff has passed
+    Retrying {
+        error: String,
+        /// How many times it has been sent
+        attempt: u32,
+    },
+    /// Failed with a permanent error or ran out of attempts
+    Failed { error: String },
+    /// Removed without being sent
+    Dropped { reason: DropReason },
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmailEvent {
+    /// The service the event came from. Such as `smtp`
+    pub backend: &'static str,
+    /// See [Email::tracking_id](crate::Email::tracking_id)
+    pub tracking_id: Option<String>,
+    /// The Message-ID header of the email
+    pub message_id: Option<MessageId>,
+    pub recipients: Vec<Address>,
+    pub kind: EventKind,
+    pub at: SystemTime,
+}
+pub trait EventHandler: Send + Sync {
+    fn on_event(&self, event: &EmailEvent);
+}
+impl<F> EventHandler for F
+where
+    F: Fn(&EmailEvent) + Send + Sync,
+{
+    fn on_event(&self, event: &EmailEvent) {
+        self(event)
+    }
+}
+/// Returned by [EmailAccess::subscribe](crate::EmailAccess::subscribe)
+pub type EventReceiver = Receiver<EmailEvent>;
+/// Forwards events to a subscriber
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+struct Subscriber(Sender<EmailEvent>);
+/// The handlers and subscribers of the queue of a service
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+#[derive(Default)]
+pub(crate) struct Events {
+    handlers: RwLock<Vec<Arc<dyn EventHandler>>>,
+    subscribers: RwLock<Vec<Subscriber>>,
+}
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+impl std::fmt::Debug for Events {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Events")
+            .field("handlers", &self.handlers.read().len())
+            .field("subscribers", &self.subscribers.read().len())
+            .finish()
+    }
+}
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+impl Events {
+    pub fn add_handler(&self, handler: Arc<dyn EventHandler>) {
+        self.handlers.write().push(handler);
+    }
+    pub fn subscribe(&self) -> EventReceiver {
+        let (sender, receiver) = bounded_channel(SUBSCRIBER_CAPACITY);
+        self.subscribers.write().push(Subscriber(sender));
+        receiver
+    }
+    pub fn emit(&self, event: EmailEvent) {
+        // Not locked while the handlers run so a handler can register another one
+        let handlers = self.handlers.read().clone();
+        for handler in handlers {
+            handler.on_event(&event);
+        }
+        let mut closed = false;
+        for Subscriber(sender) in self.subscribers.read().iter() {
+            match sender.try_send(event.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    debug!("A subscriber is falling behind. The event was dropped for it");
+                }
+                Err(TrySendError::Disconnected(_)) => closed = true,
+            }
+        }
+        if closed {
+            self.subscribers
+                .write()
+                .retain(|Subscriber(sender)| !sender.is_disconnected());
+        }
+    }
+}
+#[cfg(all(test, any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    fn event(kind: EventKind) -> EmailEvent {
+        EmailEvent {
+            backend: "test",
+            tracking_id: Some("order-1".to_owned()),
+            message_id: None,
+            recipients: Vec::new(),
+            kind,
+            at: SystemTime::now(),
+        }
+    }
+    #[test]
+    fn test_handlers_and_subscribers() {
+        let events = Events::default();
+        let count = Arc::new(AtomicUsize::new(0));
+        let handler_count = count.clone();
+        events.add_handler(Arc::new(move |_: &EmailEvent| {
+            handler_count.fetch_add(1, Ordering::Relaxed);
+        }));
+        let receiver = events.subscribe();
+        let dropped = events.subscribe();
+        drop(dropped);
+
+        events.emit(event(EventKind::Queued));
+        events.emit(event(EventKind::Sending));
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+        assert_eq!(receiver.try_recv().unwrap().kind, EventKind::Queued);
+        assert_eq!(receiver.try_recv().unwrap().kind, EventKind::Sending);
+        assert_eq!(events.subscribers.read().len(), 1);
+    }
+    #[test]
+    fn test_handler_can_add_a_handler() {
+        let events = Arc::new(Events::default());
+        let inner = Arc::downgrade(&events);
+        events.add_handler(Arc::new(move |_: &EmailEvent| {
+            if let Some(events) = inner.upgrade() {
+                events.add_handler(Arc::new(|_: &EmailEvent| {}));
+            }
+        }));
+        events.emit(event(EventKind::Queued));
+        assert_eq!(events.handlers.read().len(), 2);
+    }
+    #[test]
+    fn test_slow_subscriber_drops_events() {
+        let events = Events::default();
+        let receiver = events.subscribe();
+        for _ in 0..SUBSCRIBER_CAPACITY + 10 {
+            events.emit(event(EventKind::Queued));
+        }
+        assert_eq!(receiver.len(), SUBSCRIBER_CAPACITY);
+        assert_eq!(events.subscribers.read().len(), 1);
+    }
+}
@@ -0,0 +1,5 @@
+use super::FailoverService;
+use crate::worker::WorkerAccess;
+
+/// [send_and_wait](WorkerAccess::send_and_wait) returns which backend delivered the email.
+pub type FailoverAccess = WorkerAccess<FailoverService>;
@@ -0,0 +1,305 @@
+/*!
+Sends through the first backend that works.
+
+Each email is handed to the services in the order they are configured. If one fails with a transient or connection error the next one is tried.
+A permanent error, such as the server rejecting the recipient, is not retried on the other services.
+*/
+mod access;
+mod settings;
+use std::sync::Arc;
+
+#[doc(inline)]
+pub use access::*;
+#[doc(inline)]
+pub use settings::*;
+use thiserror::Error;
+use tracing::{debug, warn};
+
+#[cfg(feature = "lmtp")]
+use crate::lmtp::{LMTPError, LMTPService, RecipientStatus};
+#[cfg(feature = "mail-gun")]
+use crate::mail_gun::{MailGunError, MailGunResponse, MailGunService};
+use crate::{
+    metrics::Metrics,
+    rate_limit::RateLimiter,
+    shared::Mutex,
+    smtp::{load_dkim, DkimError, MessageSecurity, SMTPError, SMTPService, SecurityError},
+    worker::{self, Backend, QueueError, RawEmail},
+    MailService, MailServiceSettings, MailServiceTypes, ServiceState,
+};
+/// The error from a single backend
+#[derive(Debug, Error)]
+pub enum BackendError {
+    #[error(transparent)]
+    SMTP(#[from] SMTPError),
+    #[cfg(feature = "lmtp")]
+    #[error(transparent)]
+    LMTP(#[from] LMTPError),
+    #[cfg(feature = "mail-gun")]
+    #[error(transparent)]
+    MailGun(#[from] MailGunError),
+}
+impl BackendError {
+    /// The next backend should be tried
+    pub fn is_transient(&self) -> bool {
+        match self {
+            BackendError::SMTP(err) => err.is_transient(),
+            #[cfg(feature = "lmtp")]
+            BackendError::LMTP(err) => err.is_transient(),
+            #[cfg(feature = "mail-gun")]
+            BackendError::MailGun(err) => err.is_transient(),
+        }
+    }
+}
+/// The response from the backend that delivered the email
+#[derive(Debug)]
+pub enum BackendResponse {
+    SMTP(lettre::transport::smtp::response::Response),
+    #[cfg(feature = "lmtp")]
+    LMTP(Vec<RecipientStatus>),
+    #[cfg(feature = "mail-gun")]
+    MailGun(MailGunResponse),
+}
+#[derive(Debug, Error)]
+pub enum FailoverError {
+    #[error(transparent)]
+    QueueError(#[from] QueueError),
+    #[error("Unable to open the spool: {0}")]
+    SpoolError(std::io::Error),
+    #[error(transparent)]
+    Dkim(#[from] DkimError),
+    #[error(transparent)]
+    Security(#[from] SecurityError),
+    #[error(transparent)]
+    InvalidEmail(#[from] SMTPError),
+    #[error("No services were configured")]
+    NoServices,
+    #[error("{0:?} can not be used for failover")]
+    UnsupportedService(MailServiceTypes),
+    #[error("Service {index} failed with a permanent error: {error}")]
+    PermanentFailure { index: usize, error: BackendError },
+    #[error("All services failed")]
+    AllServicesFailed(Vec<BackendError>),
+}
+impl FailoverError {
+    /// Every service failed with a transient error. Sending again later might succeed
+    pub fn is_transient(&self) -> bool {
+        matches!(self, FailoverError::AllServicesFailed(_))
+    }
+}
+/// Which backend delivered an email
+#[derive(Debug)]
+pub struct FailoverDelivery {
+    /// The position of the service in [FailoverSettings::services]
+    pub index: usize,
+    pub service: MailServiceTypes,
+    pub response: BackendResponse,
+}
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FailoverState {
+    /// The position of the service that delivered the last email
+    pub last_delivered_by: Option<usize>,
+    /// How many emails each service delivered
+    pub delivered: Vec<u64>,
+    /// How many times each service failed to deliver an email
+    pub failures: Vec<u64>,
+}
+#[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
+enum FailoverBackend {
+    SMTP(SMTPService),
+    #[cfg(feature = "lmtp")]
+    LMTP(LMTPService),
+    #[cfg(feature = "mail-gun")]
+    MailGun(MailGunService),
+}
+impl FailoverBackend {
+    async fn new(
+        settings: &MailServiceSettings,
+        service_state: &Arc<ServiceState>,
+    ) -> Result<Self, FailoverError> {
+        let backend = match settings {
+            MailServiceSettings::SMTP(settings) => Self::SMTP(
+                SMTPService::new(Arc::new(settings.as_ref().clone()), service_state.clone()).await,
+            ),
+            #[cfg(feature = "lmtp")]
+            MailServiceSettings::LMTP(settings) => Self::LMTP(LMTPService::new(
+                Arc::new(settings.as_ref().clone()),
+                service_state.clone(),
+            )),
+            #[cfg(feature = "mail-gun")]
+            MailServiceSettings::MailGun(settings) => Self::MailGun(MailGunService::new(
+                Arc::new(settings.as_ref().clone()),
+                service_state.clone(),
+            )),
+            #[cfg(feature = "mail-whale")]
+            MailServiceSettings::MailWhale(_) => {
+                return Err(FailoverError::UnsupportedService(
+                    MailServiceTypes::MailWhale,
+                ))
+            }
+            MailServiceSettings::None => {
+                return Err(FailoverError::UnsupportedService(MailServiceTypes::None))
+            }
+        };
+        Ok(backend)
+    }
+    fn service_type(&self) -> MailServiceTypes {
+        match self {
+            FailoverBackend::SMTP(_) => MailServiceTypes::SMTP,
+            #[cfg(feature = "lmtp")]
+            FailoverBackend::LMTP(_) => MailServiceTypes::LMTP,
+            #[cfg(feature = "mail-gun")]
+            FailoverBackend::MailGun(_) => MailServiceTypes::MailGun,
+        }
+    }
+    async fn deliver(&self, email: &RawEmail) -> Result<BackendResponse, BackendError> {
+        match self {
+            FailoverBackend::SMTP(service) => {
+                Ok(BackendResponse::SMTP(service.deliver(email).await?))
+            }
+            #[cfg(feature = "lmtp")]
+            FailoverBackend::LMTP(service) => {
+                Ok(BackendResponse::LMTP(service.deliver(email).await?))
+            }
+            #[cfg(feature = "mail-gun")]
+            FailoverBackend::MailGun(service) => {
+                Ok(BackendResponse::MailGun(service.deliver(email).await?))
+            }
+        }
+    }
+    async fn is_connected(&self) -> bool {
+        match self {
+            FailoverBackend::SMTP(service) => service.is_connected().await,
+            #[cfg(feature = "lmtp")]
+            FailoverBackend::LMTP(service) => service.is_connected().await,
+            #[cfg(feature = "mail-gun")]
+            FailoverBackend::MailGun(service) => service.is_connected().await,
+        }
+    }
+}
+#[derive(Debug)]
+pub struct FailoverService {
+    settings: Arc<FailoverSettings>,
+    backends: Vec<FailoverBackend>,
+    state: Arc<Mutex<FailoverState>>,
+    service_state: Arc<ServiceState>,
+    metrics: Arc<Metrics>,
+}
+impl Backend for FailoverService {
+    const NAME: &'static str = "failover";
+    type Response = FailoverDelivery;
+    type Error = FailoverError;
+
+    fn is_transient(error: &FailoverError) -> bool {
+        error.is_transient()
+    }
+    async fn deliver(&self, email: &RawEmail) -> Result<FailoverDelivery, FailoverError> {
+        let mut errors = Vec::new();
+        for (index, backend) in self.backends.iter().enumerate() {
+            match backend.deliver(email).await {
+                Ok(response) => {
+                    let mut state = self.state.lock();
+                    state.last_delivered_by = Some(index);
+                    state.delivered[index] += 1;
+                    if index != 0 {
+                        debug!("Email delivered by failover service {}", index);
+                    }
+                    return Ok(FailoverDelivery {
+                        index,
+                        service: backend.service_type(),
+                        response,
+                    });
+                }
+                Err(error) => {
+                    self.state.lock().failures[index] += 1;
+                    if !error.is_transient() {
+                        return Err(FailoverError::PermanentFailure { index, error });
+                    }
+                    warn!("Service {} failed. Trying the next one: {}", index, error);
+                    errors.push(error);
+                    if index + 1 < self.backends.len() {
+                        self.metrics.failed_over();
+                    }
+                }
+            }
+        }
+        Err(FailoverError::AllServicesFailed(errors))
+    }
+}
+impl MailService for FailoverService {
+    type Settings = FailoverSettings;
+    type Access = FailoverAccess;
+    type Error = FailoverError;
+    type ConnectionState = FailoverState;
+
+    async fn init_with_state(
+        settings: Self::Settings,
+        service_state: Arc<ServiceState>,
+    ) -> Result<FailoverAccess, Self::Error>
+    where
+        Self: Sized,
+    {
+        if settings.services.is_empty() {
+            return Err(FailoverError::NoServices);
+        }
+        let mut backends = Vec::with_capacity(settings.services.len());
+        for service in &settings.services {
+            backends.push(FailoverBackend::new(service, &service_state).await?);
+        }
+        let dkim = load_dkim(settings.dkim.as_ref())?;
+        let security = MessageSecurity::load(settings.security.as_ref())?;
+        let (queue, receiver) =
+            worker::queue(settings.channel_size, settings.spool_directory.as_deref())
+                .map_err(FailoverError::SpoolError)?;
+        let state = Arc::new(Mutex::new(FailoverState {
+            last_delivered_by: None,
+            delivered: vec![0; backends.len()],
+            failures: vec![0; backends.len()],
+        }));
+        let settings = Arc::new(settings);
+        let service = Arc::new(FailoverService {
+            settings: settings.clone(),
+            backends,
+            state,
+            service_state: service_state.clone(),
+            metrics: queue.metrics_handle(),
+        });
+        let rate_limiter = RateLimiter::new(&settings.rate_limit).map(Arc::new);
+        worker::start(
+            service.clone(),
+            receiver,
+            service_state,
+            rate_limiter.clone(),
+        );
+
+        Ok(FailoverAccess::new(
+            service,
+            queue,
+            dkim,
+            security,
+            rate_limiter,
+        ))
+    }
+
+    fn get_state(&self) -> Arc<Mutex<Self::ConnectionState>> {
+        self.state.clone()
+    }
+
+    fn get_app_state(&self) -> Arc<ServiceState> {
+        self.service_state.clone()
+    }
+
+    fn get_settings(&self) -> Arc<Self::Settings> {
+        self.settings.clone()
+    }
+    /// True if any of the services are connected
+    async fn is_connected(&self) -> bool {
+        for backend in &self.backends {
+            if backend.is_connected().await {
+                return true;
+            }
+        }
+        false
+    }
+}
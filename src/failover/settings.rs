@@ -0,0 +1,73 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    email_types::{Address, Mailbox},
+    rate_limit::RateLimitSettings,
+    safety::SafetySettings,
+    smtp::{DkimSettings, SecuritySettings},
+    EmailSettingsType, MailServiceSettings,
+};
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub struct FailoverSettings {
+    /// The services in the order they are tried.
+    ///
+    /// Their from, reply to, queue, spool, rate limit, DKIM and security settings are ignored. The ones in these settings are used.
+    pub services: Vec<MailServiceSettings>,
+    pub from: Mailbox,
+    pub reply_to: Option<Mailbox>,
+    #[serde(default)]
+    pub channel_size: usize,
+    /// Keeps queued emails in this directory so they are sent after a restart
+    #[serde(default)]
+    pub spool_directory: Option<PathBuf>,
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+    #[serde(default)]
+    pub dkim: Option<DkimSettings>,
+    #[serde(default)]
+    pub security: Option<SecuritySettings>,
+    /// The domain of generated Message-IDs. Defaults to the domain of the from address
+    #[serde(default)]
+    pub message_id_domain: Option<String>,
+    /// Redirects or filters the recipients. For staging and QA
+    #[serde(default)]
+    pub safety: Option<SafetySettings>,
+}
+impl Default for FailoverSettings {
+    fn default() -> Self {
+        Self {
+            services: Vec::new(),
+            from: Mailbox::new(None, unsafe {
+                Address::new_unchecked("no-reply@example.com")
+            }),
+            reply_to: None,
+            channel_size: 0,
+            spool_directory: None,
+            rate_limit: RateLimitSettings::default(),
+            dkim: None,
+            security: None,
+            message_id_domain: None,
+            safety: None,
+        }
+    }
+}
+
+impl EmailSettingsType for FailoverSettings {
+    fn from(&self) -> &Mailbox {
+        &self.from
+    }
+
+    fn reply_to(&self) -> Option<&Mailbox> {
+        self.reply_to.as_ref()
+    }
+
+    fn message_id_domain(&self) -> Option<&str> {
+        self.message_id_domain.as_deref()
+    }
+
+    fn safety(&self) -> Option<&SafetySettings> {
+        self.safety.as_ref()
+    }
+}
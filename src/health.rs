@@ -0,0 +1,151 @@
+/*!
+A snapshot of how well a service is delivering.
+
+Read it with [EmailAccess::health](crate::EmailAccess::health).
+*/
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, Ordering},
+    Arc,
+};
+use std::time::SystemTime;
+
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::{rate_limit::RateLimitStatus, QueueDepth};
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum HealthStatus {
+    Healthy,
+    /// Emails are failing or the daily limit has been reached
+    Degraded,
+    /// The worker has stopped. Nothing will be sent
+    Down,
+}
+/// The last error a delivery failed with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LastError {
+    pub message: String,
+    pub at: SystemTime,
+}
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Health {
+    /// If the worker is running
+    pub worker_alive: bool,
+    pub last_success: Option<SystemTime>,
+    pub last_error: Option<LastError>,
+    /// Failed deliveries since the last success
+    pub consecutive_failures: u32,
+    pub queue_depth: QueueDepth,
+    /// None if the service is not rate limited
+    pub rate_limit: Option<RateLimitStatus>,
+    /// None if the service does not pool connections
+    pub pool: Option<PoolStatus>,
+}
+/// The connection pool of an SMTP service
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PoolStatus {
+    pub max_size: u32,
+    pub min_idle: u32,
+    /// Deliveries holding a connection right now
+    pub in_use: u32,
+    /// Times the pool was replaced because it reached its max lifetime or the access token changed
+    pub replaced: u64,
+    /// When the current pool was created. None if the service has not connected
+    pub created_at: Option<SystemTime>,
+}
+impl Health {
+    pub fn status(&self) -> HealthStatus {
+        if !self.worker_alive {
+            HealthStatus::Down
+        } else if self.consecutive_failures > 0
+            || self
+                .rate_limit
+                .as_ref()
+                .is_some_and(RateLimitStatus::is_quota_exhausted)
+        {
+            HealthStatus::Degraded
+        } else {
+            HealthStatus::Healthy
+        }
+    }
+}
+/// Kept by the worker of a service
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+#[derive(Debug, Default)]
+pub(crate) struct HealthTracker {
+    alive: AtomicBool,
+    last_success: Mutex<Option<SystemTime>>,
+    last_error: Mutex<Option<LastError>>,
+    consecutive_failures: AtomicU32,
+}
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+impl HealthTracker {
+    pub fn success(&self) {
+        *self.last_success.lock() = Some(SystemTime::now());
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+    }
+    pub fn failure(&self, error: &impl std::error::Error) {
+        *self.last_error.lock() = Some(LastError {
+            message: error.to_string(),
+            at: SystemTime::now(),
+        });
+        self.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Marks the worker alive until the guard is dropped
+    pub fn alive(this: Arc<Self>) -> AliveGuard {
+        this.alive.store(true, Ordering::Relaxed);
+        AliveGuard(this)
+    }
+    pub fn snapshot(&self, queue_depth: QueueDepth) -> Health {
+        Health {
+            worker_alive: self.alive.load(Ordering::Relaxed),
+            last_success: *self.last_success.lock(),
+            last_error: self.last_error.lock().clone(),
+            consecutive_failures: self.consecutive_failures.load(Ordering::Relaxed),
+            queue_depth,
+            rate_limit: None,
+            pool: None,
+        }
+    }
+}
+/// Marks the worker as stopped when it is dropped. Even if the worker panics
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+pub(crate) struct AliveGuard(Arc<HealthTracker>);
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+impl Drop for AliveGuard {
+    fn drop(&mut self) {
+        self.0.alive.store(false, Ordering::Relaxed);
+    }
+}
+#[cfg(all(test, any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_status() {
+        let tracker = Arc::new(HealthTracker::default());
+        assert_eq!(
+            tracker.snapshot(QueueDepth::default()).status(),
+            HealthStatus::Down
+        );
+        let guard = HealthTracker::alive(tracker.clone());
+        assert_eq!(
+            tracker.snapshot(QueueDepth::default()).status(),
+            HealthStatus::Healthy
+        );
+        tracker.failure(&std::io::Error::other("Connection refused"));
+        tracker.failure(&std::io::Error::other("Connection refused"));
+        let health = tracker.snapshot(QueueDepth::default());
+        assert_eq!(health.status(), HealthStatus::Degraded);
+        assert_eq!(health.consecutive_failures, 2);
+        assert_eq!(health.last_error.unwrap().message, "Connection refused");
+
+        tracker.success();
+        let health = tracker.snapshot(QueueDepth::default());
+        assert_eq!(health.status(), HealthStatus::Healthy);
+        assert!(health.last_success.is_some());
+        drop(guard);
+        assert!(!tracker.snapshot(QueueDepth::default()).worker_alive);
+    }
+}
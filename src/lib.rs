@@ -1,23 +1,67 @@
-use std::{borrow::Cow, error::Error, fmt::Debug, sync::Arc};
+use std::{
+    borrow::Cow,
+    error::Error,
+    fmt::Debug,
+    future::{ready, Future},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 pub(crate) mod shared;
-use email_types::Mailbox;
+use email_types::{Mailbox, MessageId};
+use events::{EventHandler, EventReceiver};
+use health::Health;
+use merge::{Merge, MergeSummary};
+use metrics::MetricsSnapshot;
+use rate_limit::RateLimitStatus;
+use safety::SafetySettings;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use template::EmailBody;
+use strum::{Display, EnumString};
+use template::{EmailBody, EmailTemplate, TemplateSet};
+#[cfg(feature = "tokio")]
+pub mod blocking;
+pub mod calendar;
 pub mod email_types;
+pub mod events;
+#[cfg(feature = "smtp")]
+pub mod failover;
+pub mod health;
+#[cfg(feature = "lmtp")]
+pub mod lmtp;
+#[cfg(feature = "smtp")]
+pub mod load_balancer;
 #[cfg(feature = "mail-gun")]
 pub mod mail_gun;
 #[cfg(feature = "mail-whale")]
 pub mod mail_whale;
+pub mod merge;
+pub mod metrics;
 pub mod no_op;
+pub mod rate_limit;
+pub mod safety;
+pub mod secret;
+#[cfg(all(feature = "smol", not(feature = "tokio")))]
+pub(crate) mod smol_rt;
 #[cfg(feature = "smtp")]
 pub mod smtp;
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+pub(crate) mod spool;
 pub mod template;
 #[cfg(feature = "tokio")]
 pub(crate) mod tokio_rt;
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+pub(crate) mod worker;
 
+#[cfg(any(feature = "tokio", feature = "smol"))]
+#[doc(inline)]
+pub use rt::ServiceState;
+/// The runtime the services run on. Tokio if both are enabled
+#[cfg(all(feature = "smol", not(feature = "tokio")))]
+pub(crate) use smol_rt as rt;
 #[cfg(feature = "tokio")]
+pub(crate) use tokio_rt as rt;
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
 #[doc(inline)]
-pub use tokio_rt::ServiceState;
+pub use worker::{QueueError, WorkerAccess};
 /// The Mail Service Types
 ///
 /// This exists for the user to able to select the mail service. Good for when you want to parse the mail service from ENV variables.
@@ -25,6 +69,8 @@ pub use tokio_rt::ServiceState;
 pub enum MailServiceTypes {
     #[cfg(feature = "smtp")]
     SMTP,
+    #[cfg(feature = "lmtp")]
+    LMTP,
     #[cfg(feature = "mail-gun")]
     MailGun,
     #[cfg(feature = "mail-whale")]
@@ -37,9 +83,11 @@ pub enum MailServiceTypes {
 #[serde(tag = "method", content = "settings")]
 pub enum MailServiceSettings {
     #[cfg(feature = "smtp")]
-    SMTP(smtp::SMTPServiceSettings),
+    SMTP(Box<smtp::SMTPServiceSettings>),
+    #[cfg(feature = "lmtp")]
+    LMTP(Box<lmtp::LMTPServiceSettings>),
     #[cfg(feature = "mail-gun")]
-    MailGun(mail_gun::MailGunSettings),
+    MailGun(Box<mail_gun::MailGunSettings>),
     #[cfg(feature = "mail-whale")]
     MailWhale(mail_whale::MailWhaleSettings),
     None,
@@ -54,7 +102,18 @@ pub trait MailService {
     type Error: Error + Send + Sync + 'static;
     type ConnectionState;
 
+    /// Starts the service with a new [ServiceState]. Ctrl-C and SIGTERM shut it down
     async fn init(settings: Self::Settings) -> Result<Self::Access, Self::Error>
+    where
+        Self: Sized,
+    {
+        Self::init_with_state(settings, ServiceState::new()).await
+    }
+    /// Starts the service with the state of the application. One state can be shared by every service
+    async fn init_with_state(
+        settings: Self::Settings,
+        service_state: Arc<ServiceState>,
+    ) -> Result<Self::Access, Self::Error>
     where
         Self: Sized;
 
@@ -67,6 +126,7 @@ pub trait MailService {
     async fn is_connected(&self) -> bool;
 }
 /// A Shared Access to the Mail Service. This is clonable and can be sent across threads.
+#[allow(async_fn_in_trait)]
 pub trait EmailAccess: Clone + Send + Sync {
     type Error: Error + Send + Sync + 'static;
     type Settings: Clone + Serialize + DeserializeOwned;
@@ -74,12 +134,119 @@ pub trait EmailAccess: Clone + Send + Sync {
 
     fn get_settings(&self) -> Arc<Self::Settings>;
 
-    /// Pushes an email to the queue.
-    fn send(&self, email: impl Email) -> Result<(), Self::Error>;
+    /// Pushes an email to the queue. Returns the Message-ID it is sent with
+    fn send(&self, email: impl Email) -> Result<MessageId, Self::Error>;
 
     fn get_state(&self) -> Arc<crate::shared::Mutex<Self::ConnectionState>>;
 
     fn get_app_state(&self) -> Arc<ServiceState>;
+    /// Holds the email until `at` and then pushes it to the queue.
+    ///
+    /// If `at` has already passed it is queued right away.
+    fn send_at(&self, email: impl Email, at: SystemTime) -> Result<Scheduled, Self::Error>;
+    /// Holds the email for `delay` and then pushes it to the queue.
+    fn send_after(&self, email: impl Email, delay: Duration) -> Result<Scheduled, Self::Error> {
+        self.send_at(email, SystemTime::now() + delay)
+    }
+    /// Cancels a scheduled email. Returns false if it was already queued or does not exist
+    fn cancel_scheduled(&self, id: ScheduledId) -> bool;
+    /// How well the service is delivering
+    fn health(&self) -> Health;
+    /// Calls the handler for every [event](events::EmailEvent) of this service
+    fn on_event(&self, handler: impl EventHandler + 'static);
+    /// Receives every [event](events::EmailEvent) from now on. Drop the receiver to unsubscribe
+    fn subscribe(&self) -> EventReceiver;
+    /// Where the public keys of recipients are found when an email is encrypted.
+    ///
+    /// Does nothing if the service has no security settings. Services that can not sign or encrypt keep this default
+    #[cfg(feature = "smtp")]
+    fn set_public_keys(&self, _keys: impl smtp::PublicKeyLookup + 'static) {}
+    /// How many emails are waiting to be sent
+    fn queue_depth(&self) -> QueueDepth {
+        QueueDepth::default()
+    }
+    /// The counters of the service. None if the service does not keep any
+    fn metrics(&self) -> Option<MetricsSnapshot> {
+        None
+    }
+    /// The state of the rate limiter. None if the service is not rate limited
+    fn rate_limit_status(&self) -> Option<RateLimitStatus> {
+        None
+    }
+    /// Renders the template for every recipient and queues the emails. See [merge]
+    fn send_merge<'a, T: EmailTemplate, D: Serialize>(
+        &self,
+        templates: &impl TemplateSet<'a>,
+        merge: Merge<D>,
+    ) -> impl Future<Output = MergeSummary> {
+        ready(merge::send_each::<T, D>(self, templates, merge))
+    }
+}
+
+/// Identifies a scheduled email so it can be cancelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ScheduledId(pub u64);
+impl std::fmt::Display for ScheduledId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+/// Returned by [EmailAccess::send_at]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Scheduled {
+    /// Cancels it with [EmailAccess::cancel_scheduled]
+    pub id: ScheduledId,
+    pub message_id: MessageId,
+}
+
+/// How urgently an email should be sent.
+///
+/// Higher priority emails are taken out of the queue first. Bulk mail is still sent while the queue is busy.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Default,
+    Serialize,
+    Deserialize,
+    Display,
+    EnumString,
+)]
+#[strum(serialize_all = "lowercase")]
+pub enum Priority {
+    /// Password resets, OTP codes and similar
+    Critical,
+    #[default]
+    Normal,
+    /// Newsletters and other mass mail
+    Bulk,
+}
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::Critical, Priority::Normal, Priority::Bulk];
+}
+/// How many emails are waiting in the queue for each [Priority]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueDepth {
+    pub critical: usize,
+    pub normal: usize,
+    pub bulk: usize,
+}
+impl QueueDepth {
+    pub fn get(&self, priority: Priority) -> usize {
+        match priority {
+            Priority::Critical => self.critical,
+            Priority::Normal => self.normal,
+            Priority::Bulk => self.bulk,
+        }
+    }
+    pub fn total(&self) -> usize {
+        self.critical + self.normal + self.bulk
+    }
 }
 
 pub trait Email: Debug {
@@ -87,18 +254,64 @@ pub trait Email: Debug {
 
     fn body(&mut self) -> Option<EmailBody>;
 
-    fn to(&self) -> impl Iterator<Item = &Mailbox> + ExactSizeIterator + '_;
+    fn to(&self) -> impl ExactSizeIterator<Item = &Mailbox> + '_;
 
     fn from(&self) -> Option<&Mailbox>;
 
     fn reply_to(&self) -> Option<&Mailbox> {
         None
     }
+
+    fn priority(&self) -> Priority {
+        Priority::Normal
+    }
+    /// The email is not sent after this time. It is read when the email is queued.
+    ///
+    /// For a time to live return `SystemTime::now() + ttl`
+    fn expires_at(&self) -> Option<SystemTime> {
+        None
+    }
+    /// Passed along in every [event](events::EmailEvent) of this email. Such as the id of the row it was created from
+    fn tracking_id(&self) -> Option<String> {
+        None
+    }
+    /// Overrides the [Protection] of the service for this email
+    fn protection(&self) -> Option<Protection> {
+        None
+    }
+    /// A custom Message-ID. One is generated with the domain of the service if None
+    fn message_id(&self) -> Option<MessageId> {
+        None
+    }
+    /// The Message-ID this email replies to
+    fn in_reply_to(&self) -> Option<MessageId> {
+        None
+    }
+    /// The Message-IDs of the thread. Usually the references of the parent followed by the parent
+    fn references(&self) -> Vec<MessageId> {
+        Vec::new()
+    }
+}
+/// Whether an email is signed and or encrypted with the `SecuritySettings` of the service
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Protection {
+    pub sign: bool,
+    /// Every recipient needs a public key
+    pub encrypt: bool,
 }
 pub trait EmailSettingsType: Clone + Serialize + DeserializeOwned {
     fn from(&self) -> &Mailbox;
 
     fn reply_to(&self) -> Option<&Mailbox>;
+    /// The domain of generated Message-IDs. The domain of [EmailSettingsType::from] is used if None
+    fn message_id_domain(&self) -> Option<&str> {
+        None
+    }
+    /// Redirects or filters the recipients of every email. See [safety]
+    fn safety(&self) -> Option<&SafetySettings> {
+        None
+    }
 }
 #[derive(Debug, Clone, PartialEq, Hash)]
 pub struct SimpleEmail {
@@ -117,7 +330,7 @@ impl Email for SimpleEmail {
         self.body.take()
     }
 
-    fn to(&self) -> impl Iterator<Item = &Mailbox> + ExactSizeIterator + '_ {
+    fn to(&self) -> impl ExactSizeIterator<Item = &Mailbox> + '_ {
         std::iter::once(&self.to)
     }
 
@@ -0,0 +1,5 @@
+use super::LMTPService;
+use crate::worker::WorkerAccess;
+
+/// [send_and_wait](WorkerAccess::send_and_wait) returns the status the server gave for each recipient.
+pub type LMTPEmailAccess = WorkerAccess<LMTPService>;
@@ -0,0 +1,167 @@
+/*!
+A minimal LMTP client. [RFC 2033](https://www.rfc-editor.org/rfc/rfc2033)
+
+LMTP is SMTP with LHLO instead of EHLO and a reply for every accepted recipient after the data is sent.
+*/
+use std::fmt::Display;
+
+use lettre::{
+    address::Envelope,
+    transport::smtp::{
+        commands::{Data, Mail, Quit, Rcpt},
+        response::Response,
+    },
+};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufStream},
+    net::TcpStream,
+};
+use tracing::debug;
+
+use super::{LMTPError, LMTPServerAddress, RecipientStatus};
+use crate::smtp::ClientId;
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+pub(crate) struct LMTPConnection {
+    stream: BufStream<Box<dyn Stream>>,
+}
+impl LMTPConnection {
+    /// Connects to the server and sends LHLO
+    pub async fn connect(
+        server: &LMTPServerAddress,
+        client_id: &ClientId,
+    ) -> Result<Self, LMTPError> {
+        let stream: Box<dyn Stream> = match server {
+            LMTPServerAddress::TCP { host, port } => {
+                let port = port.unwrap_or(LMTPServerAddress::DEFAULT_PORT);
+                Box::new(TcpStream::connect((host.as_str(), port)).await?)
+            }
+            #[cfg(unix)]
+            LMTPServerAddress::Unix(path) => Box::new(UnixStream::connect(path).await?),
+            #[cfg(not(unix))]
+            LMTPServerAddress::Unix(_) => return Err(LMTPError::UnixSocketsNotSupported),
+        };
+        let mut connection = Self {
+            stream: BufStream::new(stream),
+        };
+        let greeting = connection.read_response().await?;
+        if !greeting.is_positive() {
+            return Err(LMTPError::Rejected(greeting));
+        }
+        connection
+            .command(format!("LHLO {}\r\n", client_id))
+            .await?;
+        Ok(connection)
+    }
+    /// Sends one message. Returns the status for every recipient in the envelope.
+    ///
+    /// Recipients rejected at RCPT are reported along side the ones the server answered for after DATA.
+    /// Fails if no recipient was delivered to
+    pub async fn send(
+        &mut self,
+        envelope: &Envelope,
+        message: &[u8],
+    ) -> Result<Vec<RecipientStatus>, LMTPError> {
+        self.command(Mail::new(envelope.from().cloned(), vec![]))
+            .await?;
+        let mut statuses = Vec::with_capacity(envelope.to().len());
+        let mut accepted = Vec::with_capacity(envelope.to().len());
+        for recipient in envelope.to() {
+            let response = self
+                .raw_command(Rcpt::new(recipient.clone(), vec![]))
+                .await?;
+            if response.is_positive() {
+                accepted.push(recipient.clone());
+            } else {
+                debug!("Recipient {} rejected at RCPT", recipient);
+                statuses.push(RecipientStatus::new(recipient.clone(), response));
+            }
+        }
+        if accepted.is_empty() {
+            self.raw_command("RSET\r\n").await?;
+            return Err(LMTPError::AllRecipientsRejected(statuses));
+        }
+        self.command(Data).await?;
+        self.stream.write_all(&dot_stuff(message)).await?;
+        self.stream.flush().await?;
+        // One reply per accepted recipient, in the order they were accepted
+        for recipient in accepted {
+            let response = self.read_response().await?;
+            statuses.push(RecipientStatus::new(recipient, response));
+        }
+        if !statuses.iter().any(RecipientStatus::is_delivered) {
+            return Err(LMTPError::AllRecipientsRejected(statuses));
+        }
+        Ok(statuses)
+    }
+
+    pub async fn quit(mut self) -> Result<(), LMTPError> {
+        self.command(Quit).await?;
+        Ok(())
+    }
+    /// Sends a command and fails if the response is not positive
+    async fn command(&mut self, command: impl Display) -> Result<Response, LMTPError> {
+        let response = self.raw_command(command).await?;
+        if response.is_positive() {
+            Ok(response)
+        } else {
+            Err(LMTPError::Rejected(response))
+        }
+    }
+
+    async fn raw_command(&mut self, command: impl Display) -> Result<Response, LMTPError> {
+        self.stream
+            .write_all(command.to_string().as_bytes())
+            .await?;
+        self.stream.flush().await?;
+        self.read_response().await
+    }
+
+    async fn read_response(&mut self) -> Result<Response, LMTPError> {
+        let mut buffer = String::new();
+        loop {
+            let start = buffer.len();
+            if self.stream.read_line(&mut buffer).await? == 0 {
+                return Err(LMTPError::ConnectionClosed);
+            }
+            // Multiline responses use `-` after the code. The last line uses a space
+            if buffer.as_bytes().get(start + 3) != Some(&b'-') {
+                break;
+            }
+        }
+        buffer.parse().map_err(LMTPError::InvalidResponse)
+    }
+}
+/// Escapes lines starting with `.` and appends the end of data marker
+fn dot_stuff(message: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(message.len() + 8);
+    let mut line_start = true;
+    for &byte in message {
+        if line_start && byte == b'.' {
+            result.push(b'.');
+        }
+        result.push(byte);
+        line_start = byte == b'\n';
+    }
+    if !result.ends_with(b"\r\n") {
+        result.extend_from_slice(b"\r\n");
+    }
+    result.extend_from_slice(b".\r\n");
+    result
+}
+#[cfg(test)]
+mod tests {
+    use super::dot_stuff;
+    #[test]
+    fn test_dot_stuff() {
+        assert_eq!(dot_stuff(b"Hello\r\n"), b"Hello\r\n.\r\n");
+        assert_eq!(
+            dot_stuff(b".Hidden\r\nText\r\n..\r\nEnd"),
+            b"..Hidden\r\nText\r\n...\r\nEnd\r\n.\r\n"
+        );
+    }
+}
@@ -0,0 +1,240 @@
+/*!
+Delivers mail to a local delivery agent (Such as Dovecot) over LMTP.
+
+Uses the same queue and worker as [SMTP](crate::smtp). LMTP replies for every recipient so a delivery results in a list of [RecipientStatus]
+
+Recipients the server answered with a 4xx are sent again later. The delivery fails if no recipient was delivered to
+*/
+mod access;
+mod client;
+mod settings;
+use std::sync::Arc;
+
+#[doc(inline)]
+pub use access::*;
+use lettre::transport::smtp::response::{Response, Severity};
+#[doc(inline)]
+pub use settings::*;
+use thiserror::Error;
+use tracing::{debug, warn};
+
+use self::client::LMTPConnection;
+use crate::{
+    email_types::Address,
+    rate_limit::RateLimiter,
+    shared::Mutex,
+    smtp::{
+        load_dkim, ConnectionState, DkimError, MessageSecurity, SMTPError, SecurityError,
+        SharedConnectionState,
+    },
+    worker::{self, Backend, QueueError, RawEmail, Undelivered},
+    MailService, ServiceState,
+};
+#[derive(Debug, Error)]
+pub enum LMTPError {
+    #[error(transparent)]
+    QueueError(#[from] QueueError),
+    #[error("Unable to open the spool: {0}")]
+    SpoolError(std::io::Error),
+    #[error(transparent)]
+    Dkim(#[from] DkimError),
+    #[error(transparent)]
+    Security(#[from] SecurityError),
+    #[error(transparent)]
+    InvalidEmail(#[from] SMTPError),
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+    #[error("Invalid response from the LMTP server: {0}")]
+    InvalidResponse(lettre::transport::smtp::Error),
+    #[error("The LMTP server rejected the command: {}", format_response(.0))]
+    Rejected(Response),
+    #[error("The LMTP server rejected all recipients")]
+    AllRecipientsRejected(Vec<RecipientStatus>),
+    #[error("The LMTP server closed the connection")]
+    ConnectionClosed,
+    #[error("Timed out waiting for the LMTP server")]
+    Timeout,
+    #[error("Unix sockets are not supported on this platform")]
+    UnixSocketsNotSupported,
+}
+impl LMTPError {
+    /// Sending again later or through another server might succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            LMTPError::IOError(_)
+            | LMTPError::InvalidResponse(_)
+            | LMTPError::ConnectionClosed
+            | LMTPError::Timeout => true,
+            LMTPError::Rejected(response) => {
+                response.code().severity == Severity::TransientNegativeCompletion
+            }
+            LMTPError::AllRecipientsRejected(statuses) => {
+                statuses.iter().all(RecipientStatus::is_temporary_failure)
+            }
+            _ => false,
+        }
+    }
+}
+fn format_response(response: &Response) -> String {
+    format!(
+        "{} {}",
+        response.code(),
+        response.message().collect::<Vec<_>>().join(" ")
+    )
+}
+/// The reply the LMTP server gave for a single recipient
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecipientStatus {
+    pub recipient: Address,
+    pub response: Response,
+}
+impl RecipientStatus {
+    pub(crate) fn new(recipient: lettre::Address, response: Response) -> Self {
+        Self {
+            recipient: recipient.into(),
+            response,
+        }
+    }
+    /// The message was delivered to this recipient
+    pub fn is_delivered(&self) -> bool {
+        self.response.is_positive()
+    }
+    /// The server asked to try this recipient again later. (4xx)
+    pub fn is_temporary_failure(&self) -> bool {
+        self.response.code().severity == Severity::TransientNegativeCompletion
+    }
+}
+#[derive(Debug)]
+pub struct LMTPService {
+    settings: Arc<LMTPServiceSettings>,
+    state: SharedConnectionState,
+    service_state: Arc<ServiceState>,
+}
+impl LMTPService {
+    pub(crate) fn new(
+        settings: Arc<LMTPServiceSettings>,
+        service_state: Arc<ServiceState>,
+    ) -> Self {
+        Self {
+            settings,
+            state: Arc::new(Mutex::new(ConnectionState { connected: false })),
+            service_state,
+        }
+    }
+    async fn test_connection(settings: &LMTPServiceSettings) -> Result<(), LMTPError> {
+        let connection = LMTPConnection::connect(&settings.server, &settings.client_id).await?;
+        connection.quit().await
+    }
+
+    async fn deliver_inner(&self, email: &RawEmail) -> Result<Vec<RecipientStatus>, LMTPError> {
+        let mut connection =
+            LMTPConnection::connect(&self.settings.server, &self.settings.client_id).await?;
+        let statuses = connection.send(&email.envelope, &email.formatted).await?;
+        if let Err(err) = connection.quit().await {
+            debug!("Error closing LMTP connection: {}", err);
+        }
+        Ok(statuses)
+    }
+
+    pub fn settings(&self) -> &LMTPServiceSettings {
+        self.settings.as_ref()
+    }
+}
+impl Backend for LMTPService {
+    const NAME: &'static str = "lmtp";
+    type Response = Vec<RecipientStatus>;
+    type Error = LMTPError;
+
+    fn is_transient(error: &LMTPError) -> bool {
+        error.is_transient()
+    }
+    async fn deliver(&self, email: &RawEmail) -> Result<Vec<RecipientStatus>, LMTPError> {
+        let result = if let Some(timeout) = self.settings.get_timeout() {
+            tokio::time::timeout(timeout, self.deliver_inner(email))
+                .await
+                .unwrap_or(Err(LMTPError::Timeout))
+        } else {
+            self.deliver_inner(email).await
+        };
+        self.state.lock().connected = !matches!(
+            result,
+            Err(LMTPError::IOError(_) | LMTPError::ConnectionClosed | LMTPError::Timeout)
+        );
+        result
+    }
+    /// Recipients rejected at RCPT or after the data. The ones that failed with a 4xx are sent again
+    fn undelivered(statuses: &Vec<RecipientStatus>) -> Vec<Undelivered> {
+        statuses
+            .iter()
+            .filter(|status| !status.is_delivered())
+            .map(|status| Undelivered {
+                recipient: status.recipient.clone(),
+                error: format_response(&status.response),
+                transient: status.is_temporary_failure(),
+            })
+            .collect()
+    }
+}
+impl MailService for LMTPService {
+    type Settings = LMTPServiceSettings;
+    type Access = LMTPEmailAccess;
+    type Error = LMTPError;
+    type ConnectionState = ConnectionState;
+
+    async fn init_with_state(
+        settings: Self::Settings,
+        service_state: Arc<ServiceState>,
+    ) -> Result<LMTPEmailAccess, Self::Error>
+    where
+        Self: Sized,
+    {
+        let dkim = load_dkim(settings.dkim.as_ref())?;
+        let security = MessageSecurity::load(settings.security.as_ref())?;
+        let (queue, receiver) =
+            worker::queue(settings.channel_size, settings.spool_directory.as_deref())
+                .map_err(LMTPError::SpoolError)?;
+        let service = Arc::new(LMTPService::new(Arc::new(settings), service_state));
+        if !service.is_connected().await {
+            warn!("LMTP Test Connection Failed");
+        }
+        let rate_limiter = RateLimiter::new(&service.settings.rate_limit).map(Arc::new);
+        worker::start(
+            service.clone(),
+            receiver,
+            service.service_state.clone(),
+            rate_limiter.clone(),
+        );
+
+        Ok(LMTPEmailAccess::new(
+            service,
+            queue,
+            dkim,
+            security,
+            rate_limiter,
+        ))
+    }
+
+    fn get_state(&self) -> Arc<crate::shared::Mutex<Self::ConnectionState>> {
+        self.state.clone()
+    }
+
+    fn get_app_state(&self) -> Arc<ServiceState> {
+        self.service_state.clone()
+    }
+
+    fn get_settings(&self) -> Arc<Self::Settings> {
+        self.settings.clone()
+    }
+
+    async fn is_connected(&self) -> bool {
+        let connected = match Self::test_connection(&self.settings).await {
+            Ok(()) => true,
+            Err(err) => {
+                debug!("LMTP Test Connection Failed: {}", err);
+                false
+            }
+        };
+        self.state.lock().connected = connected;
+        connected
+    }
+}
@@ -0,0 +1,108 @@
+use std::{path::PathBuf, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    email_types::{Address, Mailbox},
+    rate_limit::RateLimitSettings,
+    safety::SafetySettings,
+    smtp::{ClientId, DkimSettings, SecuritySettings},
+    EmailSettingsType,
+};
+/// Where the LMTP server is listening
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub enum LMTPServerAddress {
+    /// If port is None, 24 is used
+    TCP { host: String, port: Option<u16> },
+    /// A Unix Domain Socket. Such as `/var/run/dovecot/lmtp`
+    Unix(PathBuf),
+}
+impl LMTPServerAddress {
+    pub const DEFAULT_PORT: u16 = 24;
+}
+impl Default for LMTPServerAddress {
+    fn default() -> Self {
+        Self::TCP {
+            host: "127.0.0.1".to_string(),
+            port: None,
+        }
+    }
+}
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub struct LMTPServiceSettings {
+    pub server: LMTPServerAddress,
+    pub from: Mailbox,
+    pub reply_to: Option<Mailbox>,
+    /// Timeout for a single delivery in milliseconds. -1 for no timeout
+    pub timeout: Option<i64>,
+    /// The name sent with LHLO
+    #[serde(default)]
+    pub client_id: ClientId,
+    #[serde(default)]
+    pub channel_size: usize,
+    /// Keeps queued emails in this directory so they are sent after a restart
+    #[serde(default)]
+    pub spool_directory: Option<PathBuf>,
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+    #[serde(default)]
+    pub dkim: Option<DkimSettings>,
+    #[serde(default)]
+    pub security: Option<SecuritySettings>,
+    /// The domain of generated Message-IDs. Defaults to the domain of the from address
+    #[serde(default)]
+    pub message_id_domain: Option<String>,
+    /// Redirects or filters the recipients. For staging and QA
+    #[serde(default)]
+    pub safety: Option<SafetySettings>,
+}
+impl LMTPServiceSettings {
+    pub(crate) fn get_timeout(&self) -> Option<Duration> {
+        if let Some(v) = self.timeout {
+            if v <= -1 {
+                return None;
+            }
+            Some(Duration::from_millis(v as u64))
+        } else {
+            None
+        }
+    }
+}
+impl Default for LMTPServiceSettings {
+    fn default() -> Self {
+        Self {
+            server: LMTPServerAddress::default(),
+            from: Mailbox::new(None, unsafe {
+                Address::new_unchecked("no-reply@example.com")
+            }),
+            reply_to: None,
+            timeout: Some(60000),
+            client_id: ClientId::default(),
+            channel_size: 0,
+            spool_directory: None,
+            rate_limit: RateLimitSettings::default(),
+            dkim: None,
+            security: None,
+            message_id_domain: None,
+            safety: None,
+        }
+    }
+}
+
+impl EmailSettingsType for LMTPServiceSettings {
+    fn from(&self) -> &Mailbox {
+        &self.from
+    }
+
+    fn reply_to(&self) -> Option<&Mailbox> {
+        self.reply_to.as_ref()
+    }
+
+    fn message_id_domain(&self) -> Option<&str> {
+        self.message_id_domain.as_deref()
+    }
+
+    fn safety(&self) -> Option<&SafetySettings> {
+        self.safety.as_ref()
+    }
+}
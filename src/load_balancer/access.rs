@@ -0,0 +1,5 @@
+use super::LoadBalancerService;
+use crate::worker::WorkerAccess;
+
+/// [send_and_wait](WorkerAccess::send_and_wait) returns which relay delivered the email.
+pub type LoadBalancerAccess = WorkerAccess<LoadBalancerService>;
@@ -0,0 +1,430 @@
+/*!
+Spreads emails across several SMTP relays.
+
+Every relay is probed with a test connection on an interval. Relays that fail are taken out of rotation until they pass again.
+If a relay fails to send with a transient error it is taken out of rotation and the email is given to the next relay.
+*/
+mod access;
+mod settings;
+use std::{pin::pin, sync::Arc, time::Duration};
+
+#[doc(inline)]
+pub use access::*;
+use futures_util::{select_biased, FutureExt};
+use lettre::transport::smtp::response::Response;
+#[doc(inline)]
+pub use settings::*;
+use thiserror::Error;
+use tracing::{debug, warn};
+
+use crate::{
+    metrics::Metrics,
+    rate_limit::RateLimiter,
+    rt,
+    shared::Mutex,
+    smtp::{
+        load_dkim, DkimError, MessageSecurity, SMTPAuthentication, SMTPError, SMTPService,
+        SecurityError, Transport,
+    },
+    worker::{self, Backend, QueueError, RawEmail},
+    MailService, ServiceState,
+};
+#[derive(Debug, Error)]
+pub enum LoadBalancerError {
+    #[error(transparent)]
+    QueueError(#[from] QueueError),
+    #[error("Unable to open the spool: {0}")]
+    SpoolError(std::io::Error),
+    #[error(transparent)]
+    Dkim(#[from] DkimError),
+    #[error(transparent)]
+    Security(#[from] SecurityError),
+    #[error(transparent)]
+    InvalidEmail(#[from] SMTPError),
+    #[error("No relays were configured")]
+    NoRelays,
+    #[error("Relay {index} uses XOAUTH2. The load balancer can not refresh access tokens")]
+    UnsupportedAuthentication { index: usize },
+    #[error("Relay {index} failed: {error}")]
+    RelayFailed { index: usize, error: SMTPError },
+}
+impl LoadBalancerError {
+    /// Sending again later might succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            LoadBalancerError::RelayFailed { error, .. } => error.is_transient(),
+            _ => false,
+        }
+    }
+}
+/// Which relay delivered an email
+#[derive(Debug)]
+pub struct LoadBalancerDelivery {
+    /// The position of the relay in [LoadBalancerSettings::relays]
+    pub index: usize,
+    pub response: Response,
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayState {
+    pub host: String,
+    /// If the relay is in rotation
+    pub healthy: bool,
+    /// Emails currently being sent through the relay
+    pub outstanding: usize,
+    pub delivered: u64,
+    pub failures: u64,
+}
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LoadBalancerState {
+    pub relays: Vec<RelayState>,
+}
+impl LoadBalancerState {
+    /// True if any relay is in rotation
+    pub fn is_connected(&self) -> bool {
+        self.relays.iter().any(|relay| relay.healthy)
+    }
+}
+/// Picks the next relay
+#[derive(Debug)]
+struct Selector {
+    strategy: BalanceStrategy,
+    weights: Vec<i64>,
+    /// Round Robin position
+    next: usize,
+    /// Smooth weighted round robin. The same algorithm nginx uses
+    current_weights: Vec<i64>,
+}
+impl Selector {
+    fn new(strategy: BalanceStrategy, weights: Vec<i64>) -> Self {
+        Self {
+            strategy,
+            current_weights: vec![0; weights.len()],
+            weights,
+            next: 0,
+        }
+    }
+    /// `candidates` are the indexes of the relays that can be picked
+    fn pick(&mut self, candidates: &[usize], relays: &[RelayState]) -> Option<usize> {
+        if candidates.is_empty() {
+            return None;
+        }
+        let picked = match self.strategy {
+            BalanceStrategy::RoundRobin => {
+                let picked = candidates
+                    .iter()
+                    .copied()
+                    .find(|index| *index >= self.next)
+                    .unwrap_or(candidates[0]);
+                self.next = picked + 1;
+                picked
+            }
+            BalanceStrategy::Weighted => {
+                let total: i64 = candidates.iter().map(|index| self.weights[*index]).sum();
+                for index in candidates {
+                    self.current_weights[*index] += self.weights[*index];
+                }
+                let picked = *candidates
+                    .iter()
+                    .max_by_key(|index| (self.current_weights[**index], usize::MAX - **index))
+                    .expect("candidates is not empty");
+                self.current_weights[picked] -= total;
+                picked
+            }
+            BalanceStrategy::LeastOutstanding => *candidates
+                .iter()
+                .min_by_key(|index| relays[**index].outstanding)
+                .expect("candidates is not empty"),
+        };
+        Some(picked)
+    }
+}
+/// An email being sent through a relay. Counted in [RelayState::outstanding] until it is dropped
+struct Outstanding<'a> {
+    index: usize,
+    state: &'a Mutex<LoadBalancerState>,
+}
+impl<'a> Outstanding<'a> {
+    fn new(index: usize, state: &'a Mutex<LoadBalancerState>) -> Self {
+        state.lock().relays[index].outstanding += 1;
+        Self { index, state }
+    }
+}
+impl Drop for Outstanding<'_> {
+    fn drop(&mut self) {
+        self.state.lock().relays[self.index].outstanding -= 1;
+    }
+}
+#[derive(Debug)]
+struct Relay {
+    transport: Transport,
+}
+#[derive(Debug)]
+pub struct LoadBalancerService {
+    settings: Arc<LoadBalancerSettings>,
+    relays: Vec<Relay>,
+    selector: Mutex<Selector>,
+    state: Arc<Mutex<LoadBalancerState>>,
+    service_state: Arc<ServiceState>,
+    metrics: Arc<Metrics>,
+}
+impl LoadBalancerService {
+    /// Picks a healthy relay that has not been tried yet and marks it as outstanding.
+    ///
+    /// If no relays are healthy all relays that have not been tried are candidates.
+    fn pick(&self, tried: &[usize]) -> Option<Outstanding<'_>> {
+        let state = self.state.lock();
+        let untried = (0..self.relays.len()).filter(|index| !tried.contains(index));
+        let mut candidates: Vec<usize> = untried
+            .clone()
+            .filter(|index| state.relays[*index].healthy)
+            .collect();
+        if candidates.is_empty() {
+            candidates = untried.collect();
+        }
+        let picked = self.selector.lock().pick(&candidates, &state.relays)?;
+        drop(state);
+        Some(Outstanding::new(picked, &self.state))
+    }
+    /// Tests every relay and updates if they are in rotation
+    async fn probe(&self) {
+        for (index, relay) in self.relays.iter().enumerate() {
+            let healthy = relay.transport.test_connection().await.unwrap_or(false);
+            let mut state = self.state.lock();
+            let relay_state = &mut state.relays[index];
+            if relay_state.healthy != healthy {
+                if healthy {
+                    debug!("Relay {} is back in rotation", relay_state.host);
+                } else {
+                    warn!("Relay {} failed its test connection", relay_state.host);
+                }
+            }
+            relay_state.healthy = healthy;
+        }
+    }
+    /// Probes the relays until the service shuts down
+    fn start_probing(this: Arc<Self>) {
+        let interval = Duration::from_millis(this.settings.probe_interval.max(1));
+        rt::spawn(async move {
+            loop {
+                let mut notified = pin!(this.service_state.notified().fuse());
+                select_biased! {
+                    _ = notified => break,
+                    _ = pin!(rt::sleep(interval).fuse()) => {}
+                }
+                if !this.service_state.is_running() {
+                    break;
+                }
+                this.probe().await;
+            }
+        });
+    }
+}
+impl Backend for LoadBalancerService {
+    const NAME: &'static str = "load_balancer";
+    type Response = LoadBalancerDelivery;
+    type Error = LoadBalancerError;
+
+    fn is_transient(error: &LoadBalancerError) -> bool {
+        error.is_transient()
+    }
+    async fn deliver(&self, email: &RawEmail) -> Result<LoadBalancerDelivery, LoadBalancerError> {
+        use lettre::AsyncTransport;
+        let mut tried = Vec::with_capacity(self.relays.len());
+        let mut last_error = None;
+        while let Some(outstanding) = self.pick(&tried) {
+            let index = outstanding.index;
+            tried.push(index);
+            let result = self.relays[index]
+                .transport
+                .send_raw(&email.envelope, &email.formatted)
+                .await;
+            drop(outstanding);
+            let mut state = self.state.lock();
+            let relay_state = &mut state.relays[index];
+            match result {
+                Ok(response) => {
+                    relay_state.delivered += 1;
+                    return Ok(LoadBalancerDelivery { index, response });
+                }
+                Err(error) => {
+                    relay_state.failures += 1;
+                    let error = SMTPError::from(error);
+                    if !error.is_transient() {
+                        return Err(LoadBalancerError::RelayFailed { index, error });
+                    }
+                    warn!(
+                        "Relay {} failed. Taking it out of rotation: {}",
+                        relay_state.host, error
+                    );
+                    relay_state.healthy = false;
+                    last_error = Some(LoadBalancerError::RelayFailed { index, error });
+                    if tried.len() < self.relays.len() {
+                        self.metrics.failed_over();
+                    }
+                }
+            }
+        }
+        Err(last_error.unwrap_or(LoadBalancerError::NoRelays))
+    }
+
+    fn concurrency(&self) -> usize {
+        if self.settings.concurrency == 0 {
+            self.relays.len()
+        } else {
+            self.settings.concurrency
+        }
+    }
+}
+impl MailService for LoadBalancerService {
+    type Settings = LoadBalancerSettings;
+    type Access = LoadBalancerAccess;
+    type Error = LoadBalancerError;
+    type ConnectionState = LoadBalancerState;
+
+    async fn init_with_state(
+        settings: Self::Settings,
+        service_state: Arc<ServiceState>,
+    ) -> Result<LoadBalancerAccess, Self::Error>
+    where
+        Self: Sized,
+    {
+        if settings.relays.is_empty() {
+            return Err(LoadBalancerError::NoRelays);
+        }
+        let mut relays = Vec::with_capacity(settings.relays.len());
+        let mut relay_states = Vec::with_capacity(settings.relays.len());
+        for (index, relay) in settings.relays.iter().enumerate() {
+            if relay.smtp.authentication == SMTPAuthentication::XOAuth2 {
+                return Err(LoadBalancerError::UnsupportedAuthentication { index });
+            }
+            let transport = SMTPService::build_transport(&relay.smtp)
+                .map_err(|error| LoadBalancerError::RelayFailed { index, error })?;
+            relays.push(Relay { transport });
+            relay_states.push(RelayState {
+                host: relay.smtp.host.clone(),
+                healthy: false,
+                outstanding: 0,
+                delivered: 0,
+                failures: 0,
+            });
+        }
+        let weights = settings
+            .relays
+            .iter()
+            .map(|relay| i64::from(relay.weight))
+            .collect();
+        let dkim = load_dkim(settings.dkim.as_ref())?;
+        let security = MessageSecurity::load(settings.security.as_ref())?;
+        let (queue, receiver) =
+            worker::queue(settings.channel_size, settings.spool_directory.as_deref())
+                .map_err(LoadBalancerError::SpoolError)?;
+        let state = Arc::new(Mutex::new(LoadBalancerState {
+            relays: relay_states,
+        }));
+        let settings = Arc::new(settings);
+        let service = Arc::new(LoadBalancerService {
+            selector: Mutex::new(Selector::new(settings.strategy, weights)),
+            settings: settings.clone(),
+            relays,
+            state: state.clone(),
+            service_state: service_state.clone(),
+            metrics: queue.metrics_handle(),
+        });
+        service.probe().await;
+        if !state.lock().is_connected() {
+            warn!("No relays passed the test connection");
+        }
+        LoadBalancerService::start_probing(service.clone());
+        let rate_limiter = RateLimiter::new(&settings.rate_limit).map(Arc::new);
+        worker::start(
+            service.clone(),
+            receiver,
+            service_state,
+            rate_limiter.clone(),
+        );
+
+        Ok(LoadBalancerAccess::new(
+            service,
+            queue,
+            dkim,
+            security,
+            rate_limiter,
+        ))
+    }
+
+    fn get_state(&self) -> Arc<Mutex<Self::ConnectionState>> {
+        self.state.clone()
+    }
+
+    fn get_app_state(&self) -> Arc<ServiceState> {
+        self.service_state.clone()
+    }
+
+    fn get_settings(&self) -> Arc<Self::Settings> {
+        self.settings.clone()
+    }
+
+    async fn is_connected(&self) -> bool {
+        self.probe().await;
+        self.state.lock().is_connected()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn relays(outstanding: &[usize]) -> Vec<RelayState> {
+        outstanding
+            .iter()
+            .map(|outstanding| RelayState {
+                host: String::new(),
+                healthy: true,
+                outstanding: *outstanding,
+                delivered: 0,
+                failures: 0,
+            })
+            .collect()
+    }
+    #[test]
+    fn test_round_robin() {
+        let relays = relays(&[0, 0, 0]);
+        let mut selector = Selector::new(BalanceStrategy::RoundRobin, vec![1, 1, 1]);
+        let picked: Vec<_> = (0..4)
+            .map(|_| selector.pick(&[0, 1, 2], &relays).unwrap())
+            .collect();
+        assert_eq!(picked, vec![0, 1, 2, 0]);
+        // Relay 1 is out of rotation
+        assert_eq!(selector.pick(&[0, 2], &relays), Some(2));
+        assert_eq!(selector.pick(&[0, 2], &relays), Some(0));
+        assert_eq!(selector.pick(&[], &relays), None);
+    }
+    #[test]
+    fn test_weighted() {
+        let relays = relays(&[0, 0]);
+        let mut selector = Selector::new(BalanceStrategy::Weighted, vec![3, 1]);
+        let picked: Vec<_> = (0..8)
+            .map(|_| selector.pick(&[0, 1], &relays).unwrap())
+            .collect();
+        assert_eq!(picked.iter().filter(|index| **index == 0).count(), 6);
+        assert_eq!(picked.iter().filter(|index| **index == 1).count(), 2);
+        // Smooth. The light relay is not starved until the end
+        assert_eq!(&picked[..4], &[0, 0, 1, 0]);
+    }
+    #[test]
+    fn test_outstanding_guard() {
+        let state = Mutex::new(LoadBalancerState {
+            relays: relays(&[0, 0]),
+        });
+        let outstanding = Outstanding::new(1, &state);
+        assert_eq!(state.lock().relays[1].outstanding, 1);
+        // A cancelled delivery drops it without reaching the end
+        drop(outstanding);
+        assert_eq!(state.lock().relays[1].outstanding, 0);
+    }
+    #[test]
+    fn test_least_outstanding() {
+        let relays = relays(&[2, 0, 1]);
+        let mut selector = Selector::new(BalanceStrategy::LeastOutstanding, vec![1, 1, 1]);
+        assert_eq!(selector.pick(&[0, 1, 2], &relays), Some(1));
+        assert_eq!(selector.pick(&[0, 2], &relays), Some(2));
+    }
+}
@@ -0,0 +1,134 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumIs, EnumString, VariantNames};
+
+use crate::{
+    email_types::{Address, Mailbox},
+    rate_limit::RateLimitSettings,
+    safety::SafetySettings,
+    smtp::{DkimSettings, SMTPServiceSettings, SecuritySettings},
+    EmailSettingsType,
+};
+/// How the next relay is picked
+#[derive(
+    Debug,
+    Deserialize,
+    Serialize,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    Display,
+    EnumString,
+    EnumIs,
+    VariantNames,
+)]
+pub enum BalanceStrategy {
+    /// Each relay in turn
+    #[default]
+    RoundRobin,
+    /// Each relay in turn. Relays with a higher weight are picked more often
+    Weighted,
+    /// The relay with the fewest emails currently being sent
+    LeastOutstanding,
+}
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub struct RelaySettings {
+    #[serde(flatten)]
+    pub smtp: SMTPServiceSettings,
+    /// Only used by [BalanceStrategy::Weighted]
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+fn default_weight() -> u32 {
+    1
+}
+impl From<SMTPServiceSettings> for RelaySettings {
+    fn from(smtp: SMTPServiceSettings) -> Self {
+        Self {
+            smtp,
+            weight: default_weight(),
+        }
+    }
+}
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub struct LoadBalancerSettings {
+    /// The from and reply to addresses, DKIM and security settings of the relays are ignored. The ones in these settings are used.
+    ///
+    /// Relays can not use [XOAuth2](crate::smtp::SMTPAuthentication::XOAuth2). Access tokens are only refreshed by [SMTPService](crate::smtp::SMTPService)
+    pub relays: Vec<RelaySettings>,
+    #[serde(default)]
+    pub strategy: BalanceStrategy,
+    /// How often every relay is checked with a test connection. In milliseconds
+    ///
+    /// Relays failing the test are taken out of rotation until they pass again.
+    #[serde(default = "default_probe_interval")]
+    pub probe_interval: u64,
+    /// How many emails can be sent at the same time. 0 uses the number of relays
+    #[serde(default)]
+    pub concurrency: usize,
+    pub from: Mailbox,
+    pub reply_to: Option<Mailbox>,
+    #[serde(default)]
+    pub channel_size: usize,
+    /// Keeps queued emails in this directory so they are sent after a restart
+    #[serde(default)]
+    pub spool_directory: Option<PathBuf>,
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+    #[serde(default)]
+    pub dkim: Option<DkimSettings>,
+    #[serde(default)]
+    pub security: Option<SecuritySettings>,
+    /// The domain of generated Message-IDs. Defaults to the domain of the from address
+    #[serde(default)]
+    pub message_id_domain: Option<String>,
+    /// Redirects or filters the recipients. For staging and QA
+    #[serde(default)]
+    pub safety: Option<SafetySettings>,
+}
+fn default_probe_interval() -> u64 {
+    30_000
+}
+impl Default for LoadBalancerSettings {
+    fn default() -> Self {
+        Self {
+            relays: Vec::new(),
+            strategy: BalanceStrategy::default(),
+            probe_interval: default_probe_interval(),
+            concurrency: 0,
+            from: Mailbox::new(None, unsafe {
+                Address::new_unchecked("no-reply@example.com")
+            }),
+            reply_to: None,
+            channel_size: 0,
+            spool_directory: None,
+            rate_limit: RateLimitSettings::default(),
+            dkim: None,
+            security: None,
+            message_id_domain: None,
+            safety: None,
+        }
+    }
+}
+
+impl EmailSettingsType for LoadBalancerSettings {
+    fn from(&self) -> &Mailbox {
+        &self.from
+    }
+
+    fn reply_to(&self) -> Option<&Mailbox> {
+        self.reply_to.as_ref()
+    }
+
+    fn message_id_domain(&self) -> Option<&str> {
+        self.message_id_domain.as_deref()
+    }
+
+    fn safety(&self) -> Option<&SafetySettings> {
+        self.safety.as_ref()
+    }
+}
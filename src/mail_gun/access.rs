@@ -0,0 +1,13 @@
+use super::MailGunService;
+use crate::worker::WorkerAccess;
+
+/// [send_merge](crate::EmailAccess::send_merge) sends batches of [MailGunSettings::batch_size](super::MailGunSettings::batch_size) recipients with recipient variables.
+///
+/// The template is rendered once with `%recipient.{field}%` for every top level field of the data.
+/// So the template should only print the fields. Mailgun inserts the values into the HTML as they are.
+/// Escape any HTML in the data before it is merged.
+///
+/// Every email is rendered and queued instead if the data is not a map, the service has security, safety or DKIM settings or the body has a calendar.
+/// An address that is already in the merge fails. Batches are sent right away without going through the queue or the spool.
+/// Every recipient takes a slot of the rate limit and is reported with events and metrics once its batch is sent
+pub type MailGunAccess = WorkerAccess<MailGunService>;
@@ -0,0 +1,123 @@
+//! Batch sending with [recipient variables](https://documentation.mailgun.com/docs/mailgun/user-manual/sending-messages/batch-sending)
+use std::collections::BTreeSet;
+
+use reqwest::{multipart::Form, Client};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use super::{endpoint, read_response, MailGunError, MailGunResponse, MailGunSettings};
+use crate::{email_types::Mailbox, merge::MergeRecipient, template::EmailBody};
+
+/// The data of every recipient. None if any of them is not a map
+pub(super) fn recipient_variables<D: Serialize>(
+    recipients: &[MergeRecipient<D>],
+) -> Option<Vec<Map<String, Value>>> {
+    recipients
+        .iter()
+        .map(|recipient| match serde_json::to_value(&recipient.data) {
+            Ok(Value::Object(variables)) => Some(variables),
+            _ => None,
+        })
+        .collect()
+}
+/// `%recipient.{key}%` for every key used by a recipient. Mailgun replaces them for every recipient
+pub(super) fn placeholders(variables: &[Map<String, Value>]) -> Value {
+    let keys: BTreeSet<&String> = variables.iter().flat_map(Map::keys).collect();
+    keys.into_iter()
+        .map(|key| (key.clone(), Value::String(format!("%recipient.{key}%"))))
+        .collect::<Map<_, _>>()
+        .into()
+}
+/// The email of a batch. Shared by every recipient
+pub(super) struct Batch<'a> {
+    pub from: &'a Mailbox,
+    pub reply_to: Option<&'a Mailbox>,
+    pub subject: &'a str,
+    pub body: &'a EmailBody,
+}
+impl Batch<'_> {
+    pub async fn send(
+        &self,
+        client: &Client,
+        settings: &MailGunSettings,
+        recipients: &[(&Mailbox, Map<String, Value>)],
+    ) -> Result<MailGunResponse, MailGunError> {
+        let mut variables = Map::with_capacity(recipients.len());
+        let mut form = Form::new()
+            .text("from", self.from.to_string())
+            .text("subject", self.subject.to_owned());
+        for (to, data) in recipients {
+            form = form.text("to", to.to_string());
+            variables.insert(to.email.to_string(), Value::Object(data.clone()));
+        }
+        form = form.text("recipient-variables", Value::Object(variables).to_string());
+        if let Some(reply_to) = self.reply_to {
+            form = form.text("h:Reply-To", reply_to.to_string());
+        }
+        if let Some(text) = &self.body.text_body {
+            form = form.text("text", text.clone());
+        }
+        if let Some(html) = &self.body.html_body {
+            form = form.text("html", html.clone());
+        }
+        let response = client
+            .post(endpoint(settings, "messages"))
+            .basic_auth(
+                &settings.client_id,
+                Some(settings.client_secret.expose_secret()),
+            )
+            .multipart(form)
+            .send()
+            .await?;
+        read_response(response).await
+    }
+}
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    #[derive(Serialize)]
+    struct Data {
+        name: &'static str,
+        code: u32,
+    }
+    fn recipient<D>(to: &str, data: D) -> MergeRecipient<D> {
+        MergeRecipient {
+            to: Mailbox::try_from(to).unwrap(),
+            data,
+            tracking_id: None,
+        }
+    }
+    #[test]
+    fn test_variables() {
+        let recipients = [
+            recipient(
+                "a@example.com",
+                Data {
+                    name: "Alice",
+                    code: 1,
+                },
+            ),
+            recipient(
+                "b@example.com",
+                Data {
+                    name: "Bob",
+                    code: 2,
+                },
+            ),
+        ];
+        let variables = recipient_variables(&recipients).unwrap();
+        assert_eq!(
+            Value::Object(variables[1].clone()),
+            json!({"name": "Bob", "code": 2})
+        );
+        assert_eq!(
+            placeholders(&variables),
+            json!({"code": "%recipient.code%", "name": "%recipient.name%"})
+        );
+
+        let recipients = [recipient("a@example.com", "Not a map")];
+        assert!(recipient_variables(&recipients).is_none());
+    }
+}
@@ -1,3 +1,297 @@
+/*!
+Sends mail through the [Mailgun](https://www.mailgun.com/) HTTP API.
+
+Messages are built the same way as [SMTP](crate::smtp) and uploaded to the `messages.mime` endpoint.
+*/
+mod access;
+mod batch;
 mod settings;
+use std::{collections::HashSet, sync::Arc};
+
+#[doc(inline)]
+pub use access::*;
+use reqwest::{
+    multipart::{Form, Part},
+    Client, StatusCode,
+};
+use serde::{Deserialize, Serialize};
 #[doc(inline)]
 pub use settings::MailGunSettings;
+use thiserror::Error;
+use tracing::warn;
+
+use self::batch::{placeholders, recipient_variables, Batch};
+use crate::{
+    email_types::MessageId,
+    merge::{Merge, MergeOutcome, MergeResult, MergeSummary},
+    rate_limit::RateLimiter,
+    shared::Mutex,
+    smtp::{
+        load_dkim, ConnectionState, DkimError, MessageSecurity, SMTPError, SecurityError,
+        SharedConnectionState,
+    },
+    template::{EmailTemplate, TemplateSet},
+    worker::{self, Backend, QueueError, RawEmail},
+    MailService, ServiceState,
+};
+#[derive(Debug, Error)]
+pub enum MailGunError {
+    #[error(transparent)]
+    QueueError(#[from] QueueError),
+    #[error("Unable to open the spool: {0}")]
+    SpoolError(std::io::Error),
+    #[error(transparent)]
+    Dkim(#[from] DkimError),
+    #[error(transparent)]
+    Security(#[from] SecurityError),
+    #[error(transparent)]
+    InvalidEmail(#[from] SMTPError),
+    #[error(transparent)]
+    RequestError(#[from] reqwest::Error),
+    #[error("Mailgun rejected the email. {status}: {message}")]
+    Rejected { status: StatusCode, message: String },
+}
+impl MailGunError {
+    /// The request might succeed if tried again later
+    pub fn is_transient(&self) -> bool {
+        match self {
+            MailGunError::RequestError(err) => !err.is_builder() && !err.is_decode(),
+            MailGunError::Rejected { status, .. } => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => false,
+        }
+    }
+}
+/// The response from Mailgun for an accepted email
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct MailGunResponse {
+    /// The Message-ID Mailgun assigned
+    pub id: String,
+    pub message: String,
+}
+#[derive(Debug)]
+pub struct MailGunService {
+    settings: Arc<MailGunSettings>,
+    client: Client,
+    state: SharedConnectionState,
+    service_state: Arc<ServiceState>,
+    /// Shared with the worker. Batches of a merge wait on it as well
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+impl MailGunService {
+    pub(crate) fn new(settings: Arc<MailGunSettings>, service_state: Arc<ServiceState>) -> Self {
+        Self {
+            settings,
+            client: Client::new(),
+            state: Arc::new(Mutex::new(ConnectionState { connected: false })),
+            service_state,
+            rate_limiter: None,
+        }
+    }
+
+    pub fn settings(&self) -> &MailGunSettings {
+        self.settings.as_ref()
+    }
+}
+impl Backend for MailGunService {
+    const NAME: &'static str = "mailgun";
+    type Response = MailGunResponse;
+    type Error = MailGunError;
+
+    fn is_transient(error: &MailGunError) -> bool {
+        error.is_transient()
+    }
+    async fn deliver(&self, email: &RawEmail) -> Result<MailGunResponse, MailGunError> {
+        let mut form = Form::new();
+        for to in email.envelope.to() {
+            form = form.text("to", to.to_string());
+        }
+        let form = form.part(
+            "message",
+            Part::bytes(email.formatted.clone()).file_name("message.mime"),
+        );
+        let response = self
+            .client
+            .post(endpoint(&self.settings, "messages.mime"))
+            .basic_auth(
+                &self.settings.client_id,
+                Some(self.settings.client_secret.expose_secret()),
+            )
+            .multipart(form)
+            .send()
+            .await;
+        let response = match response {
+            Ok(ok) => ok,
+            Err(err) => {
+                self.state.lock().connected = false;
+                return Err(err.into());
+            }
+        };
+        self.state.lock().connected = true;
+        read_response(response).await
+    }
+    /// See [MailGunAccess]
+    async fn send_merge<'a, T: EmailTemplate, D: Serialize>(
+        &self,
+        templates: &impl TemplateSet<'a>,
+        merge: Merge<D>,
+    ) -> Result<MergeSummary, Merge<D>> {
+        let variables = match recipient_variables(&merge.recipients) {
+            Some(variables)
+                if self.settings.batch_size > 0
+                    && self.settings.security.is_none()
+                    && self.settings.safety.is_none()
+                    && self.settings.dkim.is_none() =>
+            {
+                variables
+            }
+            _ => return Err(merge),
+        };
+        let body = match templates.build_email::<T>(&placeholders(&variables)) {
+            Ok(body) if body.calendar.is_none() => body,
+            _ => return Err(merge),
+        };
+        let batch = Batch {
+            from: merge.from.as_ref().unwrap_or(&self.settings.from),
+            reply_to: self.settings.reply_to.as_ref(),
+            subject: &merge.subject,
+            body: &body,
+        };
+        // Recipient variables are keyed by the address. So an address can only be in a merge once
+        let mut seen = HashSet::with_capacity(merge.recipients.len());
+        let mut outcomes: Vec<Option<MergeOutcome>> = merge
+            .recipients
+            .iter()
+            .map(|recipient| {
+                let duplicate = !seen.insert(recipient.to.email.to_string().to_lowercase());
+                duplicate.then(|| MergeOutcome::Failed(format!("{} is a duplicate", recipient.to)))
+            })
+            .collect();
+        let recipients: Vec<_> = merge
+            .recipients
+            .iter()
+            .zip(variables)
+            .enumerate()
+            .filter(|(index, _)| outcomes[*index].is_none())
+            .map(|(index, (recipient, variables))| (index, (&recipient.to, variables)))
+            .collect();
+        for chunk in recipients.chunks(self.settings.batch_size) {
+            // Every recipient of the batch is an email for the rate limit
+            if let Some(rate_limiter) = &self.rate_limiter {
+                for _ in chunk {
+                    rate_limiter.acquire().await;
+                }
+            }
+            let (indexes, chunk): (Vec<_>, Vec<_>) = chunk.iter().cloned().unzip();
+            let outcome = match batch.send(&self.client, &self.settings, &chunk).await {
+                Ok(response) => MergeOutcome::Queued(MessageId::new(response.id)),
+                Err(err) => MergeOutcome::Failed(err.to_string()),
+            };
+            for index in indexes {
+                outcomes[index] = Some(outcome.clone());
+            }
+        }
+        let results = merge
+            .recipients
+            .into_iter()
+            .zip(outcomes)
+            .map(|(recipient, outcome)| MergeResult {
+                to: recipient.to,
+                tracking_id: recipient.tracking_id,
+                outcome: outcome.expect("Every recipient is batched or a duplicate"),
+            })
+            .collect();
+        Ok(MergeSummary { results })
+    }
+}
+fn endpoint(settings: &MailGunSettings, endpoint: &str) -> String {
+    format!(
+        "{}/{}/{}",
+        settings.api_url.trim_end_matches('/'),
+        settings.domain(),
+        endpoint
+    )
+}
+async fn read_response(response: reqwest::Response) -> Result<MailGunResponse, MailGunError> {
+    let status = response.status();
+    if !status.is_success() {
+        let message = response.text().await.unwrap_or_default();
+        return Err(MailGunError::Rejected { status, message });
+    }
+    Ok(response.json().await?)
+}
+impl MailService for MailGunService {
+    type Settings = MailGunSettings;
+    type Access = MailGunAccess;
+    type Error = MailGunError;
+    type ConnectionState = ConnectionState;
+
+    async fn init_with_state(
+        settings: Self::Settings,
+        service_state: Arc<ServiceState>,
+    ) -> Result<MailGunAccess, Self::Error>
+    where
+        Self: Sized,
+    {
+        let dkim = load_dkim(settings.dkim.as_ref())?;
+        let security = MessageSecurity::load(settings.security.as_ref())?;
+        let (queue, receiver) =
+            worker::queue(settings.channel_size, settings.spool_directory.as_deref())
+                .map_err(MailGunError::SpoolError)?;
+        let settings = Arc::new(settings);
+        let rate_limiter = RateLimiter::new(&settings.rate_limit).map(Arc::new);
+        let service = Arc::new(MailGunService {
+            rate_limiter: rate_limiter.clone(),
+            ..MailGunService::new(settings, service_state)
+        });
+        if !service.is_connected().await {
+            warn!("Mailgun Test Connection Failed");
+        }
+        worker::start(
+            service.clone(),
+            receiver,
+            service.service_state.clone(),
+            rate_limiter.clone(),
+        );
+
+        Ok(MailGunAccess::new(
+            service,
+            queue,
+            dkim,
+            security,
+            rate_limiter,
+        ))
+    }
+
+    fn get_state(&self) -> Arc<crate::shared::Mutex<Self::ConnectionState>> {
+        self.state.clone()
+    }
+
+    fn get_app_state(&self) -> Arc<ServiceState> {
+        self.service_state.clone()
+    }
+
+    fn get_settings(&self) -> Arc<Self::Settings> {
+        self.settings.clone()
+    }
+    /// Checks that the API Key can access the domain
+    async fn is_connected(&self) -> bool {
+        let response = self
+            .client
+            .get(format!(
+                "{}/domains/{}",
+                self.settings.api_url.trim_end_matches('/'),
+                self.settings.domain()
+            ))
+            .basic_auth(
+                &self.settings.client_id,
+                Some(self.settings.client_secret.expose_secret()),
+            )
+            .send()
+            .await;
+        let connected = matches!(response, Ok(response) if response.status().is_success());
+        self.state.lock().connected = connected;
+        connected
+    }
+}
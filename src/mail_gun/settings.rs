@@ -1,16 +1,128 @@
+use std::path::PathBuf;
+
 use serde::{Deserialize, Serialize};
+
+use crate::{
+    email_types::{Address, Mailbox},
+    rate_limit::RateLimitSettings,
+    safety::SafetySettings,
+    secret::Secret,
+    smtp::{DkimSettings, SecuritySettings},
+    EmailSettingsType,
+};
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
 pub struct MailGunSettings {
     pub api_url: String,
+    /// The username for the API. Mailgun uses `api`
     pub client_id: String,
-    pub client_secret: String,
+    /// The API Key
+    pub client_secret: Secret,
+    /// The sending domain registered with Mailgun. Defaults to the domain of [MailGunSettings::from]
+    #[serde(default)]
+    pub domain: Option<String>,
+    #[serde(default = "default_from")]
+    pub from: Mailbox,
+    pub reply_to: Option<Mailbox>,
+    #[serde(default)]
+    pub channel_size: usize,
+    /// Keeps queued emails in this directory so they are sent after a restart
+    #[serde(default)]
+    pub spool_directory: Option<PathBuf>,
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+    #[serde(default)]
+    pub dkim: Option<DkimSettings>,
+    #[serde(default)]
+    pub security: Option<SecuritySettings>,
+    /// The domain of generated Message-IDs. Defaults to [MailGunSettings::domain]
+    #[serde(default)]
+    pub message_id_domain: Option<String>,
+    /// Redirects or filters the recipients. For staging and QA
+    #[serde(default)]
+    pub safety: Option<SafetySettings>,
+    /// How many recipients [send_merge](crate::EmailAccess::send_merge) puts in one request. Mailgun accepts up to 1000
+    ///
+    /// 0 renders and queues every email instead
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+}
+fn default_batch_size() -> usize {
+    1000
+}
+fn default_from() -> Mailbox {
+    Mailbox::new(None, unsafe {
+        Address::new_unchecked("no-reply@example.com")
+    })
+}
+impl MailGunSettings {
+    /// The sending domain the API is called with
+    pub fn domain(&self) -> &str {
+        self.domain.as_deref().unwrap_or(self.from.email.domain())
+    }
 }
 impl Default for MailGunSettings {
     fn default() -> Self {
         Self {
             api_url: "https://api.mailgun.net/v3".to_string(),
-            client_id: String::default(),
-            client_secret: String::default(),
+            client_id: "api".to_string(),
+            client_secret: Secret::default(),
+            domain: None,
+            from: default_from(),
+            reply_to: None,
+            channel_size: 0,
+            spool_directory: None,
+            rate_limit: RateLimitSettings::default(),
+            dkim: None,
+            security: None,
+            message_id_domain: None,
+            safety: None,
+            batch_size: default_batch_size(),
         }
     }
 }
+
+impl EmailSettingsType for MailGunSettings {
+    fn from(&self) -> &Mailbox {
+        &self.from
+    }
+
+    fn reply_to(&self) -> Option<&Mailbox> {
+        self.reply_to.as_ref()
+    }
+
+    fn message_id_domain(&self) -> Option<&str> {
+        Some(self.message_id_domain.as_deref().unwrap_or(self.domain()))
+    }
+
+    fn safety(&self) -> Option<&SafetySettings> {
+        self.safety.as_ref()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_minimal_settings() {
+        let settings: MailGunSettings = toml::from_str(
+            r#"
+            api_url = "https://api.eu.mailgun.net/v3"
+            client_id = "api"
+            client_secret = "key"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(settings.from, MailGunSettings::default().from);
+        assert_eq!(settings.batch_size, 1000);
+
+        let settings: MailGunSettings = toml::from_str(
+            r#"
+            api_url = "https://api.eu.mailgun.net/v3"
+            client_id = "api"
+            client_secret = "key"
+            from = "no-reply@mg.example.com"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(settings.domain(), "mg.example.com");
+    }
+}
@@ -0,0 +1,158 @@
+/*!
+Sends one template to many recipients with different data. Also known as mail merge.
+
+Every email is rendered with the [TemplateSet] and queued. Mailgun sends batches through its API instead.
+See [EmailAccess::send_merge].
+*/
+use std::borrow::Cow;
+
+use serde::Serialize;
+
+use crate::{
+    email_types::{Mailbox, MessageId},
+    template::{EmailBody, EmailTemplate, TemplateSet},
+    Email, EmailAccess, Priority,
+};
+/// The emails to send
+#[derive(Debug, Clone, PartialEq)]
+pub struct Merge<D> {
+    pub subject: Cow<'static, str>,
+    /// Defaults to the from address of the service
+    pub from: Option<Mailbox>,
+    pub priority: Priority,
+    pub recipients: Vec<MergeRecipient<D>>,
+}
+impl<D> Merge<D> {
+    /// Sent with [Priority::Bulk]
+    pub fn new(subject: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            subject: subject.into(),
+            from: None,
+            priority: Priority::Bulk,
+            recipients: Vec::new(),
+        }
+    }
+    pub fn recipient(mut self, to: Mailbox, data: D) -> Self {
+        self.recipients.push(MergeRecipient {
+            to,
+            data,
+            tracking_id: None,
+        });
+        self
+    }
+}
+/// A recipient and the data their email is rendered with
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeRecipient<D> {
+    pub to: Mailbox,
+    pub data: D,
+    /// See [Email::tracking_id]
+    pub tracking_id: Option<String>,
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeOutcome {
+    /// Queued or accepted by the API. The Message-ID is shared by a batch
+    Queued(MessageId),
+    /// It could not be rendered or queued
+    Failed(String),
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeResult {
+    pub to: Mailbox,
+    pub tracking_id: Option<String>,
+    pub outcome: MergeOutcome,
+}
+/// What happened to every recipient. In the order they were given
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MergeSummary {
+    pub results: Vec<MergeResult>,
+}
+impl MergeSummary {
+    pub fn queued(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|result| matches!(result.outcome, MergeOutcome::Queued(_)))
+            .count()
+    }
+    pub fn failed(&self) -> impl Iterator<Item = &MergeResult> + '_ {
+        self.results
+            .iter()
+            .filter(|result| matches!(result.outcome, MergeOutcome::Failed(_)))
+    }
+}
+/// One rendered email of a merge
+#[derive(Debug)]
+struct MergeEmail<'a> {
+    subject: Cow<'static, str>,
+    body: Option<EmailBody>,
+    to: &'a Mailbox,
+    from: Option<&'a Mailbox>,
+    priority: Priority,
+    tracking_id: Option<String>,
+}
+impl Email for MergeEmail<'_> {
+    fn subject(&self) -> Cow<'static, str> {
+        self.subject.clone()
+    }
+
+    fn body(&mut self) -> Option<EmailBody> {
+        self.body.take()
+    }
+
+    fn to(&self) -> impl ExactSizeIterator<Item = &Mailbox> + '_ {
+        std::iter::once(self.to)
+    }
+
+    fn from(&self) -> Option<&Mailbox> {
+        self.from
+    }
+
+    fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    fn tracking_id(&self) -> Option<String> {
+        self.tracking_id.clone()
+    }
+}
+/// Renders and queues an email for every recipient
+pub(crate) fn send_each<'a, T: EmailTemplate, D: Serialize>(
+    access: &impl EmailAccess,
+    templates: &impl TemplateSet<'a>,
+    merge: Merge<D>,
+) -> MergeSummary {
+    let Merge {
+        subject,
+        from,
+        priority,
+        recipients,
+    } = merge;
+    let results = recipients
+        .into_iter()
+        .map(|recipient| {
+            let outcome = match templates.build_email::<T>(&recipient.data) {
+                Ok(body) => {
+                    let email = MergeEmail {
+                        subject: subject.clone(),
+                        body: Some(body),
+                        to: &recipient.to,
+                        from: from.as_ref(),
+                        priority,
+                        tracking_id: recipient.tracking_id.clone(),
+                    };
+                    match access.send(email) {
+                        Ok(message_id) => MergeOutcome::Queued(message_id),
+                        Err(err) => MergeOutcome::Failed(err.to_string()),
+                    }
+                }
+                Err(err) => MergeOutcome::Failed(err.to_string()),
+            };
+            MergeResult {
+                to: recipient.to,
+                tracking_id: recipient.tracking_id,
+                outcome,
+            }
+        })
+        .collect();
+    MergeSummary { results }
+}
@@ -0,0 +1,312 @@
+/*!
+Counters for what a service has sent.
+
+Every queued service keeps its own counters. They can be read with [EmailAccess::metrics](crate::EmailAccess::metrics) and rendered in the Prometheus text format with [MetricsSnapshot::to_prometheus].
+Use [to_prometheus] to render the snapshots of several services as one response.
+
+With the `metrics` feature the same numbers are also reported to the [metrics](https://docs.rs/metrics) facade. Labelled by `backend`.
+
+| Name | Type |
+|------|------|
+| `any_mail_queued_total` | Counter |
+| `any_mail_sent_total` | Counter |
+| `any_mail_failed_total` | Counter. Every failed attempt. Including the ones that are retried |
+| `any_mail_retried_total` | Counter. Attempts queued again after a transient error |
+| `any_mail_failed_over_total` | Counter. Emails handed to another server after a failure |
+| `any_mail_expired_total` | Counter |
+| `any_mail_send_duration_seconds` | Histogram |
+| `any_mail_queue_depth` | Gauge. Also labelled by `priority` |
+*/
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{fmt::Write, time::Duration};
+
+use crate::{Priority, QueueDepth};
+/// The upper bounds of the latency histogram buckets in seconds
+pub const LATENCY_BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Not cumulative. The last one is everything over the largest bucket
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+/// Kept by the queue and worker of a service
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+#[derive(Debug)]
+pub(crate) struct Metrics {
+    backend: &'static str,
+    queued: AtomicU64,
+    sent: AtomicU64,
+    failed: AtomicU64,
+    retried: AtomicU64,
+    failed_over: AtomicU64,
+    expired: AtomicU64,
+    latency: Histogram,
+}
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+impl Metrics {
+    pub fn new(backend: &'static str) -> Self {
+        Self {
+            backend,
+            queued: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            retried: AtomicU64::new(0),
+            failed_over: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+            latency: Histogram::default(),
+        }
+    }
+    fn increment(&self, counter: &AtomicU64, _name: &'static str) {
+        counter.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!(_name, "backend" => self.backend).increment(1);
+    }
+    pub fn queued(&self) {
+        self.increment(&self.queued, "any_mail_queued_total");
+    }
+    /// A delivery finished
+    pub fn delivered(&self, success: bool, latency: Duration) {
+        self.counted(success);
+        let seconds = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency
+            .sum_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        ::metrics::histogram!("any_mail_send_duration_seconds", "backend" => self.backend)
+            .record(seconds);
+    }
+    /// A delivery finished without a latency of its own. Such as a recipient of a batch
+    pub fn counted(&self, success: bool) {
+        if success {
+            self.increment(&self.sent, "any_mail_sent_total");
+        } else {
+            self.increment(&self.failed, "any_mail_failed_total");
+        }
+    }
+    /// The email is queued again after a transient error
+    pub fn retried(&self) {
+        self.increment(&self.retried, "any_mail_retried_total");
+    }
+    /// The email was handed to another server after a failure
+    pub fn failed_over(&self) {
+        self.increment(&self.failed_over, "any_mail_failed_over_total");
+    }
+    pub fn expired(&self) {
+        self.increment(&self.expired, "any_mail_expired_total");
+    }
+    /// Reports the queue depth to the metrics facade
+    pub fn record_depth(&self, _depth: QueueDepth) {
+        #[cfg(feature = "metrics")]
+        for priority in Priority::ALL {
+            ::metrics::gauge!(
+                "any_mail_queue_depth",
+                "backend" => self.backend,
+                "priority" => priority.to_string()
+            )
+            .set(_depth.get(priority) as f64);
+        }
+    }
+    pub fn snapshot(&self, queue_depth: QueueDepth) -> MetricsSnapshot {
+        let mut cumulative = 0;
+        let buckets = LATENCY_BUCKETS
+            .iter()
+            .zip(&self.latency.buckets)
+            .map(|(bound, count)| {
+                cumulative += count.load(Ordering::Relaxed);
+                (*bound, cumulative)
+            })
+            .collect();
+        let count =
+            cumulative + self.latency.buckets[LATENCY_BUCKETS.len()].load(Ordering::Relaxed);
+        MetricsSnapshot {
+            backend: self.backend,
+            queued: self.queued.load(Ordering::Relaxed),
+            sent: self.sent.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            retried: self.retried.load(Ordering::Relaxed),
+            failed_over: self.failed_over.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+            queue_depth,
+            latency: LatencyHistogram {
+                buckets,
+                count,
+                sum: Duration::from_micros(self.latency.sum_micros.load(Ordering::Relaxed)),
+            },
+        }
+    }
+}
+/// How long deliveries took
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyHistogram {
+    /// The upper bound in seconds and how many deliveries took at most that long
+    pub buckets: Vec<(f64, u64)>,
+    pub count: u64,
+    pub sum: Duration,
+}
+/// The counters of a service at one point in time
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricsSnapshot {
+    /// The name of the service. Such as `smtp`
+    pub backend: &'static str,
+    pub queued: u64,
+    pub sent: u64,
+    /// Every failed attempt. Including transient errors that were retried
+    pub failed: u64,
+    /// Attempts that are queued again after a transient error
+    pub retried: u64,
+    /// Emails that were handed to another server after a failure
+    pub failed_over: u64,
+    /// Emails dropped because they expired before they were sent
+    pub expired: u64,
+    pub queue_depth: QueueDepth,
+    pub latency: LatencyHistogram,
+}
+impl MetricsSnapshot {
+    /// Renders the snapshot in the Prometheus text format
+    pub fn to_prometheus(&self) -> String {
+        to_prometheus(std::slice::from_ref(self))
+    }
+    fn counters(&self) -> [u64; COUNTERS.len()] {
+        [
+            self.queued,
+            self.sent,
+            self.failed,
+            self.retried,
+            self.failed_over,
+            self.expired,
+        ]
+    }
+}
+/// The name and help of every counter. In the order of [MetricsSnapshot::counters]
+const COUNTERS: [(&str, &str); 6] = [
+    ("any_mail_queued_total", "Emails queued"),
+    ("any_mail_sent_total", "Emails sent"),
+    (
+        "any_mail_failed_total",
+        "Attempts that failed to send. Including the ones that are retried",
+    ),
+    (
+        "any_mail_retried_total",
+        "Attempts queued again after a transient error",
+    ),
+    (
+        "any_mail_failed_over_total",
+        "Emails handed to another server after a failure",
+    ),
+    (
+        "any_mail_expired_total",
+        "Emails that expired before they were sent",
+    ),
+];
+/// Renders the snapshots of several services in the Prometheus text format.
+///
+/// Every metric is described once with the values of all the services below it
+pub fn to_prometheus(snapshots: &[MetricsSnapshot]) -> String {
+    let mut output = String::new();
+    for (index, (name, help)) in COUNTERS.into_iter().enumerate() {
+        let _ = writeln!(output, "# HELP {name} {help}");
+        let _ = writeln!(output, "# TYPE {name} counter");
+        for snapshot in snapshots {
+            let _ = writeln!(
+                output,
+                "{name}{{backend=\"{}\"}} {}",
+                snapshot.backend,
+                snapshot.counters()[index]
+            );
+        }
+    }
+    let name = "any_mail_send_duration_seconds";
+    let _ = writeln!(output, "# HELP {name} How long it took to send an email");
+    let _ = writeln!(output, "# TYPE {name} histogram");
+    for snapshot in snapshots {
+        let backend = snapshot.backend;
+        let latency = &snapshot.latency;
+        for (bound, count) in &latency.buckets {
+            let _ = writeln!(
+                output,
+                "{name}_bucket{{backend=\"{backend}\",le=\"{bound}\"}} {count}"
+            );
+        }
+        let _ = writeln!(
+            output,
+            "{name}_bucket{{backend=\"{backend}\",le=\"+Inf\"}} {}",
+            latency.count
+        );
+        let _ = writeln!(
+            output,
+            "{name}_sum{{backend=\"{backend}\"}} {}",
+            latency.sum.as_secs_f64()
+        );
+        let _ = writeln!(
+            output,
+            "{name}_count{{backend=\"{backend}\"}} {}",
+            latency.count
+        );
+    }
+    let name = "any_mail_queue_depth";
+    let _ = writeln!(output, "# HELP {name} Emails waiting to be sent");
+    let _ = writeln!(output, "# TYPE {name} gauge");
+    for snapshot in snapshots {
+        for priority in Priority::ALL {
+            let _ = writeln!(
+                output,
+                "{name}{{backend=\"{}\",priority=\"{priority}\"}} {}",
+                snapshot.backend,
+                snapshot.queue_depth.get(priority)
+            );
+        }
+    }
+    output
+}
+#[cfg(all(test, any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_snapshot() {
+        let metrics = Metrics::new("smtp");
+        metrics.queued();
+        metrics.queued();
+        metrics.delivered(true, Duration::from_millis(30));
+        metrics.delivered(false, Duration::from_secs(2));
+        metrics.delivered(true, Duration::from_secs(120));
+        let snapshot = metrics.snapshot(QueueDepth {
+            bulk: 4,
+            ..Default::default()
+        });
+        assert_eq!(snapshot.queued, 2);
+        assert_eq!(snapshot.sent, 2);
+        assert_eq!(snapshot.failed, 1);
+        assert_eq!(snapshot.latency.count, 3);
+        assert_eq!(snapshot.latency.buckets[0], (0.05, 1));
+        assert_eq!(snapshot.latency.buckets[5], (2.5, 2));
+        assert_eq!(snapshot.latency.buckets[9], (60.0, 2));
+
+        let text = snapshot.to_prometheus();
+        assert!(text.contains("any_mail_sent_total{backend=\"smtp\"} 2\n"));
+        assert!(text
+            .contains("any_mail_send_duration_seconds_bucket{backend=\"smtp\",le=\"+Inf\"} 3\n"));
+        assert!(text.contains("any_mail_queue_depth{backend=\"smtp\",priority=\"bulk\"} 4\n"));
+
+        let lmtp = MetricsSnapshot {
+            backend: "lmtp",
+            ..snapshot.clone()
+        };
+        let text = to_prometheus(&[snapshot, lmtp]);
+        assert_eq!(text.matches("# HELP any_mail_sent_total ").count(), 1);
+        assert_eq!(
+            text.matches("# TYPE any_mail_send_duration_seconds ")
+                .count(),
+            1
+        );
+        assert!(text.contains(
+            "any_mail_sent_total{backend=\"smtp\"} 2\nany_mail_sent_total{backend=\"lmtp\"} 2\n"
+        ));
+    }
+}
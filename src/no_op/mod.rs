@@ -1,10 +1,37 @@
-use std::{convert::Infallible, sync::Arc};
+use std::{convert::Infallible, sync::Arc, time::SystemTime};
 
 use tracing::info;
 
-use crate::{EmailAccess, MailService};
+use crate::{
+    email_types::MessageId,
+    events::{EventHandler, EventReceiver},
+    health::Health,
+    Email, EmailAccess, MailService, QueueDepth, Scheduled, ScheduledId, ServiceState,
+};
 #[derive(Debug, Clone)]
-pub struct NoOpAccess;
+pub struct NoOpAccess {
+    service_state: Arc<ServiceState>,
+}
+impl NoOpAccess {
+    pub fn new(service_state: Arc<ServiceState>) -> Self {
+        Self { service_state }
+    }
+}
+impl Default for NoOpAccess {
+    fn default() -> Self {
+        Self::new(ServiceState::new())
+    }
+}
+fn message_id(email: &impl Email) -> MessageId {
+    email.message_id().unwrap_or_else(|| {
+        MessageId::generate(
+            email
+                .from()
+                .map(|from| from.email.domain())
+                .unwrap_or("localhost"),
+        )
+    })
+}
 impl EmailAccess for NoOpAccess {
     type Error = Infallible;
 
@@ -16,9 +43,9 @@ impl EmailAccess for NoOpAccess {
         Arc::new(())
     }
 
-    fn send(&self, email: impl crate::Email) -> Result<(), Self::Error> {
+    fn send(&self, email: impl crate::Email) -> Result<MessageId, Self::Error> {
         info!("NoOpAccess: {:?}", email);
-        Ok(())
+        Ok(message_id(&email))
     }
 
     fn get_state(&self) -> std::sync::Arc<crate::shared::Mutex<Self::ConnectionState>> {
@@ -26,11 +53,55 @@ impl EmailAccess for NoOpAccess {
     }
 
     fn get_app_state(&self) -> std::sync::Arc<crate::ServiceState> {
-        crate::ServiceState::new()
+        self.service_state.clone()
+    }
+
+    fn send_at(&self, email: impl crate::Email, at: SystemTime) -> Result<Scheduled, Self::Error> {
+        info!("NoOpAccess: {:?} at {:?}", email, at);
+        Ok(Scheduled {
+            id: ScheduledId(0),
+            message_id: message_id(&email),
+        })
+    }
+
+    fn cancel_scheduled(&self, _: ScheduledId) -> bool {
+        false
+    }
+
+    fn on_event(&self, _: impl EventHandler + 'static) {}
+
+    /// Nothing is ever sent. The receiver is closed
+    fn subscribe(&self) -> EventReceiver {
+        crate::shared::unbdounded_channel().1
+    }
+
+    fn health(&self) -> Health {
+        Health {
+            worker_alive: true,
+            last_success: None,
+            last_error: None,
+            consecutive_failures: 0,
+            queue_depth: QueueDepth::default(),
+            rate_limit: None,
+            pool: None,
+        }
     }
 }
 
-pub struct NoOpService;
+#[derive(Debug)]
+pub struct NoOpService {
+    service_state: Arc<ServiceState>,
+}
+impl NoOpService {
+    pub fn new(service_state: Arc<ServiceState>) -> Self {
+        Self { service_state }
+    }
+}
+impl Default for NoOpService {
+    fn default() -> Self {
+        Self::new(ServiceState::new())
+    }
+}
 impl MailService for NoOpService {
     type Settings = ();
 
@@ -40,11 +111,14 @@ impl MailService for NoOpService {
 
     type ConnectionState = ();
 
-    async fn init(_: Self::Settings) -> Result<Self::Access, Self::Error>
+    async fn init_with_state(
+        _: Self::Settings,
+        service_state: Arc<ServiceState>,
+    ) -> Result<Self::Access, Self::Error>
     where
         Self: Sized,
     {
-        Ok(NoOpAccess)
+        Ok(NoOpAccess::new(service_state))
     }
 
     fn get_state(&self) -> std::sync::Arc<crate::shared::Mutex<Self::ConnectionState>> {
@@ -52,7 +126,7 @@ impl MailService for NoOpService {
     }
 
     fn get_app_state(&self) -> std::sync::Arc<crate::ServiceState> {
-        crate::ServiceState::new()
+        self.service_state.clone()
     }
 
     fn get_settings(&self) -> std::sync::Arc<Self::Settings> {
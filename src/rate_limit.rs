@@ -0,0 +1,214 @@
+/*!
+Paces how fast a service sends.
+
+A token bucket limits the rate and a rolling 24 hour window limits how many emails are sent a day.
+*/
+use std::time::SystemTime;
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+/// A rolling day
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+const DAY: Duration = Duration::from_secs(60 * 60 * 24);
+/// Everything is optional. The defaults do not limit anything
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash, Default)]
+#[serde(default)]
+pub struct RateLimitSettings {
+    /// How many emails can be sent every `period`. 0 for no limit
+    pub max_emails: u32,
+    /// In milliseconds. 0 is treated as one second
+    pub period: u64,
+    /// How many emails can be sent at once before pacing starts. 0 uses max_emails
+    pub burst: u32,
+    /// How many emails can be sent in any 24 hours. 0 for no limit
+    pub daily_limit: u32,
+}
+impl RateLimitSettings {
+    pub fn is_limited(&self) -> bool {
+        self.max_emails != 0 || self.daily_limit != 0
+    }
+    #[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+    fn period(&self) -> Duration {
+        if self.period == 0 {
+            Duration::from_secs(1)
+        } else {
+            Duration::from_millis(self.period)
+        }
+    }
+}
+/// A snapshot of the rate limiter
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RateLimitStatus {
+    /// Emails sent in the last 24 hours
+    pub sent_last_day: u32,
+    pub daily_limit: Option<u32>,
+    /// If the daily limit has been reached, when the next email can be sent
+    pub quota_exhausted_until: Option<SystemTime>,
+}
+impl RateLimitStatus {
+    pub fn is_quota_exhausted(&self) -> bool {
+        self.quota_exhausted_until.is_some()
+    }
+}
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+#[derive(Debug)]
+struct Limits {
+    tokens: f64,
+    last_refill: Instant,
+    sent: VecDeque<Instant>,
+}
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    settings: RateLimitSettings,
+    limits: Mutex<Limits>,
+}
+#[cfg(all(any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+impl RateLimiter {
+    /// None if the settings do not limit anything
+    pub fn new(settings: &RateLimitSettings) -> Option<Self> {
+        if !settings.is_limited() {
+            return None;
+        }
+        Some(Self {
+            limits: Mutex::new(Limits {
+                tokens: f64::from(Self::burst(settings)),
+                last_refill: Instant::now(),
+                sent: VecDeque::new(),
+            }),
+            settings: settings.clone(),
+        })
+    }
+    fn burst(settings: &RateLimitSettings) -> u32 {
+        if settings.burst == 0 {
+            settings.max_emails
+        } else {
+            settings.burst
+        }
+    }
+    /// Takes a slot to send an email. If there is none, returns when to try again
+    fn reserve(&self, now: Instant) -> Result<(), Instant> {
+        let mut limits = self.limits.lock();
+        let settings = &self.settings;
+        while limits
+            .sent
+            .front()
+            .is_some_and(|sent| now.duration_since(*sent) >= DAY)
+        {
+            limits.sent.pop_front();
+        }
+        if settings.daily_limit != 0 && limits.sent.len() >= settings.daily_limit as usize {
+            return Err(limits.sent[0] + DAY);
+        }
+        if settings.max_emails != 0 {
+            let per_token = settings.period().as_secs_f64() / f64::from(settings.max_emails);
+            let elapsed = now.duration_since(limits.last_refill).as_secs_f64();
+            limits.tokens =
+                (limits.tokens + elapsed / per_token).min(f64::from(Self::burst(settings)));
+            limits.last_refill = now;
+            if limits.tokens < 1.0 {
+                return Err(now + Duration::from_secs_f64((1.0 - limits.tokens) * per_token));
+            }
+            limits.tokens -= 1.0;
+        }
+        if settings.daily_limit != 0 {
+            limits.sent.push_back(now);
+        }
+        Ok(())
+    }
+    /// Takes a token if one is free without waiting
+    pub fn try_acquire(&self) -> bool {
+        self.reserve(Instant::now()).is_ok()
+    }
+    /// Waits until an email can be sent
+    #[cfg(any(feature = "tokio", feature = "smol"))]
+    pub async fn acquire(&self) {
+        let mut warned = false;
+        loop {
+            let retry_at = match self.reserve(Instant::now()) {
+                Ok(()) => return,
+                Err(retry_at) => retry_at,
+            };
+            if !warned && self.status().is_quota_exhausted() {
+                tracing::warn!("Daily email limit reached. Waiting until it resets");
+                warned = true;
+            }
+            crate::rt::sleep_until(retry_at).await;
+        }
+    }
+
+    pub fn status(&self) -> RateLimitStatus {
+        let now = Instant::now();
+        let limits = self.limits.lock();
+        let sent_last_day = limits
+            .sent
+            .iter()
+            .filter(|sent| now.duration_since(**sent) < DAY)
+            .count() as u32;
+        let daily_limit = (self.settings.daily_limit != 0).then_some(self.settings.daily_limit);
+        let quota_exhausted_until = daily_limit
+            .filter(|limit| sent_last_day >= *limit)
+            .and_then(|_| {
+                limits
+                    .sent
+                    .iter()
+                    .find(|sent| now.duration_since(**sent) < DAY)
+            })
+            .map(|oldest| SystemTime::now() + (*oldest + DAY).duration_since(now));
+        RateLimitStatus {
+            sent_last_day,
+            daily_limit,
+            quota_exhausted_until,
+        }
+    }
+}
+#[cfg(all(test, any(feature = "tokio", feature = "smol"), feature = "lettre"))]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_no_limits() {
+        assert!(RateLimiter::new(&RateLimitSettings::default()).is_none());
+    }
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(&RateLimitSettings {
+            max_emails: 2,
+            period: 1000,
+            ..Default::default()
+        })
+        .unwrap();
+        let now = Instant::now();
+        assert!(limiter.reserve(now).is_ok());
+        assert!(limiter.reserve(now).is_ok());
+        let retry_at = limiter.reserve(now).unwrap_err();
+        assert_eq!(retry_at, now + Duration::from_millis(500));
+        assert!(limiter.reserve(retry_at).is_ok());
+    }
+    #[test]
+    fn test_daily_limit() {
+        let limiter = RateLimiter::new(&RateLimitSettings {
+            daily_limit: 2,
+            ..Default::default()
+        })
+        .unwrap();
+        let now = Instant::now();
+        assert!(limiter.reserve(now).is_ok());
+        assert!(!limiter.status().is_quota_exhausted());
+        assert!(limiter.reserve(now + Duration::from_secs(60)).is_ok());
+        assert_eq!(
+            limiter.reserve(now + Duration::from_secs(120)),
+            Err(now + DAY)
+        );
+        let status = limiter.status();
+        assert_eq!(status.sent_last_day, 2);
+        assert!(status.is_quota_exhausted());
+        // The first email is out of the window
+        assert!(limiter.reserve(now + DAY).is_ok());
+    }
+}
@@ -0,0 +1,137 @@
+/*!
+Keeps staging and QA environments from emailing real people.
+
+Set `safety` in the settings of a service. It is applied to every email before it is queued.
+```toml
+[safety]
+mode = "Redirect"
+to = "qa-inbox@example.com"
+```
+```toml
+[safety]
+mode = "Allowlist"
+allowed = ["example.com", "someone@partner.com"]
+```
+*/
+#[cfg(feature = "smtp")]
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "smtp")]
+use tracing::info;
+
+use crate::email_types::Mailbox;
+/// The header the original recipients are kept in by [SafetySettings::Redirect]. Not `X-Original-To` as delivery agents set that one
+pub const ORIGINAL_TO_HEADER: &str = "X-Any-Mail-Original-To";
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+#[serde(tag = "mode")]
+pub enum SafetySettings {
+    /// Every email is sent to `to` instead.
+    ///
+    /// The original recipients are kept in the [ORIGINAL_TO_HEADER] header and added to the subject
+    Redirect { to: Mailbox },
+    /// Recipients that are not allowed are removed. An email without any allowed recipients is not sent
+    ///
+    /// An entry is either a domain such as `example.com` or an address such as `someone@example.com`
+    Allowlist { allowed: Vec<String> },
+}
+/// The recipients and subject an email is sent with
+#[cfg(feature = "smtp")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Guarded {
+    pub to: Vec<Mailbox>,
+    pub subject: Cow<'static, str>,
+    /// The value of [ORIGINAL_TO_HEADER]
+    pub original_to: Option<String>,
+}
+#[cfg(feature = "smtp")]
+impl SafetySettings {
+    /// Empty `to` if no recipient is allowed
+    pub(crate) fn guard(&self, to: Vec<Mailbox>, subject: Cow<'static, str>) -> Guarded {
+        match self {
+            SafetySettings::Redirect { to: catch_all } => {
+                let original = to
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                Guarded {
+                    to: vec![catch_all.clone()],
+                    subject: Cow::Owned(format!("[To: {original}] {subject}")),
+                    original_to: Some(original),
+                }
+            }
+            SafetySettings::Allowlist { allowed } => {
+                let (to, removed): (Vec<_>, Vec<_>) =
+                    to.into_iter().partition(|to| self.is_allowed(allowed, to));
+                for removed in removed {
+                    info!("{} is not on the allowlist. It was removed", removed.email);
+                }
+                Guarded {
+                    to,
+                    subject,
+                    original_to: None,
+                }
+            }
+        }
+    }
+    fn is_allowed(&self, allowed: &[String], to: &Mailbox) -> bool {
+        let address = to.email.as_ref();
+        let domain = to.email.domain();
+        allowed.iter().any(|allowed| {
+            if allowed.contains('@') {
+                allowed.eq_ignore_ascii_case(address)
+            } else {
+                allowed.eq_ignore_ascii_case(domain)
+            }
+        })
+    }
+}
+#[cfg(all(test, feature = "smtp"))]
+mod tests {
+    use super::*;
+    fn mailbox(address: &str) -> Mailbox {
+        Mailbox::try_from(address).unwrap()
+    }
+    #[test]
+    fn test_redirect() {
+        let safety = SafetySettings::Redirect {
+            to: mailbox("qa@example.com"),
+        };
+        let guarded = safety.guard(
+            vec![mailbox("a@customer.com"), mailbox("b@customer.com")],
+            "Receipt".into(),
+        );
+        assert_eq!(guarded.to, [mailbox("qa@example.com")]);
+        assert_eq!(
+            guarded.subject,
+            "[To: a@customer.com, b@customer.com] Receipt"
+        );
+        assert_eq!(
+            guarded.original_to.as_deref(),
+            Some("a@customer.com, b@customer.com")
+        );
+    }
+    #[test]
+    fn test_allowlist() {
+        let safety: SafetySettings = toml::from_str(
+            "mode = \"Allowlist\"\nallowed = [\"Example.com\", \"someone@partner.com\"]",
+        )
+        .unwrap();
+        let guarded = safety.guard(
+            vec![
+                mailbox("a@example.com"),
+                mailbox("someone@partner.com"),
+                mailbox("other@partner.com"),
+                mailbox("a@customer.com"),
+            ],
+            "Receipt".into(),
+        );
+        assert_eq!(
+            guarded.to,
+            [mailbox("a@example.com"), mailbox("someone@partner.com")]
+        );
+        assert_eq!(guarded.subject, "Receipt");
+        assert_eq!(guarded.original_to, None);
+    }
+}
@@ -0,0 +1,198 @@
+/*!
+Passwords and API keys in settings.
+
+A [Secret] is never printed. `Debug`, `Display` and `Serialize` only show where it came from.
+
+Serializing an inline secret writes `[REDACTED]` in its place. Reading `[REDACTED]` back is an error,
+so settings written out with an inline secret can not be loaded with a placeholder as the password.
+Use `Env` or `File` for settings that are written back.
+
+In a config file a secret is a plain string or where to read it from:
+```toml
+password = "literal value"
+password = { Env = "SMTP_PASSWORD" }
+password = { File = "/run/secrets/smtp_password" }
+```
+*/
+use std::{
+    fmt::{Debug, Display},
+    path::{Path, PathBuf},
+};
+
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+const REDACTED: &str = "[REDACTED]";
+#[derive(Debug, Error)]
+pub enum SecretError {
+    #[error("Unable to read the environment variable {name}: {source}")]
+    Env {
+        name: String,
+        source: std::env::VarError,
+    },
+    #[error("Unable to read the secret file {path:?}: {source}")]
+    File {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+/// Where a secret was loaded from
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+enum Origin {
+    #[default]
+    Inline,
+    Env(String),
+    File(PathBuf),
+}
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum Repr {
+    Inline(String),
+    Source(Source),
+}
+#[derive(Deserialize, Serialize)]
+enum Source {
+    Env(String),
+    File(PathBuf),
+    Inline(String),
+}
+/// A password or key that is redacted when printed or serialized
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct Secret {
+    value: String,
+    origin: Origin,
+}
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self {
+            value: value.into(),
+            origin: Origin::Inline,
+        }
+    }
+    pub fn from_env(name: impl Into<String>) -> Result<Self, SecretError> {
+        let name = name.into();
+        match std::env::var(&name) {
+            Ok(value) => Ok(Self {
+                value,
+                origin: Origin::Env(name),
+            }),
+            Err(source) => Err(SecretError::Env { name, source }),
+        }
+    }
+    /// Trailing new lines are removed. Such as the ones in Docker and Kubernetes secrets
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, SecretError> {
+        let path = path.as_ref().to_path_buf();
+        match std::fs::read_to_string(&path) {
+            Ok(value) => Ok(Self {
+                value: value.trim_end_matches(['\r', '\n']).to_owned(),
+                origin: Origin::File(path),
+            }),
+            Err(source) => Err(SecretError::File { path, source }),
+        }
+    }
+    /// The actual value. Do not log it
+    pub fn expose_secret(&self) -> &str {
+        &self.value
+    }
+    pub fn is_empty(&self) -> bool {
+        self.value.is_empty()
+    }
+}
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self::new(value)
+    }
+}
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Self::new(value)
+    }
+}
+impl Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.origin {
+            Origin::Inline => write!(f, "Secret({REDACTED})"),
+            Origin::Env(name) => write!(f, "Secret(Env({name:?}))"),
+            Origin::File(path) => write!(f, "Secret(File({path:?}))"),
+        }
+    }
+}
+impl Display for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+impl Serialize for Secret {
+    /// Env and File secrets are written as their source. Inline secrets are redacted unless empty
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let repr = match &self.origin {
+            Origin::Inline if self.value.is_empty() => Repr::Inline(String::new()),
+            Origin::Inline => Repr::Inline(REDACTED.to_owned()),
+            Origin::Env(name) => Repr::Source(Source::Env(name.clone())),
+            Origin::File(path) => Repr::Source(Source::File(path.clone())),
+        };
+        repr.serialize(serializer)
+    }
+}
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match Repr::deserialize(deserializer)? {
+            Repr::Inline(value) | Repr::Source(Source::Inline(value)) if value == REDACTED => Err(
+                D::Error::custom("The secret was redacted when written. Set the value again"),
+            ),
+            Repr::Inline(value) | Repr::Source(Source::Inline(value)) => Ok(Self::new(value)),
+            Repr::Source(Source::Env(name)) => Self::from_env(name).map_err(D::Error::custom),
+            Repr::Source(Source::File(path)) => Self::from_file(path).map_err(D::Error::custom),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Settings {
+        password: Secret,
+    }
+    #[test]
+    fn test_redacted() {
+        let secret = Secret::new("hunter2");
+        assert_eq!(format!("{secret:?}"), "Secret([REDACTED])");
+        assert_eq!(secret.to_string(), "[REDACTED]");
+        let settings = toml::to_string(&Settings { password: secret }).unwrap();
+        assert!(!settings.contains("hunter2"));
+        // A redacted secret is not read back as the password
+        let error = toml::from_str::<Settings>(&settings).unwrap_err();
+        assert!(error.to_string().contains("redacted"));
+        assert_eq!(Secret::default().expose_secret(), "");
+    }
+    #[test]
+    fn test_sources() {
+        let settings: Settings = toml::from_str(r#"password = "hunter2""#).unwrap();
+        assert_eq!(settings.password.expose_secret(), "hunter2");
+
+        let path = std::env::temp_dir().join(format!("any_mail-secret-{}", std::process::id()));
+        std::fs::write(&path, "from a file\n").unwrap();
+        let settings: Settings =
+            toml::from_str(&format!("password = {{ File = {:?} }}", path.display())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(settings.password.expose_secret(), "from a file");
+        // The path is kept so the settings can be written back
+        let written = toml::to_string(&settings).unwrap();
+        assert!(written.contains("File"));
+        assert!(!written.contains("from a file"));
+
+        let settings: Settings =
+            toml::from_str(r#"password = { Env = "CARGO_PKG_NAME" }"#).unwrap();
+        assert_eq!(settings.password.expose_secret(), "any_mail");
+        assert_eq!(
+            format!("{:?}", settings.password),
+            r#"Secret(Env("CARGO_PKG_NAME"))"#
+        );
+
+        let missing = toml::from_str::<Settings>(r#"password = { Env = "ANY_MAIL_NOT_SET" }"#);
+        assert!(missing
+            .unwrap_err()
+            .to_string()
+            .contains("ANY_MAIL_NOT_SET"));
+    }
+}
@@ -3,7 +3,7 @@
 /*!
 Re-exports of some shared types that are used by multiple mail services.
 */
-pub use flume::{Receiver, Sender};
+pub use flume::{Receiver, Sender, TrySendError, WeakSender};
 pub use parking_lot::Mutex;
 /// Create an unbounded channel.
 pub fn unbdounded_channel<T>() -> (Sender<T>, Receiver<T>) {
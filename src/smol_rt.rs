@@ -0,0 +1,152 @@
+#[cfg(feature = "lettre")]
+use std::time::{Duration, Instant};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use async_signal::{Signal, Signals};
+use event_listener::Event;
+use futures_util::StreamExt;
+/// The Service State handles watching for a shutdown signal.
+///
+/// One can be shared by every service with [MailService::init_with_state](crate::MailService::init_with_state).
+///
+/// This is the smol Implementation.
+///
+/// Ordering is Relaxed because the only time it is changed is when the service is shutting down.
+#[derive(Debug)]
+pub struct ServiceState {
+    pub notify: Event,
+    pub running: AtomicBool,
+    /// Ctrl-C and SIGTERM shut the services down
+    handle_signals: bool,
+    watching: AtomicBool,
+    /// Workers that have not stopped yet
+    workers: AtomicUsize,
+    stopped: Event,
+}
+impl ServiceState {
+    /// Creates a new Service State. Ctrl-C and SIGTERM shut it down
+    pub fn new() -> Arc<Self> {
+        Self::create(true)
+    }
+    /// The application calls [ServiceState::shutdown] itself
+    pub fn without_signal_handling() -> Arc<Self> {
+        Self::create(false)
+    }
+    fn create(handle_signals: bool) -> Arc<Self> {
+        Arc::new(Self {
+            notify: Event::new(),
+            running: AtomicBool::new(true),
+            handle_signals,
+            watching: AtomicBool::new(false),
+            workers: AtomicUsize::new(0),
+            stopped: Event::new(),
+        })
+    }
+    /// Starts a task that watches for a shutdown signal.
+    ///
+    /// Does nothing if signal handling is disabled or it is already watching
+    pub fn watch_for_shutdown(this: Arc<Self>) {
+        if !this.handle_signals || this.watching.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        spawn(async move {
+            match Signals::new([Signal::Int, Signal::Term]) {
+                Ok(mut signals) => {
+                    signals.next().await;
+                }
+                Err(e) => tracing::error!("Failed to watch for shutdown: {}", e),
+            }
+            this.shutdown();
+        });
+    }
+    /// Checks if the service is running.
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+    /// Shuts down the service.
+    pub fn shutdown(&self) {
+        self.running.store(false, Ordering::Relaxed);
+
+        self.notify.notify(usize::MAX);
+        if self.workers.load(Ordering::Relaxed) == 0 {
+            self.stopped.notify(usize::MAX);
+        }
+    }
+    /// Completes once the services are shut down and every email that was being sent is finished
+    pub async fn join(&self) {
+        loop {
+            let stopped = self.stopped.listen();
+            if !self.is_running() && self.workers.load(Ordering::Relaxed) == 0 {
+                return;
+            }
+            stopped.await;
+        }
+    }
+    /// Completes when the service is told to shut down. Create it before checking anything it should not miss
+    #[cfg(feature = "lettre")]
+    pub(crate) fn notified(&self) -> impl Future<Output = ()> + Send + '_ {
+        self.notify.listen()
+    }
+    /// Held by a worker until it stops
+    #[cfg(feature = "lettre")]
+    pub(crate) fn worker_started(self: &Arc<Self>) -> WorkerGuard {
+        self.workers.fetch_add(1, Ordering::Relaxed);
+        WorkerGuard(self.clone())
+    }
+}
+#[cfg(feature = "lettre")]
+pub(crate) struct WorkerGuard(Arc<ServiceState>);
+#[cfg(feature = "lettre")]
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        if self.0.workers.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.0.stopped.notify(usize::MAX);
+        }
+    }
+}
+/// Runs the future in the background
+pub(crate) fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+    smol::spawn(future).detach();
+}
+/// Runs blocking code, such as writing to disk, on a thread that does not run tasks
+#[cfg(feature = "lettre")]
+pub(crate) async fn spawn_blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    smol::unblock(f).await
+}
+#[cfg(feature = "lettre")]
+pub(crate) async fn sleep(duration: Duration) {
+    smol::Timer::after(duration).await;
+}
+#[cfg(feature = "lettre")]
+pub(crate) async fn sleep_until(deadline: Instant) {
+    smol::Timer::at(deadline).await;
+}
+#[cfg(all(test, feature = "lettre"))]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_shutdown() {
+        let state = ServiceState::without_signal_handling();
+        smol::block_on(async {
+            let notified = state.notified();
+            let worker = state.worker_started();
+            let (sender, receiver) = flume::bounded(1);
+            spawn(async move {
+                sleep(Duration::from_millis(10)).await;
+                sender.send_async(()).await.unwrap();
+                drop(worker);
+            });
+            receiver.recv_async().await.unwrap();
+            state.shutdown();
+            notified.await;
+            state.join().await;
+        });
+        assert!(!state.is_running());
+    }
+}
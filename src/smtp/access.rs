@@ -1,40 +1,16 @@
-use std::sync::Arc;
-
-use lettre::{message::MessageBuilder, Message};
-
-use super::{ConnectionState, SMTPError, SMTPServiceSettings, SharedConnectionState};
-use crate::{shared::Sender, Email, EmailAccess, ServiceState};
-
-#[derive(Debug, Clone)]
-pub struct SMTPEmailAccess {
-    pub(super) settings: Arc<SMTPServiceSettings>,
-    pub(super) queue: Sender<Message>,
-    pub(super) message_builder: MessageBuilder,
-    pub(super) state: SharedConnectionState,
-    pub(super) service_state: Arc<ServiceState>,
-}
-impl EmailAccess for SMTPEmailAccess {
-    type Error = SMTPError;
-
-    type Settings = SMTPServiceSettings;
-    type ConnectionState = ConnectionState;
-
-    fn send(&self, email: impl Email) -> Result<(), Self::Error> {
-        let message =
-            super::email_to_message(self.message_builder.clone(), self.settings.as_ref(), email)?;
-        self.queue.send(message)?;
-        Ok(())
-    }
-
-    fn get_settings(&self) -> Arc<Self::Settings> {
-        self.settings.clone()
-    }
-
-    fn get_state(&self) -> Arc<crate::shared::Mutex<Self::ConnectionState>> {
-        self.state.clone()
-    }
-
-    fn get_app_state(&self) -> Arc<crate::ServiceState> {
-        self.service_state.clone()
+use super::{SMTPError, SMTPService, SMTPServiceSettings};
+use crate::worker::WorkerAccess;
+
+pub type SMTPEmailAccess = WorkerAccess<SMTPService>;
+impl SMTPEmailAccess {
+    /// Switches to the new settings once a test connection with them succeeds. Such as to rotate credentials or change the host.
+    ///
+    /// Emails that are being sent finish on the old connection and queued emails are sent with the new one.
+    /// If the test fails the current settings are kept.
+    ///
+    /// The pool size, rate limit, queue and spool are kept from [init](crate::MailService::init).
+    /// Changing DKIM or security fails with [SMTPError::NotReloadable]
+    pub async fn reload(&self, settings: SMTPServiceSettings) -> Result<(), SMTPError> {
+        self.service().reload(settings).await
     }
 }
@@ -0,0 +1,189 @@
+use std::{
+    error::Error,
+    fmt::Debug,
+    future::Future,
+    time::{Duration, SystemTime},
+};
+
+use async_lock::Mutex;
+use futures_util::future::BoxFuture;
+use lettre::transport::smtp::authentication::Mechanism;
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumIs, EnumString, VariantNames};
+use tracing::debug;
+
+/// How the client authenticates with the server
+#[derive(
+    Debug,
+    Deserialize,
+    Serialize,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    Display,
+    EnumString,
+    EnumIs,
+    VariantNames,
+)]
+pub enum SMTPAuthentication {
+    /// PLAIN or LOGIN. Nothing is sent if the username is empty
+    #[default]
+    Auto,
+    /// No authentication
+    NONE,
+    Plain,
+    Login,
+    /// OAuth2. Used by Microsoft 365 and Google Workspace
+    ///
+    /// The password is used as the access token unless a [TokenProvider] is given to
+    /// [SMTPService::init_with_token_provider](super::SMTPService::init_with_token_provider)
+    XOAuth2,
+}
+impl SMTPAuthentication {
+    /// The mechanisms lettre may use. None if no credentials are sent
+    pub(crate) fn mechanisms(&self, username: &str) -> Option<Vec<Mechanism>> {
+        match self {
+            SMTPAuthentication::Auto if username.is_empty() => None,
+            SMTPAuthentication::Auto => Some(vec![Mechanism::Plain, Mechanism::Login]),
+            SMTPAuthentication::NONE => None,
+            SMTPAuthentication::Plain => Some(vec![Mechanism::Plain]),
+            SMTPAuthentication::Login => Some(vec![Mechanism::Login]),
+            SMTPAuthentication::XOAuth2 => Some(vec![Mechanism::Xoauth2]),
+        }
+    }
+}
+/// An OAuth2 access token
+#[derive(Clone, PartialEq, Eq)]
+pub struct AccessToken {
+    pub token: String,
+    /// None if it does not expire
+    pub expires_at: Option<SystemTime>,
+}
+impl Debug for AccessToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccessToken")
+            .field("expires_at", &self.expires_at)
+            .finish_non_exhaustive()
+    }
+}
+pub type TokenError = Box<dyn Error + Send + Sync>;
+/// Gets access tokens for XOAUTH2. Such as with a refresh token or client credentials
+pub trait TokenProvider: Send + Sync {
+    fn access_token(&self) -> BoxFuture<'_, Result<AccessToken, TokenError>>;
+}
+impl<F, Fut> TokenProvider for F
+where
+    F: Fn() -> Fut + Send + Sync,
+    Fut: Future<Output = Result<AccessToken, TokenError>> + Send + 'static,
+{
+    fn access_token(&self) -> BoxFuture<'_, Result<AccessToken, TokenError>> {
+        Box::pin(self())
+    }
+}
+/// Tokens are refreshed this long before they expire
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+/// Keeps the current token and refreshes it before it expires
+pub(crate) struct TokenRefresher {
+    provider: Box<dyn TokenProvider>,
+    /// None until the first token is fetched
+    current: Mutex<Option<AccessToken>>,
+}
+impl Debug for TokenRefresher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenRefresher")
+            .field("current", &self.current)
+            .finish_non_exhaustive()
+    }
+}
+impl TokenRefresher {
+    pub fn new(provider: impl TokenProvider + 'static) -> Self {
+        Self {
+            provider: Box::new(provider),
+            current: Mutex::new(None),
+        }
+    }
+    /// A new token if the current one is about to expire
+    pub async fn refresh_if_needed(&self) -> Result<Option<String>, TokenError> {
+        let mut current = self.current.lock().await;
+        let expiring = match current.as_ref() {
+            Some(token) => token
+                .expires_at
+                .is_some_and(|at| at <= SystemTime::now() + REFRESH_MARGIN),
+            None => true,
+        };
+        if !expiring {
+            return Ok(None);
+        }
+        let token = self.provider.access_token().await?;
+        debug!(
+            "Refreshed the access token. Expires at {:?}",
+            token.expires_at
+        );
+        *current = Some(token.clone());
+        Ok(Some(token.token))
+    }
+    /// The last token. Even if it is about to expire
+    pub async fn current(&self) -> Option<String> {
+        self.current
+            .lock()
+            .await
+            .as_ref()
+            .map(|token| token.token.clone())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    #[test]
+    fn test_mechanisms() {
+        assert_eq!(SMTPAuthentication::Auto.mechanisms(""), None);
+        assert_eq!(
+            SMTPAuthentication::Auto.mechanisms("user"),
+            Some(vec![Mechanism::Plain, Mechanism::Login])
+        );
+        assert_eq!(SMTPAuthentication::NONE.mechanisms("user"), None);
+        assert_eq!(
+            SMTPAuthentication::XOAuth2.mechanisms("user"),
+            Some(vec![Mechanism::Xoauth2])
+        );
+    }
+    #[tokio::test]
+    async fn test_refresh() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let provider = {
+            let calls = calls.clone();
+            move || {
+                let call = calls.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    Ok(AccessToken {
+                        token: format!("token-{call}"),
+                        expires_at: Some(SystemTime::now() + Duration::from_secs(3600)),
+                    })
+                }
+            }
+        };
+        let refresher = TokenRefresher::new(provider);
+        assert_eq!(
+            refresher.refresh_if_needed().await.unwrap().as_deref(),
+            Some("token-0")
+        );
+        // Still valid
+        assert_eq!(refresher.refresh_if_needed().await.unwrap(), None);
+        assert_eq!(refresher.current().await.as_deref(), Some("token-0"));
+        refresher.current.lock().await.as_mut().unwrap().expires_at =
+            Some(SystemTime::now() + Duration::from_secs(30));
+        assert_eq!(
+            refresher.refresh_if_needed().await.unwrap().as_deref(),
+            Some("token-1")
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,190 @@
+use std::sync::Arc;
+
+use lettre::message::{
+    dkim::{
+        DkimCanonicalization as LettreCanonicalization, DkimCanonicalizationType, DkimConfig,
+        DkimSigningAlgorithm, DkimSigningKey, DkimSigningKeyError,
+    },
+    header::HeaderName,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::KeySource;
+
+#[derive(Debug, Error)]
+pub enum DkimError {
+    #[error("Unable to read the DKIM private key: {0}")]
+    ReadKey(#[from] std::io::Error),
+    #[error("Invalid DKIM private key: {0}")]
+    InvalidKey(#[from] DkimSigningKeyError),
+    #[error("Invalid header name to sign: {0}")]
+    InvalidHeader(String),
+}
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum DkimAlgorithm {
+    /// The key is a PKCS#1 PEM
+    #[default]
+    Rsa,
+    /// The key is the base64 encoded 32 byte seed
+    Ed25519,
+}
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Canonicalization {
+    Simple,
+    #[default]
+    Relaxed,
+}
+impl From<Canonicalization> for DkimCanonicalizationType {
+    fn from(value: Canonicalization) -> Self {
+        match value {
+            Canonicalization::Simple => Self::Simple,
+            Canonicalization::Relaxed => Self::Relaxed,
+        }
+    }
+}
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(default)]
+pub struct DkimCanonicalization {
+    pub header: Canonicalization,
+    pub body: Canonicalization,
+}
+/// Signs every outgoing message with DKIM
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub struct DkimSettings {
+    /// The name of the key published in DNS. `{selector}._domainkey.{domain}`
+    pub selector: String,
+    pub domain: String,
+    #[serde(default)]
+    pub algorithm: DkimAlgorithm,
+    pub private_key: KeySource,
+    /// The headers included in the signature. A header can only be listed once
+    #[serde(default = "default_headers")]
+    pub headers: Vec<String>,
+    #[serde(default)]
+    pub canonicalization: DkimCanonicalization,
+}
+fn default_headers() -> Vec<String> {
+    ["From", "To", "Subject", "Date"]
+        .into_iter()
+        .map(ToOwned::to_owned)
+        .collect()
+}
+impl DkimSettings {
+    /// Loads the key and builds the signing config
+    pub(crate) fn config(&self) -> Result<DkimConfig, DkimError> {
+        let key = self.private_key.read()?;
+        let algorithm = match self.algorithm {
+            DkimAlgorithm::Rsa => DkimSigningAlgorithm::Rsa,
+            DkimAlgorithm::Ed25519 => DkimSigningAlgorithm::Ed25519,
+        };
+        let key = DkimSigningKey::new(key.trim(), algorithm)?;
+        let headers = self
+            .headers
+            .iter()
+            .map(|header| {
+                HeaderName::new_from_ascii(header.clone())
+                    .map_err(|_| DkimError::InvalidHeader(header.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(DkimConfig::new(
+            self.selector.clone(),
+            self.domain.clone(),
+            key,
+            headers,
+            LettreCanonicalization {
+                header: self.canonicalization.header.into(),
+                body: self.canonicalization.body.into(),
+            },
+        ))
+    }
+}
+/// Builds the signing config when the service starts so a bad key is reported by init
+pub(crate) fn load_dkim(
+    settings: Option<&DkimSettings>,
+) -> Result<Option<Arc<DkimConfig>>, DkimError> {
+    settings
+        .map(|settings| settings.config().map(Arc::new))
+        .transpose()
+}
+#[cfg(test)]
+mod tests {
+    use lettre::message::MessageBuilder;
+
+    use super::*;
+    use crate::{
+        email_types::Mailbox, smtp::SMTPServiceSettings, template::EmailBody, SimpleEmail,
+    };
+    /// The Ed25519 key from RFC 8463
+    const KEY: &str = "nWGxne/9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2A=";
+    fn settings() -> DkimSettings {
+        DkimSettings {
+            selector: "brisbane".to_owned(),
+            domain: "example.com".to_owned(),
+            algorithm: DkimAlgorithm::Ed25519,
+            private_key: KeySource::Inline(KEY.into()),
+            headers: default_headers(),
+            canonicalization: DkimCanonicalization::default(),
+        }
+    }
+    #[test]
+    fn test_sign() {
+        let dkim = load_dkim(Some(&settings())).unwrap().unwrap();
+        let from = Mailbox::try_from("sender@example.com").unwrap();
+        let email = SimpleEmail {
+            subject: "Signed",
+            body: Some(EmailBody {
+                html_body: None,
+                text_body: Some("Hello".to_owned()),
+                calendar: None,
+            }),
+            to: Mailbox::try_from("user@example.com").unwrap(),
+            from: Some(from.clone()),
+        };
+        let settings = crate::smtp::SMTPServiceSettings {
+            from,
+            ..Default::default()
+        };
+        let (message, _) = super::super::email_to_message(
+            MessageBuilder::new(),
+            &settings,
+            Some(&dkim),
+            None,
+            email,
+        )
+        .unwrap();
+        let formatted = String::from_utf8(message.formatted()).unwrap();
+        // The header is folded over several lines
+        let (_, signature) = formatted.split_once("DKIM-Signature: ").unwrap();
+        let (signature, _) = signature.split_once("\r\nContent-Type").unwrap();
+        assert!(signature.contains("a=ed25519-sha256"));
+        assert!(signature.contains("d=example.com"));
+        assert!(signature.contains("s=brisbane"));
+        assert!(signature.contains("c=relaxed/relaxed"));
+        assert!(signature.contains("h=from:to:subject:date"));
+    }
+    #[test]
+    fn test_invalid_settings() {
+        let mut invalid_header = settings();
+        invalid_header.headers.push("Not A Header".to_owned());
+        assert!(matches!(
+            invalid_header.config(),
+            Err(DkimError::InvalidHeader(_))
+        ));
+        let missing_key = DkimSettings {
+            private_key: KeySource::File("/nonexistent/dkim.key".into()),
+            ..settings()
+        };
+        assert!(matches!(missing_key.config(), Err(DkimError::ReadKey(_))));
+        assert!(load_dkim(None).unwrap().is_none());
+    }
+    #[test]
+    fn test_inline_key_is_not_printed() {
+        let settings = SMTPServiceSettings {
+            dkim: Some(settings()),
+            ..Default::default()
+        };
+        assert!(!format!("{settings:?}").contains(KEY));
+        assert!(!toml::to_string(&settings).unwrap().contains(KEY));
+    }
+}
@@ -1,33 +1,56 @@
 mod access;
+mod auth;
+mod dkim;
+mod pool;
+mod secure;
 mod settings;
-use std::{mem, sync::Arc};
+mod tls;
+use std::sync::Arc;
 
 #[doc(inline)]
 pub use access::*;
+#[doc(inline)]
+pub use auth::*;
+#[doc(inline)]
+pub use dkim::*;
 use lettre::{
-    message::{header, Mailbox as SMTPMailBox, MessageBuilder, MultiPart, SinglePart},
-    transport::smtp::{
-        authentication::Credentials,
-        client::{Tls, TlsParameters},
+    message::{
+        dkim::DkimConfig,
+        header::{self, HeaderName, HeaderValue},
+        Mailbox as SMTPMailBox, MessageBuilder, MultiPart, SinglePart,
     },
+    transport::smtp::{authentication::Credentials, client::Tls, response::Response},
     AsyncSmtpTransport, Message,
 };
+use parking_lot::RwLock;
+#[doc(inline)]
+pub use pool::*;
+#[doc(inline)]
+pub use secure::*;
 #[doc(inline)]
 pub use settings::*;
 use thiserror::Error;
-use tracing::{debug, error, instrument, warn};
+#[doc(inline)]
+pub use tls::*;
+use tracing::{debug, instrument, warn};
 
 use crate::{
-    email_types::{Address, Mailbox},
-    shared::{bounded_channel, unbdounded_channel, Mutex, Receiver},
+    email_types::{Address, Mailbox, MessageId},
+    health::PoolStatus,
+    rate_limit::RateLimiter,
+    safety::{Guarded, ORIGINAL_TO_HEADER},
+    shared::Mutex,
     template::EmailBody,
-    Email, MailService, ServiceState,
+    worker::{self, Backend, QueueError, RawEmail},
+    Email, EmailSettingsType, MailService, ServiceState,
 };
 
 #[derive(Debug, Error)]
 pub enum SMTPError {
-    #[error("The email queue is full")]
-    QueueError(#[from] flume::SendError<Message>),
+    #[error(transparent)]
+    QueueError(#[from] QueueError),
+    #[error("Unable to open the spool: {0}")]
+    SpoolError(std::io::Error),
     #[error(transparent)]
     InvalidEmailAddress(#[from] lettre::address::AddressError),
     #[error(transparent)]
@@ -36,15 +59,31 @@ pub enum SMTPError {
     NoBodyProvided,
     #[error("No To Address was Provided")]
     NoToAddressProvided,
+    #[error("No recipient is on the allowlist")]
+    NoAllowedRecipients,
+    #[error("Email Transport not initialized")]
+    TransportNotInitialized,
     #[error(transparent)]
     SendError(#[from] lettre::transport::smtp::Error),
+    #[error(transparent)]
+    Dkim(#[from] DkimError),
+    #[error(transparent)]
+    Security(#[from] SecurityError),
+    #[error(transparent)]
+    Tls(#[from] TlsError),
+    #[error("Unable to get an access token: {0}")]
+    Token(TokenError),
+    #[error("The test connection with the new settings failed")]
+    TestConnectionFailed,
+    #[error("The {0} settings can not be changed by a reload. Restart the service instead")]
+    NotReloadable(&'static str),
 }
-#[cfg(any(feature = "tokio"))]
+#[cfg(feature = "tokio")]
 type SelectedExecutor = lettre::Tokio1Executor;
-#[cfg(not(any(feature = "tokio")))]
-compile_error!(
-    "No executor selected, please select one of the following features: tokio, actix-rt"
-);
+#[cfg(all(feature = "smol", not(feature = "tokio")))]
+type SelectedExecutor = lettre::AsyncStd1Executor;
+#[cfg(not(any(feature = "tokio", feature = "smol")))]
+compile_error!("No executor selected, please select one of the following features: tokio, smol");
 #[derive(Debug)]
 pub struct ConnectionState {
     pub connected: bool,
@@ -52,47 +91,91 @@ pub struct ConnectionState {
 
 pub type SharedConnectionState = Arc<Mutex<ConnectionState>>;
 
-type Transport = AsyncSmtpTransport<SelectedExecutor>;
+pub(crate) type Transport = AsyncSmtpTransport<SelectedExecutor>;
 #[derive(Debug)]
 pub struct SMTPService {
-    settings: Arc<SMTPServiceSettings>,
-    queue: Receiver<Message>,
-    transport: Option<Transport>,
+    /// Replaced together with the transport by a reload
+    settings: RwLock<Arc<SMTPServiceSettings>>,
+    transport: RwLock<Option<Transport>>,
+    oauth2: Option<TokenRefresher>,
+    pool: Arc<PoolTracker>,
     state: SharedConnectionState,
     service_state: Arc<ServiceState>,
 }
+impl SMTPError {
+    /// Sending again later or through another server might succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            SMTPError::TransportNotInitialized | SMTPError::Token(_) => true,
+            SMTPError::SendError(err) => !err.is_permanent(),
+            _ => false,
+        }
+    }
+}
 impl SMTPService {
-    /// Internal Send that doesn't return an error
-    #[instrument]
-    async fn send(&self, email: Message) {
-        use lettre::AsyncTransport;
-        if let Some(transport) = &self.transport {
-            match transport.send(email).await {
-                Ok(_ok) => {
-                    // TODO: Handle OK
-                }
-                Err(err) => {
-                    error!("Error Sending Email: {}", err);
-                }
+    /// Builds the transport. If the connection test fails the service will not be able to send.
+    pub(crate) async fn new(
+        settings: Arc<SMTPServiceSettings>,
+        service_state: Arc<ServiceState>,
+    ) -> Self {
+        Self::with_token_provider(settings, None, service_state).await
+    }
+    /// The access tokens of the provider are used instead of the password
+    async fn with_token_provider(
+        settings: Arc<SMTPServiceSettings>,
+        oauth2: Option<TokenRefresher>,
+        service_state: Arc<ServiceState>,
+    ) -> Self {
+        let connection = async {
+            let secret = match &oauth2 {
+                Some(oauth2) => oauth2.refresh_if_needed().await.map_err(SMTPError::Token)?,
+                None => None,
+            };
+            Self::build_connection(&settings, secret).await
+        };
+        let transport = match connection.await {
+            Ok(ok) => ok,
+            Err(value) => {
+                warn!("Error building email transport: {}", value);
+                None
             }
-        } else {
-            debug!("Email Transport not initialized");
+        };
+        let state = ConnectionState {
+            connected: transport.is_some(),
+        };
+        let pool = PoolTracker::new(&settings.pool);
+        if transport.is_some() {
+            pool.created(false);
+        }
+        Self {
+            settings: RwLock::new(settings),
+            transport: RwLock::new(transport),
+            oauth2,
+            pool: Arc::new(pool),
+            state: Arc::new(Mutex::new(state)),
+            service_state,
         }
     }
-    #[instrument]
-    async fn build_connection(
+    /// Builds the transport without connecting to the server
+    pub(crate) fn build_transport(settings: &SMTPServiceSettings) -> Result<Transport, SMTPError> {
+        Self::build_transport_with_secret(settings, None)
+    }
+    /// The secret replaces the password. Such as an access token
+    fn build_transport_with_secret(
         settings: &SMTPServiceSettings,
-    ) -> Result<Option<lettre::AsyncSmtpTransport<SelectedExecutor>>, SMTPError> {
+        secret: Option<String>,
+    ) -> Result<Transport, SMTPError> {
         let SMTPServiceSettings {
             username,
             password,
+            authentication,
             host,
             port,
             encryption,
             client_id,
             ..
         } = settings.clone();
-        let credentials = Credentials::new(username, password);
+        let mechanisms = authentication.mechanisms(&username);
 
         let port = port.unwrap_or(encryption.default_port());
 
@@ -102,68 +185,168 @@ impl SMTPService {
         );
 
         let tls = match settings.encryption {
-            SMTPServiceEncryption::StartTLS => Tls::Required(TlsParameters::new(host.clone())?),
+            SMTPServiceEncryption::StartTLS => Tls::Required(settings.tls.parameters(&host)?),
+            SMTPServiceEncryption::Opportunistic => {
+                Tls::Opportunistic(settings.tls.parameters(&host)?)
+            }
             SMTPServiceEncryption::NONE => Tls::None,
-            SMTPServiceEncryption::TLS => Tls::Wrapper(TlsParameters::new(host.clone())?),
+            SMTPServiceEncryption::TLS => Tls::Wrapper(settings.tls.parameters(&host)?),
         };
 
-        let transport = Transport::builder_dangerous(host)
+        let mut builder = Transport::builder_dangerous(host)
             .port(port)
             .tls(tls)
-            .credentials(credentials)
             .timeout(settings.get_timeout())
             .hello_name(client_id.into())
-            .build();
-
-        // TODO Support Client Name
-
+            .pool_config(settings.pool.config());
+        if let Some(mechanisms) = mechanisms {
+            builder = builder
+                .credentials(Credentials::new(
+                    username,
+                    secret.unwrap_or_else(|| password.expose_secret().to_owned()),
+                ))
+                .authentication(mechanisms);
+        }
+        Ok(builder.build())
+    }
+    #[instrument(skip(secret))]
+    async fn build_connection(
+        settings: &SMTPServiceSettings,
+        secret: Option<String>,
+    ) -> Result<Option<Transport>, SMTPError> {
+        let transport = Self::build_transport_with_secret(settings, secret)?;
         if !transport.test_connection().await? {
             warn!("Email Transport Test Connection Failed");
             return Ok(None);
         }
         Ok(Some(transport))
     }
-    #[cfg(feature = "tokio")]
-    async fn start_inner(self) {
-        use tokio::select;
-        loop {
-            let receiver = self.queue.recv_async();
-            let notified = self.service_state.notify.notified();
-            let message = select! {
-            _ = notified => {
-                debug!("Notified to shutdown");
-                break
-            }
-            v = receiver => {
-                if let Ok(value) = v{
-                    value
-                } else {
-                    debug!("All Senders Dropped. Closing SMTP Service");
-                    break;
-                }
-              }
+
+    /// The transport. Rebuilt first if the access token is about to expire or the pool reached its max lifetime
+    async fn transport(&self) -> Result<Transport, SMTPError> {
+        let refreshed = match &self.oauth2 {
+            Some(oauth2) => oauth2.refresh_if_needed().await.map_err(SMTPError::Token)?,
+            None => None,
+        };
+        let max_lifetime = self.settings().pool.max_lifetime();
+        let expired = || max_lifetime.is_some_and(|lifetime| self.pool.expired(lifetime));
+        if refreshed.is_some() || expired() {
+            let secret = match (refreshed.clone(), &self.oauth2) {
+                (Some(token), _) => Some(token),
+                (None, Some(oauth2)) => oauth2.current().await,
+                (None, None) => None,
             };
-            // Maybe we should start a new task for this?
-            self.send(message).await;
+            let mut transport = self.transport.write();
+            // Another delivery might have replaced it while the token was read
+            if refreshed.is_some() || expired() {
+                let previous =
+                    transport.replace(Self::build_transport_with_secret(&self.settings(), secret)?);
+                self.pool.created(previous.is_some());
+            }
         }
+        self.transport
+            .read()
+            .clone()
+            .ok_or(SMTPError::TransportNotInitialized)
+    }
+
+    pub fn settings(&self) -> Arc<SMTPServiceSettings> {
+        self.settings.read().clone()
+    }
+    /// Switches to the new settings once a test connection with them succeeds.
+    ///
+    /// Emails that are being sent finish on the old transport.
+    /// The pool size, rate limit, queue and spool are kept from the start of the service.
+    /// DKIM and security are loaded by the access so changing them is an error
+    pub(crate) async fn reload(&self, settings: SMTPServiceSettings) -> Result<(), SMTPError> {
+        let current = self.settings();
+        if settings.dkim != current.dkim {
+            return Err(SMTPError::NotReloadable("DKIM"));
+        }
+        if settings.security != current.security {
+            return Err(SMTPError::NotReloadable("security"));
+        }
+        let secret = match &self.oauth2 {
+            Some(oauth2) => {
+                oauth2.refresh_if_needed().await.map_err(SMTPError::Token)?;
+                oauth2.current().await
+            }
+            None => None,
+        };
+        let Some(transport) = Self::build_connection(&settings, secret).await? else {
+            return Err(SMTPError::TestConnectionFailed);
+        };
+        let mut current = self.transport.write();
+        *self.settings.write() = Arc::new(settings);
+        let previous = current.replace(transport);
+        self.pool.created(previous.is_some());
+        self.state.lock().connected = true;
+        debug!("Reloaded the SMTP settings");
+        Ok(())
     }
-    #[cfg(not(feature = "tokio"))]
-    async fn start_inner(self) {
-        compile_error!("Currently only tokio is supported")
+    /// Authenticates with XOAUTH2 using the access tokens of the provider.
+    ///
+    /// Tokens are refreshed shortly before they expire. Set [SMTPServiceSettings::authentication] to [SMTPAuthentication::XOAuth2]
+    pub async fn init_with_token_provider(
+        settings: SMTPServiceSettings,
+        provider: impl TokenProvider + 'static,
+        service_state: Arc<ServiceState>,
+    ) -> Result<SMTPEmailAccess, SMTPError> {
+        Self::start(settings, Some(TokenRefresher::new(provider)), service_state).await
     }
-    fn start(self) {
-        let service_state = self.service_state.clone();
-        ServiceState::watch_for_shutdown(service_state);
-        #[cfg(feature = "tokio")]
-        tokio::spawn(self.start_inner());
-        #[cfg(not(any(feature = "tokio")))]
-        compile_error!(
-            "No executor selected, please select one of the following features: tokio, actix-rt"
+    async fn start(
+        settings: SMTPServiceSettings,
+        oauth2: Option<TokenRefresher>,
+        service_state: Arc<ServiceState>,
+    ) -> Result<SMTPEmailAccess, SMTPError> {
+        let dkim = load_dkim(settings.dkim.as_ref())?;
+        let security = MessageSecurity::load(settings.security.as_ref())?;
+        let (queue, receiver) =
+            worker::queue(settings.channel_size, settings.spool_directory.as_deref())
+                .map_err(SMTPError::SpoolError)?;
+        let settings = Arc::new(settings);
+        let service = Arc::new(
+            SMTPService::with_token_provider(settings.clone(), oauth2, service_state).await,
+        );
+        let rate_limiter = RateLimiter::new(&settings.rate_limit).map(Arc::new);
+        worker::start(
+            service.clone(),
+            receiver,
+            service.service_state.clone(),
+            rate_limiter.clone(),
         );
+
+        Ok(SMTPEmailAccess::new(
+            service,
+            queue,
+            dkim,
+            security,
+            rate_limiter,
+        ))
     }
+}
+impl Backend for SMTPService {
+    const NAME: &'static str = "smtp";
+    type Response = Response;
+    type Error = SMTPError;
 
-    pub fn settings(&self) -> &SMTPServiceSettings {
-        self.settings.as_ref()
+    fn is_transient(error: &SMTPError) -> bool {
+        error.is_transient()
+    }
+    async fn deliver(&self, email: &RawEmail) -> Result<Response, SMTPError> {
+        use lettre::AsyncTransport;
+        let transport = self.transport().await?;
+        let _in_use = self.pool.acquire();
+        Ok(transport
+            .send_raw(&email.envelope, &email.formatted)
+            .await?)
+    }
+    /// One email per pooled connection
+    fn concurrency(&self) -> usize {
+        self.settings().pool.max_size() as usize
+    }
+    fn pool_status(&self) -> Option<PoolStatus> {
+        Some(self.pool.status())
     }
 }
 impl MailService for SMTPService {
@@ -172,45 +355,14 @@ impl MailService for SMTPService {
     type Error = SMTPError;
     type ConnectionState = ConnectionState;
 
-    async fn init(settings: Self::Settings) -> Result<SMTPEmailAccess, Self::Error>
+    async fn init_with_state(
+        settings: Self::Settings,
+        service_state: Arc<ServiceState>,
+    ) -> Result<SMTPEmailAccess, Self::Error>
     where
         Self: Sized,
     {
-        let transport = match Self::build_connection(&settings).await {
-            Ok(ok) => ok,
-            Err(value) => {
-                warn!("Error building email transport: {}", value);
-                None
-            }
-        };
-        let (queue, receiver) = if settings.channel_size == 0 {
-            unbdounded_channel()
-        } else {
-            bounded_channel(settings.channel_size)
-        };
-        let state = ConnectionState {
-            connected: transport.is_some(),
-        };
-
-        let shared = Arc::new(Mutex::new(state));
-        let settings = Arc::new(settings);
-        let service_state = ServiceState::new();
-        SMTPService {
-            settings: settings.clone(),
-            queue: receiver,
-            transport,
-            state: shared.clone(),
-            service_state: service_state.clone(),
-        }
-        .start();
-
-        Ok(SMTPEmailAccess {
-            settings: settings,
-            queue: queue,
-            message_builder: MessageBuilder::new(),
-            state: shared,
-            service_state,
-        })
+        Self::start(settings, None, service_state).await
     }
 
     fn get_state(&self) -> Arc<crate::shared::Mutex<Self::ConnectionState>> {
@@ -222,11 +374,11 @@ impl MailService for SMTPService {
     }
 
     fn get_settings(&self) -> Arc<Self::Settings> {
-        self.settings.clone()
+        self.settings()
     }
 
     async fn is_connected(&self) -> bool {
-        if let Some(transport) = &self.transport {
+        if let Ok(transport) = self.transport().await {
             let connected = transport.test_connection().await.unwrap_or(false);
             self.state.lock().connected = connected;
             connected
@@ -236,36 +388,98 @@ impl MailService for SMTPService {
     }
 }
 
+/// Builds the Message. The from and reply to addresses fall back to the ones in the settings.
+///
+/// Returns the Message-ID. It is generated if the email does not have one.
+///
+/// The body is signed or encrypted as asked by [Email::protection] or the [SecuritySettings].
+/// It is signed with DKIM last if DKIM is configured.
+/// The recipients are redirected or filtered first if [EmailSettingsType::safety] is set.
 pub(crate) fn email_to_message(
     mut message: MessageBuilder,
-    _settings: &SMTPServiceSettings,
+    settings: &impl EmailSettingsType,
+    dkim: Option<&DkimConfig>,
+    security: Option<&MessageSecurity>,
     mut email: impl Email,
-) -> Result<Message, SMTPError> {
+) -> Result<(Message, MessageId), SMTPError> {
     let Some(body) = email.body() else {
         return Err(SMTPError::NoBodyProvided);
     };
-    let mut to_set = email.to();
-    if to_set.len() == 0 {
+    if email.to().len() == 0 {
         return Err(SMTPError::NoToAddressProvided);
     }
+    let mut recipients: Vec<Mailbox> = email.to().cloned().collect();
+    let mut subject = email.subject();
+    let mut original_to = None;
+    if let Some(safety) = settings.safety() {
+        Guarded {
+            to: recipients,
+            subject,
+            original_to,
+        } = safety.guard(recipients, subject);
+        if recipients.is_empty() {
+            return Err(SMTPError::NoAllowedRecipients);
+        }
+    }
     let body = email_body_to_multipart(body);
-    if to_set.len() != 1 {
-        todo!("Implement sending to multiple addresses")
-    } else {
-        message = message.to(to_set.next().unwrap().clone().into());
+    let protection = email
+        .protection()
+        .or(security.map(MessageSecurity::protection))
+        .unwrap_or_default();
+    let body = match security {
+        Some(security) if protection.sign || protection.encrypt => {
+            let recipients: Vec<_> = recipients.iter().map(|to| &to.email).collect();
+            security.protect(body, protection, &recipients)?
+        }
+        None if protection.sign || protection.encrypt => {
+            return Err(SecurityError::NotConfigured.into());
+        }
+        _ => ProtectedBody::Multi(body),
+    };
+    message = message.subject(subject);
+    for to in recipients {
+        message = message.to(to.try_into()?);
+    }
+    if let Some(original_to) = original_to {
+        message = message.raw_header(HeaderValue::new(
+            HeaderName::new_from_ascii_str(ORIGINAL_TO_HEADER),
+            original_to,
+        ));
     }
 
-    if let Some(from) = email.from() {
-        message = message.from(from.clone().into());
-    };
+    let from = email.from().unwrap_or(settings.from());
+    message = message.from(from.clone().try_into()?);
 
-    if let Some(reply_to) = email.reply_to() {
-        message = message.reply_to(reply_to.clone().into());
-    };
+    let message_id = email.message_id().unwrap_or_else(|| {
+        MessageId::generate(
+            settings
+                .message_id_domain()
+                .unwrap_or(settings.from().email.domain()),
+        )
+    });
+    message = message.message_id(Some(message_id.to_string()));
+    if let Some(in_reply_to) = email.in_reply_to() {
+        message = message.in_reply_to(in_reply_to.to_string());
+    }
+    let references = email.references();
+    if !references.is_empty() {
+        let references: Vec<_> = references.iter().map(MessageId::as_str).collect();
+        message = message.references(references.join(" "));
+    }
 
-    let message = message.multipart(body)?;
+    if let Some(reply_to) = email.reply_to().or(settings.reply_to()) {
+        message = message.reply_to(reply_to.clone().try_into()?);
+    };
 
-    Ok(message)
+    let mut message = match body {
+        ProtectedBody::Multi(body) => message.multipart(body)?,
+        #[cfg(feature = "smime")]
+        ProtectedBody::Single(body) => message.singlepart(body)?,
+    };
+    if let Some(dkim) = dkim {
+        message.sign(dkim);
+    }
+    Ok((message, message_id))
 }
 
 fn email_body_to_multipart(body: EmailBody) -> MultiPart {
@@ -288,33 +502,50 @@ fn email_body_to_multipart(body: EmailBody) -> MultiPart {
     } else {
         multipart
     };
-    multipart
+    let Some(calendar) = body.calendar else {
+        return multipart;
+    };
+    // Clients that understand the alternative show the invitation. The attachment is for the rest
+    let ics = calendar.to_ics();
+    let content_type = format!("text/calendar; method={}; charset=utf-8", calendar.method);
+    MultiPart::mixed()
+        .multipart(
+            multipart.singlepart(
+                SinglePart::builder()
+                    .header(header::ContentType::parse(&content_type).expect("Valid content type"))
+                    .body(ics.clone()),
+            ),
+        )
+        .singlepart(
+            SinglePart::builder()
+                .header(
+                    header::ContentType::parse("application/ics; name=\"invite.ics\"")
+                        .expect("Valid content type"),
+                )
+                .header(header::ContentDisposition::attachment("invite.ics"))
+                .body(ics),
+        )
 }
 
-impl From<Mailbox> for SMTPMailBox {
-    fn from(value: Mailbox) -> Self {
+impl TryFrom<Mailbox> for SMTPMailBox {
+    type Error = lettre::address::AddressError;
+    fn try_from(value: Mailbox) -> Result<Self, Self::Error> {
         let Mailbox { name, email } = value;
-        SMTPMailBox::new(name, email.into())
+        Ok(SMTPMailBox::new(name, email.try_into()?))
     }
 }
-#[cfg(debug_assertions)]
-impl From<Address> for lettre::Address {
-    fn from(value: Address) -> Self {
-        // As long as lettre deosn't change the layout of this struct, this is safe
-        let result: lettre::Address = unsafe { mem::transmute_copy(&value) };
-        // Checks for layout changes
-        assert_eq!(
-            &value, &result,
-            "lettre::Address did not have the same layout as any_mail::email_types::Address"
-        );
-        result
+impl TryFrom<Address> for lettre::Address {
+    type Error = lettre::address::AddressError;
+    /// lettre validates the address more strictly than [Address]
+    fn try_from(value: Address) -> Result<Self, Self::Error> {
+        lettre::Address::try_from(value.serialized)
     }
 }
-#[cfg(not(debug_assertions))]
-impl From<Address> for lettre::Address {
-    fn from(value: Address) -> Self {
-        let result: lettre::Address = unsafe { mem::transmute(&value) };
-        result
+
+impl From<lettre::Address> for Address {
+    fn from(value: lettre::Address) -> Self {
+        // lettre::Address has already been validated
+        unsafe { Address::new_unchecked(value.to_string()) }
     }
 }
 
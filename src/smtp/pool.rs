@@ -0,0 +1,133 @@
+use std::{
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use lettre::transport::smtp::PoolConfig;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::health::PoolStatus;
+/// Tunes the connection pool. By default up to ten connections are kept open and ten emails are delivered at the same time.
+///
+/// Emails sent at the same time may arrive in a different order. Set `max_size` to 1 to send them one at a time
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+#[serde(default)]
+pub struct PoolSettings {
+    /// The most connections kept open. Also how many emails are delivered at the same time. 0 is treated as 1
+    pub max_size: u32,
+    /// Connections opened ahead of time and kept open while idle
+    pub min_idle: u32,
+    /// In milliseconds. Idle connections are closed after this long. 0 is treated as one minute
+    pub idle_timeout: u64,
+    /// In milliseconds. The pool is replaced after this long so no connection is older. 0 for no limit
+    pub max_lifetime: u64,
+}
+impl Default for PoolSettings {
+    fn default() -> Self {
+        Self {
+            max_size: 10,
+            min_idle: 0,
+            idle_timeout: 0,
+            max_lifetime: 0,
+        }
+    }
+}
+impl PoolSettings {
+    pub fn max_size(&self) -> u32 {
+        self.max_size.max(1)
+    }
+    fn idle_timeout(&self) -> Duration {
+        if self.idle_timeout == 0 {
+            Duration::from_secs(60)
+        } else {
+            Duration::from_millis(self.idle_timeout)
+        }
+    }
+    pub(crate) fn max_lifetime(&self) -> Option<Duration> {
+        (self.max_lifetime != 0).then(|| Duration::from_millis(self.max_lifetime))
+    }
+    pub(crate) fn config(&self) -> PoolConfig {
+        PoolConfig::new()
+            .max_size(self.max_size())
+            .min_idle(self.min_idle.min(self.max_size()))
+            .idle_timeout(self.idle_timeout())
+    }
+}
+/// What [PoolStatus] is made from. lettre does not expose its pool so the service keeps count
+#[derive(Debug)]
+pub(crate) struct PoolTracker {
+    max_size: u32,
+    min_idle: u32,
+    in_use: AtomicU32,
+    replaced: AtomicU64,
+    created_at: Mutex<Option<SystemTime>>,
+}
+impl PoolTracker {
+    pub fn new(settings: &PoolSettings) -> Self {
+        Self {
+            max_size: settings.max_size(),
+            min_idle: settings.min_idle,
+            in_use: AtomicU32::new(0),
+            replaced: AtomicU64::new(0),
+            created_at: Mutex::new(None),
+        }
+    }
+    /// A new pool was created. `replaced` is false for the first one
+    pub fn created(&self, replaced: bool) {
+        *self.created_at.lock() = Some(SystemTime::now());
+        if replaced {
+            self.replaced.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    /// If the current pool is older than `lifetime`
+    pub fn expired(&self, lifetime: Duration) -> bool {
+        self.created_at.lock().is_some_and(|created_at| {
+            created_at
+                .elapsed()
+                .is_ok_and(|elapsed| elapsed >= lifetime)
+        })
+    }
+    /// Counts a connection as in use until the guard is dropped
+    pub fn acquire(&self) -> InUseGuard<'_> {
+        self.in_use.fetch_add(1, Ordering::Relaxed);
+        InUseGuard(self)
+    }
+    pub fn status(&self) -> PoolStatus {
+        PoolStatus {
+            max_size: self.max_size,
+            min_idle: self.min_idle,
+            in_use: self.in_use.load(Ordering::Relaxed),
+            replaced: self.replaced.load(Ordering::Relaxed),
+            created_at: *self.created_at.lock(),
+        }
+    }
+}
+pub(crate) struct InUseGuard<'a>(&'a PoolTracker);
+impl Drop for InUseGuard<'_> {
+    fn drop(&mut self) {
+        self.0.in_use.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_tracker() {
+        let tracker = PoolTracker::new(&PoolSettings::default());
+        assert_eq!(tracker.status().max_size, 10);
+        assert!(!tracker.expired(Duration::ZERO));
+        tracker.created(false);
+        assert!(tracker.expired(Duration::ZERO));
+        assert!(!tracker.expired(Duration::from_secs(60)));
+        {
+            let _guard = tracker.acquire();
+            assert_eq!(tracker.status().in_use, 1);
+        }
+        tracker.created(true);
+        let status = tracker.status();
+        assert_eq!(status.in_use, 0);
+        assert_eq!(status.replaced, 1);
+        assert!(status.created_at.is_some());
+    }
+}
@@ -0,0 +1,291 @@
+/*!
+Signing and encrypting the body of an email with S/MIME or OpenPGP.
+
+The multipart built from the [EmailBody](crate::template::EmailBody) is wrapped in the structure the standard expects.
+
+| | S/MIME (`smime` feature) | OpenPGP (`openpgp` feature) |
+|---|---|---|
+| Sign | `multipart/signed` with `application/pkcs7-signature` | `multipart/signed` with `application/pgp-signature` |
+| Encrypt | `application/pkcs7-mime` | `multipart/encrypted` with `application/pgp-encrypted` |
+
+When an email is both signed and encrypted, S/MIME signs and then encrypts the signed entity. OpenPGP signs inside the encrypted message.
+
+The public keys of the recipients are found with a [PublicKeyLookup]. Register one with [EmailAccess::set_public_keys](crate::EmailAccess::set_public_keys)
+or set [SecuritySettings::recipient_keys] to read them from a directory.
+*/
+#[cfg(feature = "openpgp")]
+mod openpgp;
+#[cfg(feature = "smime")]
+mod smime;
+use std::{
+    fmt::Debug,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use lettre::message::MultiPart;
+#[cfg(feature = "smime")]
+use lettre::message::SinglePart;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::warn;
+
+#[cfg(feature = "openpgp")]
+use crate::secret::Secret;
+use crate::{email_types::Address, Protection};
+
+#[derive(Debug, Error)]
+pub enum SecurityError {
+    #[error("Unable to read a key: {0}")]
+    ReadKey(#[from] std::io::Error),
+    #[cfg(feature = "smime")]
+    #[error(transparent)]
+    SMime(#[from] openssl::error::ErrorStack),
+    #[cfg(feature = "openpgp")]
+    #[error(transparent)]
+    OpenPgp(#[from] pgp::errors::Error),
+    #[error("No public key was found for {0}")]
+    MissingPublicKey(Address),
+    #[error("The public key of {0} can not be used for encryption")]
+    NoEncryptionKey(Address),
+    #[error("The email asked to be signed or encrypted but the service has no security settings")]
+    NotConfigured,
+}
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub enum SecurityMethod {
+    #[cfg(feature = "smime")]
+    SMime {
+        /// PEM. Any certificates after the first are sent along as the chain
+        certificate: super::KeySource,
+        /// PEM
+        private_key: super::KeySource,
+    },
+    #[cfg(feature = "openpgp")]
+    OpenPgp {
+        /// The armored secret key. The primary key signs
+        secret_key: super::KeySource,
+        #[serde(default)]
+        passphrase: Option<Secret>,
+    },
+}
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub struct SecuritySettings {
+    pub method: SecurityMethod,
+    /// What is done to every email. An email can override it with [Email::protection](crate::Email::protection)
+    #[serde(default)]
+    pub protection: Protection,
+    /// Read public keys from this directory. `{address}.pem` for S/MIME and `{address}.asc` for OpenPGP
+    #[serde(default)]
+    pub recipient_keys: Option<PathBuf>,
+}
+/// Finds the public keys of the recipients
+pub trait PublicKeyLookup: Send + Sync + Debug {
+    /// The PEM certificate of the recipient
+    fn smime_certificate(&self, _recipient: &Address) -> Option<String> {
+        None
+    }
+    /// The armored public key of the recipient
+    fn openpgp_key(&self, _recipient: &Address) -> Option<String> {
+        None
+    }
+}
+/// Reads `{address}.pem` and `{address}.asc` from a directory.
+///
+/// Addresses that are not a plain file name, such as ones with a `/`, have no key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyDirectory(pub PathBuf);
+impl KeyDirectory {
+    fn read(&self, recipient: &Address, extension: &str) -> Option<String> {
+        let name = format!("{recipient}.{extension}");
+        let mut components = Path::new(&name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(file)), None) if file == name.as_str() => {
+                std::fs::read_to_string(self.0.join(file)).ok()
+            }
+            _ => {
+                warn!("{} is not a valid key file name", name);
+                None
+            }
+        }
+    }
+}
+impl PublicKeyLookup for KeyDirectory {
+    fn smime_certificate(&self, recipient: &Address) -> Option<String> {
+        self.read(recipient, "pem")
+    }
+    fn openpgp_key(&self, recipient: &Address) -> Option<String> {
+        self.read(recipient, "asc")
+    }
+}
+#[derive(Debug)]
+struct NoKeys;
+impl PublicKeyLookup for NoKeys {}
+#[derive(Debug)]
+enum Signer {
+    #[cfg(feature = "smime")]
+    SMime(smime::SMime),
+    #[cfg(feature = "openpgp")]
+    OpenPgp(Box<openpgp::OpenPgp>),
+}
+/// The loaded keys of a service
+#[derive(Debug)]
+pub(crate) struct MessageSecurity {
+    signer: Signer,
+    protection: Protection,
+    keys: RwLock<Arc<dyn PublicKeyLookup>>,
+}
+/// The body after it was signed or encrypted
+pub(crate) enum ProtectedBody {
+    Multi(MultiPart),
+    /// S/MIME encrypts the whole entity into one part
+    #[cfg(feature = "smime")]
+    Single(SinglePart),
+}
+impl MessageSecurity {
+    /// Loads the keys when the service starts so a bad key is reported by init
+    // Without a method enabled the settings can not be created
+    #[cfg_attr(
+        not(any(feature = "smime", feature = "openpgp")),
+        allow(unreachable_code, unused_variables)
+    )]
+    pub fn load(settings: Option<&SecuritySettings>) -> Result<Option<Arc<Self>>, SecurityError> {
+        let Some(settings) = settings else {
+            return Ok(None);
+        };
+        let signer = match settings.method {
+            #[cfg(feature = "smime")]
+            SecurityMethod::SMime {
+                ref certificate,
+                ref private_key,
+            } => Signer::SMime(smime::SMime::load(certificate, private_key)?),
+            #[cfg(feature = "openpgp")]
+            SecurityMethod::OpenPgp {
+                ref secret_key,
+                ref passphrase,
+            } => Signer::OpenPgp(Box::new(openpgp::OpenPgp::load(
+                secret_key,
+                passphrase.as_ref().map(Secret::expose_secret),
+            )?)),
+        };
+        let keys: Arc<dyn PublicKeyLookup> = match &settings.recipient_keys {
+            Some(directory) => Arc::new(KeyDirectory(directory.clone())),
+            None => Arc::new(NoKeys),
+        };
+        Ok(Some(Arc::new(Self {
+            signer,
+            protection: settings.protection,
+            keys: RwLock::new(keys),
+        })))
+    }
+    pub fn set_public_keys(&self, keys: Arc<dyn PublicKeyLookup>) {
+        *self.keys.write() = keys;
+    }
+    pub fn protection(&self) -> Protection {
+        self.protection
+    }
+    /// Signs and encrypts the body as asked
+    #[cfg_attr(
+        not(any(feature = "smime", feature = "openpgp")),
+        allow(unused_variables)
+    )]
+    pub fn protect(
+        &self,
+        body: MultiPart,
+        protection: Protection,
+        recipients: &[&Address],
+    ) -> Result<ProtectedBody, SecurityError> {
+        let keys = self.keys.read().clone();
+        match self.signer {
+            #[cfg(feature = "smime")]
+            Signer::SMime(ref signer) => {
+                signer.protect(body, protection, recipients, keys.as_ref())
+            }
+            #[cfg(feature = "openpgp")]
+            Signer::OpenPgp(ref signer) => {
+                signer.protect(body, protection, recipients, keys.as_ref())
+            }
+        }
+    }
+}
+/// The bytes a detached signature covers.
+///
+/// The line break before the boundary that follows the entity belongs to the boundary
+#[cfg(any(feature = "smime", feature = "openpgp"))]
+fn signed_content(entity: &MultiPart) -> Vec<u8> {
+    let mut formatted = entity.formatted();
+    if formatted.ends_with(b"\r\n") {
+        formatted.truncate(formatted.len() - 2);
+    }
+    formatted
+}
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use lettre::message::MessageBuilder;
+
+    use super::*;
+    use crate::{email_types::Mailbox, smtp::SMTPError, template::EmailBody, Email};
+    #[derive(Debug)]
+    struct SignedEmail(Mailbox);
+    impl Email for SignedEmail {
+        fn subject(&self) -> Cow<'static, str> {
+            Cow::Borrowed("Signed")
+        }
+        fn body(&mut self) -> Option<EmailBody> {
+            Some(EmailBody {
+                html_body: None,
+                text_body: Some("Hello".to_owned()),
+                calendar: None,
+            })
+        }
+        fn to(&self) -> impl ExactSizeIterator<Item = &Mailbox> + '_ {
+            std::iter::once(&self.0)
+        }
+        fn from(&self) -> Option<&Mailbox> {
+            None
+        }
+        fn protection(&self) -> Option<Protection> {
+            Some(Protection {
+                sign: true,
+                encrypt: false,
+            })
+        }
+    }
+    #[test]
+    fn test_not_configured() {
+        let settings = crate::smtp::SMTPServiceSettings::default();
+        let email = SignedEmail(Mailbox::try_from("user@example.com").unwrap());
+        let result =
+            crate::smtp::email_to_message(MessageBuilder::new(), &settings, None, None, email);
+        assert!(matches!(
+            result,
+            Err(SMTPError::Security(SecurityError::NotConfigured))
+        ));
+    }
+    #[test]
+    fn test_key_directory() {
+        let directory =
+            std::env::temp_dir().join(format!("any_mail_key_directory_{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("keys")).unwrap();
+        std::fs::write(directory.join("user@example.com.pem"), "certificate").unwrap();
+        std::fs::write(
+            directory.join("keys").join("user@example.com.pem"),
+            "outside",
+        )
+        .unwrap();
+        let keys = KeyDirectory(directory.clone());
+
+        let user = Address::try_from("user@example.com").unwrap();
+        assert_eq!(
+            keys.smime_certificate(&user).as_deref(),
+            Some("certificate")
+        );
+        assert_eq!(keys.openpgp_key(&user), None);
+        // The path is not followed into another directory
+        let nested = Address::try_from("keys/user@example.com").unwrap();
+        assert_eq!(keys.smime_certificate(&nested), None);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,278 @@
+use chrono::SubsecRound;
+use lettre::message::{
+    header::{ContentDisposition, ContentTransferEncoding, ContentType},
+    Body, MultiPart, SinglePart,
+};
+use pgp::{
+    composed::{
+        ArmorOptions, Deserializable, MessageBuilder, SignedPublicKey, SignedSecretKey,
+        StandaloneSignature,
+    },
+    crypto::{hash::HashAlgorithm, sym::SymmetricKeyAlgorithm},
+    packet::{SignatureConfig, SignatureType, Subpacket, SubpacketData},
+    types::{KeyDetails, KeyVersion, Password, PublicKeyTrait},
+};
+use rand::thread_rng;
+
+use super::{signed_content, ProtectedBody, PublicKeyLookup, SecurityError};
+use crate::{email_types::Address, smtp::KeySource, Protection};
+
+pub(super) struct OpenPgp {
+    key: SignedSecretKey,
+    passphrase: Option<String>,
+}
+impl std::fmt::Debug for OpenPgp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OpenPgp")
+            .field("key", &self.key.primary_key.fingerprint())
+            .finish_non_exhaustive()
+    }
+}
+impl OpenPgp {
+    pub fn load(secret_key: &KeySource, passphrase: Option<&str>) -> Result<Self, SecurityError> {
+        let (key, _) = SignedSecretKey::from_string(&secret_key.read()?)?;
+        Ok(Self {
+            key,
+            passphrase: passphrase.map(ToOwned::to_owned),
+        })
+    }
+    fn password(&self) -> Password {
+        self.passphrase
+            .as_deref()
+            .map(Password::from)
+            .unwrap_or_default()
+    }
+    pub fn protect(
+        &self,
+        body: MultiPart,
+        protection: Protection,
+        recipients: &[&Address],
+        keys: &dyn PublicKeyLookup,
+    ) -> Result<ProtectedBody, SecurityError> {
+        if !protection.encrypt {
+            let body = if protection.sign {
+                self.sign(body)?
+            } else {
+                body
+            };
+            return Ok(ProtectedBody::Multi(body));
+        }
+        let mut public_keys = Vec::with_capacity(recipients.len());
+        for recipient in recipients {
+            let key = keys
+                .openpgp_key(recipient)
+                .ok_or_else(|| SecurityError::MissingPublicKey((*recipient).clone()))?;
+            let (key, _) = SignedPublicKey::from_string(&key)?;
+            public_keys.push(((*recipient).clone(), key));
+        }
+        let mut rng = thread_rng();
+        let mut builder = MessageBuilder::from_bytes("", body.formatted())
+            .seipd_v1(&mut rng, SymmetricKeyAlgorithm::AES256);
+        for (recipient, key) in &public_keys {
+            if let Some(subkey) = key
+                .public_subkeys
+                .iter()
+                .find(|subkey| subkey.is_encryption_key())
+            {
+                builder.encrypt_to_key(&mut rng, subkey)?;
+            } else if key.primary_key.is_encryption_key() {
+                builder.encrypt_to_key(&mut rng, &key.primary_key)?;
+            } else {
+                return Err(SecurityError::NoEncryptionKey(recipient.clone()));
+            }
+        }
+        if protection.sign {
+            builder.sign(
+                &self.key.primary_key,
+                self.password(),
+                HashAlgorithm::Sha256,
+            );
+        }
+        let encrypted = builder.to_armored_string(&mut rng, ArmorOptions::default())?;
+        Ok(ProtectedBody::Multi(
+            MultiPart::encrypted("application/pgp-encrypted".to_owned())
+                .singlepart(
+                    SinglePart::builder()
+                        .header(ContentType::parse("application/pgp-encrypted").unwrap())
+                        .body(String::from("Version: 1")),
+                )
+                .singlepart(armored(
+                    "application/octet-stream; name=\"encrypted.asc\"",
+                    "encrypted.asc",
+                    encrypted,
+                    true,
+                )),
+        ))
+    }
+    fn sign(&self, body: MultiPart) -> Result<MultiPart, SecurityError> {
+        let key = &self.key.primary_key;
+        let mut config = match key.version() {
+            KeyVersion::V6 => SignatureConfig::v6(
+                thread_rng(),
+                SignatureType::Binary,
+                key.algorithm(),
+                HashAlgorithm::Sha256,
+            )?,
+            _ => {
+                let mut config = SignatureConfig::v4(
+                    SignatureType::Binary,
+                    key.algorithm(),
+                    HashAlgorithm::Sha256,
+                );
+                config.unhashed_subpackets =
+                    vec![Subpacket::regular(SubpacketData::Issuer(key.key_id()))?];
+                config
+            }
+        };
+        config.hashed_subpackets = vec![
+            Subpacket::regular(SubpacketData::IssuerFingerprint(key.fingerprint()))?,
+            Subpacket::regular(SubpacketData::SignatureCreationTime(
+                chrono::Utc::now().trunc_subsecs(0),
+            ))?,
+        ];
+        let signature = config.sign(key, &self.password(), signed_content(&body).as_slice())?;
+        let signature =
+            StandaloneSignature::new(signature).to_armored_string(ArmorOptions::default())?;
+        Ok(MultiPart::signed(
+            "application/pgp-signature".to_owned(),
+            "pgp-sha256".to_owned(),
+        )
+        .multipart(body)
+        .singlepart(armored(
+            "application/pgp-signature; name=\"signature.asc\"",
+            "signature.asc",
+            signature,
+            false,
+        )))
+    }
+}
+fn armored(content_type: &str, file_name: &str, armored: String, inline: bool) -> SinglePart {
+    let disposition = if inline {
+        ContentDisposition::inline_with_name(file_name)
+    } else {
+        ContentDisposition::attachment(file_name)
+    };
+    SinglePart::builder()
+        .header(ContentType::parse(content_type).expect("Valid content type"))
+        .header(disposition)
+        .body(
+            Body::new_with_encoding(armored, ContentTransferEncoding::SevenBit)
+                .expect("Armored text is 7bit"),
+        )
+}
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use pgp::{
+        composed::{KeyType, Message, SecretKeyParamsBuilder, SubkeyParamsBuilder},
+        crypto::ecc_curve::ECCCurve,
+    };
+
+    use super::*;
+    use crate::smtp::secure::{MessageSecurity, SecurityMethod, SecuritySettings};
+    #[derive(Debug)]
+    struct PublicKeys(String);
+    impl PublicKeyLookup for PublicKeys {
+        fn openpgp_key(&self, _: &Address) -> Option<String> {
+            Some(self.0.clone())
+        }
+    }
+    fn generate() -> SignedSecretKey {
+        let mut rng = thread_rng();
+        SecretKeyParamsBuilder::default()
+            .key_type(KeyType::Ed25519Legacy)
+            .can_certify(true)
+            .can_sign(true)
+            .primary_user_id("Sender <sender@example.com>".into())
+            .subkeys(vec![SubkeyParamsBuilder::default()
+                .key_type(KeyType::ECDH(ECCCurve::Curve25519))
+                .can_encrypt(true)
+                .build()
+                .unwrap()])
+            .build()
+            .unwrap()
+            .generate(&mut rng)
+            .unwrap()
+            .sign(&mut rng, &Password::empty())
+            .unwrap()
+    }
+    /// OpenPGP always returns a multipart
+    fn multipart(body: ProtectedBody) -> MultiPart {
+        match body {
+            ProtectedBody::Multi(body) => body,
+            #[cfg(feature = "smime")]
+            ProtectedBody::Single(_) => panic!("OpenPGP returned a single part"),
+        }
+    }
+    #[test]
+    fn test_sign_and_encrypt() {
+        let key = generate();
+        let public_key = key
+            .signed_public_key()
+            .to_armored_string(ArmorOptions::default())
+            .unwrap();
+        let security = MessageSecurity::load(Some(&SecuritySettings {
+            method: SecurityMethod::OpenPgp {
+                secret_key: KeySource::Inline(
+                    key.to_armored_string(ArmorOptions::default())
+                        .unwrap()
+                        .into(),
+                ),
+                passphrase: None,
+            },
+            protection: Protection::default(),
+            recipient_keys: None,
+        }))
+        .unwrap()
+        .unwrap();
+        let body = || MultiPart::alternative().singlepart(SinglePart::plain("Hello".to_owned()));
+        let recipient = Address::try_from("user@example.com".to_owned()).unwrap();
+        let sign = Protection {
+            sign: true,
+            encrypt: false,
+        };
+        let signed = multipart(security.protect(body(), sign, &[&recipient]).unwrap());
+        let formatted = String::from_utf8(signed.formatted()).unwrap();
+        assert!(formatted.contains("multipart/signed"));
+        assert!(formatted.contains("protocol=\"application/pgp-signature\""));
+        // The signature covers the first part
+        let boundary = formatted
+            .split_once("boundary=\"")
+            .unwrap()
+            .1
+            .split_once('"')
+            .unwrap()
+            .0;
+        let parts: Vec<_> = formatted.split(&format!("\r\n--{boundary}")).collect();
+        let content = parts[1].trim_start_matches("\r\n");
+        let (signature, _) =
+            StandaloneSignature::from_string(parts[2].split_once("\r\n\r\n").unwrap().1).unwrap();
+        signature
+            .verify(&key.signed_public_key(), content.as_bytes())
+            .unwrap();
+
+        let encrypt = Protection {
+            sign: true,
+            encrypt: true,
+        };
+        assert!(matches!(
+            security.protect(body(), encrypt, &[&recipient]),
+            Err(SecurityError::MissingPublicKey(_))
+        ));
+        security.set_public_keys(Arc::new(PublicKeys(public_key)));
+        let encrypted = multipart(security.protect(body(), encrypt, &[&recipient]).unwrap());
+        let formatted = String::from_utf8(encrypted.formatted()).unwrap();
+        assert!(formatted.contains("multipart/encrypted"));
+        assert!(formatted.contains("Version: 1"));
+        assert!(!formatted.contains("Hello"));
+
+        let armored = &formatted[formatted.find("-----BEGIN PGP MESSAGE-----").unwrap()
+            ..formatted.find("-----END PGP MESSAGE-----").unwrap() + 25];
+        let (message, _) = Message::from_string(armored).unwrap();
+        let mut message = message.decrypt(&Password::empty(), &key).unwrap();
+        let decrypted = message.as_data_string().unwrap();
+        assert!(decrypted.contains("Hello"));
+        message.verify(&key.signed_public_key()).unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+use lettre::message::{
+    header::{ContentDisposition, ContentTransferEncoding, ContentType},
+    Body, MultiPart, SinglePart,
+};
+use openssl::{
+    pkcs7::{Pkcs7, Pkcs7Flags},
+    pkey::{PKey, Private},
+    stack::Stack,
+    symm::Cipher,
+    x509::X509,
+};
+
+use super::{signed_content, ProtectedBody, PublicKeyLookup, SecurityError};
+use crate::{email_types::Address, smtp::KeySource, Protection};
+
+pub(super) struct SMime {
+    certificate: X509,
+    chain: Stack<X509>,
+    key: PKey<Private>,
+}
+impl std::fmt::Debug for SMime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SMime")
+            .field("certificate", &self.certificate.subject_name())
+            .finish_non_exhaustive()
+    }
+}
+impl SMime {
+    pub fn load(certificate: &KeySource, private_key: &KeySource) -> Result<Self, SecurityError> {
+        let certificates = certificate.read()?;
+        let certificate = X509::from_pem(certificates.as_bytes())?;
+        let mut chain = Stack::new()?;
+        for certificate in X509::stack_from_pem(certificates.as_bytes())?
+            .into_iter()
+            .skip(1)
+        {
+            chain.push(certificate)?;
+        }
+        let key = PKey::private_key_from_pem(private_key.read()?.as_bytes())?;
+        Ok(Self {
+            certificate,
+            chain,
+            key,
+        })
+    }
+    pub fn protect(
+        &self,
+        body: MultiPart,
+        protection: Protection,
+        recipients: &[&Address],
+        keys: &dyn PublicKeyLookup,
+    ) -> Result<ProtectedBody, SecurityError> {
+        let body = if protection.sign {
+            self.sign(body)?
+        } else {
+            body
+        };
+        if !protection.encrypt {
+            return Ok(ProtectedBody::Multi(body));
+        }
+        let mut certificates = Stack::new()?;
+        for recipient in recipients {
+            let certificate = keys
+                .smime_certificate(recipient)
+                .ok_or_else(|| SecurityError::MissingPublicKey((*recipient).clone()))?;
+            certificates.push(X509::from_pem(certificate.as_bytes())?)?;
+        }
+        let encrypted = Pkcs7::encrypt(
+            &certificates,
+            &body.formatted(),
+            Cipher::aes_256_cbc(),
+            Pkcs7Flags::BINARY,
+        )?;
+        Ok(ProtectedBody::Single(attachment(
+            "application/pkcs7-mime; smime-type=enveloped-data; name=\"smime.p7m\"",
+            "smime.p7m",
+            encrypted.to_der()?,
+        )))
+    }
+    fn sign(&self, body: MultiPart) -> Result<MultiPart, SecurityError> {
+        let signature = Pkcs7::sign(
+            &self.certificate,
+            &self.key,
+            &self.chain,
+            &signed_content(&body),
+            Pkcs7Flags::DETACHED | Pkcs7Flags::BINARY,
+        )?;
+        Ok(MultiPart::signed(
+            "application/pkcs7-signature".to_owned(),
+            "sha-256".to_owned(),
+        )
+        .multipart(body)
+        .singlepart(attachment(
+            "application/pkcs7-signature; name=\"smime.p7s\"",
+            "smime.p7s",
+            signature.to_der()?,
+        )))
+    }
+}
+fn attachment(content_type: &str, file_name: &str, der: Vec<u8>) -> SinglePart {
+    SinglePart::builder()
+        .header(ContentType::parse(content_type).expect("Valid content type"))
+        .header(ContentDisposition::attachment(file_name))
+        .body(
+            Body::new_with_encoding(der, ContentTransferEncoding::Base64)
+                .expect("Anything can be base64 encoded"),
+        )
+}
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use openssl::{
+        asn1::Asn1Time,
+        hash::MessageDigest,
+        rsa::Rsa,
+        x509::{X509Name, X509},
+    };
+
+    use super::*;
+    use crate::smtp::secure::{MessageSecurity, SecurityMethod, SecuritySettings};
+    #[derive(Debug)]
+    struct Certificates(String);
+    impl PublicKeyLookup for Certificates {
+        fn smime_certificate(&self, _: &Address) -> Option<String> {
+            Some(self.0.clone())
+        }
+    }
+    fn self_signed() -> (String, String) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_text("CN", "sender@example.com")
+            .unwrap();
+        let name = name.build();
+        let mut certificate = X509::builder().unwrap();
+        certificate.set_version(2).unwrap();
+        certificate.set_subject_name(&name).unwrap();
+        certificate.set_issuer_name(&name).unwrap();
+        certificate.set_pubkey(&key).unwrap();
+        certificate
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        certificate
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        certificate.sign(&key, MessageDigest::sha256()).unwrap();
+        (
+            String::from_utf8(certificate.build().to_pem().unwrap()).unwrap(),
+            String::from_utf8(key.private_key_to_pem_pkcs8().unwrap()).unwrap(),
+        )
+    }
+    #[test]
+    fn test_sign_and_encrypt() {
+        let (certificate, key) = self_signed();
+        let security = MessageSecurity::load(Some(&SecuritySettings {
+            method: SecurityMethod::SMime {
+                certificate: KeySource::Inline(certificate.clone().into()),
+                private_key: KeySource::Inline(key.clone().into()),
+            },
+            protection: Protection::default(),
+            recipient_keys: None,
+        }))
+        .unwrap()
+        .unwrap();
+        let body = || MultiPart::alternative().singlepart(SinglePart::plain("Hello".to_owned()));
+        let recipient = Address::try_from("user@example.com".to_owned()).unwrap();
+        let sign = Protection {
+            sign: true,
+            encrypt: false,
+        };
+        let ProtectedBody::Multi(signed) = security.protect(body(), sign, &[&recipient]).unwrap()
+        else {
+            panic!("Signing returns a multipart");
+        };
+        let formatted = String::from_utf8(signed.formatted()).unwrap();
+        assert!(formatted.contains("multipart/signed"));
+        assert!(formatted.contains("protocol=\"application/pkcs7-signature\""));
+        assert!(formatted.contains("Hello"));
+
+        // No key for the recipient
+        let encrypt = Protection {
+            sign: true,
+            encrypt: true,
+        };
+        assert!(matches!(
+            security.protect(body(), encrypt, &[&recipient]),
+            Err(SecurityError::MissingPublicKey(_))
+        ));
+        security.set_public_keys(Arc::new(Certificates(certificate.clone())));
+        let ProtectedBody::Single(encrypted) =
+            security.protect(body(), encrypt, &[&recipient]).unwrap()
+        else {
+            panic!("Encrypting returns a single part");
+        };
+        let formatted = String::from_utf8(encrypted.formatted()).unwrap();
+        assert!(formatted.contains("application/pkcs7-mime"));
+        assert!(!formatted.contains("Hello"));
+
+        // The recipient can decrypt it and check the signature
+        let der = openssl::base64::decode_block(
+            &formatted
+                .split_once("\r\n\r\n")
+                .unwrap()
+                .1
+                .replace("\r\n", ""),
+        )
+        .unwrap();
+        let certificate = X509::from_pem(certificate.as_bytes()).unwrap();
+        let key = PKey::private_key_from_pem(key.as_bytes()).unwrap();
+        let decrypted = Pkcs7::from_der(&der)
+            .unwrap()
+            .decrypt(&key, &certificate, Pkcs7Flags::BINARY)
+            .unwrap();
+        let decrypted = String::from_utf8(decrypted).unwrap();
+        let boundary = decrypted
+            .split_once("boundary=\"")
+            .unwrap()
+            .1
+            .split_once('"')
+            .unwrap()
+            .0;
+        let delimiter = format!("\r\n--{boundary}");
+        let parts: Vec<_> = decrypted.split(&delimiter).collect();
+        let content = parts[1].trim_start_matches("\r\n");
+        let signature = parts[2]
+            .split_once("\r\n\r\n")
+            .unwrap()
+            .1
+            .replace("\r\n", "");
+        let signature =
+            Pkcs7::from_der(&openssl::base64::decode_block(&signature).unwrap()).unwrap();
+        let mut store = openssl::x509::store::X509StoreBuilder::new().unwrap();
+        store.add_cert(certificate.clone()).unwrap();
+        let mut certificates = Stack::new().unwrap();
+        certificates.push(certificate).unwrap();
+        signature
+            .verify(
+                &certificates,
+                &store.build(),
+                Some(content.as_bytes()),
+                None,
+                Pkcs7Flags::BINARY,
+            )
+            .unwrap();
+    }
+}
@@ -2,15 +2,20 @@ use std::{
     convert::Infallible,
     fmt::Display,
     net::{Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
     str::FromStr,
     time::Duration,
 };
 
 use serde::{Deserialize, Serialize};
-use strum::{Display, EnumIs, EnumString, EnumVariantNames};
+use strum::{Display, EnumIs, EnumString, VariantNames};
 
+use super::{DkimSettings, PoolSettings, SMTPAuthentication, SecuritySettings, TlsSettings};
 use crate::{
     email_types::{Address, Mailbox},
+    rate_limit::RateLimitSettings,
+    safety::SafetySettings,
+    secret::Secret,
     EmailSettingsType,
 };
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -42,7 +47,7 @@ impl Default for ClientId {
         hostname::get()
             .ok()
             .and_then(|v| v.to_str().map(ToOwned::to_owned))
-            .map(|v| Self::Domain(v))
+            .map(Self::Domain)
             .unwrap_or(Self::IPv4(Ipv4Addr::LOCALHOST))
     }
 }
@@ -106,6 +111,22 @@ mod tests {
         assert_eq!(ipv6_parsed, ClientId::IPv6(ipv6.parse().unwrap()));
     }
 }
+/// A key or certificate. Files are read when the service starts
+///
+/// Inline keys are a [Secret] so they are not printed with the settings
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub enum KeySource {
+    File(PathBuf),
+    Inline(Secret),
+}
+impl KeySource {
+    pub(crate) fn read(&self) -> std::io::Result<String> {
+        match self {
+            KeySource::File(path) => std::fs::read_to_string(path),
+            KeySource::Inline(key) => Ok(key.expose_secret().to_owned()),
+        }
+    }
+}
 #[derive(
     Debug,
     Deserialize,
@@ -118,7 +139,7 @@ mod tests {
     Display,
     EnumString,
     EnumIs,
-    EnumVariantNames,
+    VariantNames,
 )]
 pub enum SMTPServiceEncryption {
     /// Default Port is 25
@@ -126,6 +147,10 @@ pub enum SMTPServiceEncryption {
     NONE,
     /// Default Port is 587
     StartTLS,
+    /// STARTTLS if the server offers it. Otherwise plain text
+    ///
+    /// Default Port is 587
+    Opportunistic,
     /// Default Port is 465
     TLS,
 }
@@ -133,7 +158,7 @@ impl SMTPServiceEncryption {
     pub fn default_port(&self) -> u16 {
         match self {
             SMTPServiceEncryption::NONE => 25,
-            SMTPServiceEncryption::StartTLS => 587,
+            SMTPServiceEncryption::StartTLS | SMTPServiceEncryption::Opportunistic => 587,
             SMTPServiceEncryption::TLS => 465,
         }
     }
@@ -141,7 +166,9 @@ impl SMTPServiceEncryption {
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
 pub struct SMTPServiceSettings {
     pub username: String,
-    pub password: String,
+    pub password: Secret,
+    #[serde(default)]
+    pub authentication: SMTPAuthentication,
     pub host: String,
     /// If None, the port will be determined by the encryption type
     ///
@@ -150,6 +177,8 @@ pub struct SMTPServiceSettings {
     /// - TLS: 465
     pub port: Option<u16>,
     pub encryption: SMTPServiceEncryption,
+    #[serde(default)]
+    pub tls: TlsSettings,
     pub from: Mailbox,
     pub reply_to: Option<Mailbox>,
     pub timeout: Option<i64>,
@@ -157,6 +186,23 @@ pub struct SMTPServiceSettings {
     pub client_id: ClientId,
     #[serde(default)]
     pub channel_size: usize,
+    #[serde(default)]
+    pub pool: PoolSettings,
+    /// Keeps queued emails in this directory so they are sent after a restart
+    #[serde(default)]
+    pub spool_directory: Option<PathBuf>,
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+    #[serde(default)]
+    pub dkim: Option<DkimSettings>,
+    #[serde(default)]
+    pub security: Option<SecuritySettings>,
+    /// The domain of generated Message-IDs. Defaults to the domain of the from address
+    #[serde(default)]
+    pub message_id_domain: Option<String>,
+    /// Redirects or filters the recipients. For staging and QA
+    #[serde(default)]
+    pub safety: Option<SafetySettings>,
 }
 impl SMTPServiceSettings {
     pub(crate) fn get_timeout(&self) -> Option<Duration> {
@@ -175,9 +221,11 @@ impl Default for SMTPServiceSettings {
         Self {
             username: Default::default(),
             password: Default::default(),
+            authentication: SMTPAuthentication::default(),
             host: "127.0.0.1".to_string(),
             port: None,
             encryption: SMTPServiceEncryption::TLS,
+            tls: TlsSettings::default(),
             from: Mailbox::new(None, unsafe {
                 Address::new_unchecked("no-reply@example.com")
             }),
@@ -185,6 +233,13 @@ impl Default for SMTPServiceSettings {
                 Address::new_unchecked("admin@edxample.com")
             })),
             channel_size: 0,
+            pool: PoolSettings::default(),
+            spool_directory: None,
+            rate_limit: RateLimitSettings::default(),
+            dkim: None,
+            security: None,
+            message_id_domain: None,
+            safety: None,
             timeout: Some(60000),
             client_id: ClientId::default(),
         }
@@ -199,4 +254,12 @@ impl EmailSettingsType for SMTPServiceSettings {
     fn reply_to(&self) -> Option<&Mailbox> {
         self.reply_to.as_ref()
     }
+
+    fn message_id_domain(&self) -> Option<&str> {
+        self.message_id_domain.as_deref()
+    }
+
+    fn safety(&self) -> Option<&SafetySettings> {
+        self.safety.as_ref()
+    }
 }
@@ -0,0 +1,121 @@
+use lettre::transport::smtp::client::{
+    Certificate, CertificateStore, Identity, TlsParameters, TlsVersion as LettreTlsVersion,
+};
+use rustls_pki_types::{pem::PemObject, CertificateDer};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::warn;
+
+use super::KeySource;
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("Unable to read a TLS certificate or key: {0}")]
+    Read(#[from] std::io::Error),
+    #[error("No certificate was found in {0}")]
+    NoCertificate(&'static str),
+    #[error(transparent)]
+    Invalid(#[from] lettre::transport::smtp::Error),
+}
+/// The lowest TLS version that is accepted. Older versions are not supported
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum TlsVersion {
+    #[default]
+    Tls12,
+    Tls13,
+}
+impl From<TlsVersion> for LettreTlsVersion {
+    fn from(value: TlsVersion) -> Self {
+        match value {
+            TlsVersion::Tls12 => Self::Tlsv12,
+            TlsVersion::Tls13 => Self::Tlsv13,
+        }
+    }
+}
+/// Identifies the client for mutual TLS
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub struct ClientCertificate {
+    /// A PEM certificate
+    pub certificate: KeySource,
+    /// A PEM PKCS#8 private key
+    pub private_key: KeySource,
+}
+/// Used by StartTLS, Opportunistic and TLS encryption
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq, Hash)]
+#[serde(default)]
+pub struct TlsSettings {
+    /// PEM certificates to trust. Such as the certificate of a private CA
+    pub root_certificates: Option<KeySource>,
+    /// Only trust the `root_certificates`
+    pub disable_system_roots: bool,
+    pub client_certificate: Option<ClientCertificate>,
+    pub min_version: TlsVersion,
+    /// The name sent with SNI and checked against the certificate. Defaults to the host
+    pub server_name: Option<String>,
+    /// Accepts any certificate for any name. Only for development
+    pub dangerous_accept_invalid_certs: bool,
+}
+impl TlsSettings {
+    pub(crate) fn parameters(&self, host: &str) -> Result<TlsParameters, TlsError> {
+        let server_name = self.server_name.clone().unwrap_or_else(|| host.to_owned());
+        let mut builder = TlsParameters::builder(server_name)
+            .set_min_tls_version(self.min_version.into())
+            .dangerous_accept_invalid_certs(self.dangerous_accept_invalid_certs)
+            .dangerous_accept_invalid_hostnames(self.dangerous_accept_invalid_certs);
+        if self.disable_system_roots {
+            builder = builder.certificate_store(CertificateStore::None);
+        }
+        if let Some(root_certificates) = &self.root_certificates {
+            let pem = root_certificates.read()?;
+            if !pem.contains("-----BEGIN CERTIFICATE-----") {
+                return Err(TlsError::NoCertificate("root_certificates"));
+            }
+            builder = builder.add_root_certificate(Certificate::from_pem(pem.as_bytes())?);
+        }
+        if let Some(client) = &self.client_certificate {
+            // lettre's rustls backend expects the certificate as DER
+            let certificate = CertificateDer::from_pem_slice(client.certificate.read()?.as_bytes())
+                .map_err(|_| TlsError::NoCertificate("client_certificate"))?;
+            let identity = Identity::from_pem(&certificate, client.private_key.read()?.as_bytes())?;
+            builder = builder.identify_with(identity);
+        }
+        if self.dangerous_accept_invalid_certs {
+            warn!("TLS certificates are not verified. Do not use this in production");
+        }
+        Ok(builder.build()?)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parameters() {
+        let settings = TlsSettings {
+            server_name: Some("relay.internal".to_owned()),
+            ..Default::default()
+        };
+        let parameters = settings.parameters("127.0.0.1").unwrap();
+        assert_eq!(parameters.domain(), "relay.internal");
+
+        let settings = TlsSettings {
+            root_certificates: Some(KeySource::Inline("Not a certificate".into())),
+            ..Default::default()
+        };
+        assert!(matches!(
+            settings.parameters("127.0.0.1"),
+            Err(TlsError::NoCertificate("root_certificates"))
+        ));
+
+        let settings = TlsSettings {
+            client_certificate: Some(ClientCertificate {
+                certificate: KeySource::File("does-not-exist.pem".into()),
+                private_key: KeySource::Inline("".into()),
+            }),
+            ..Default::default()
+        };
+        assert!(matches!(
+            settings.parameters("127.0.0.1"),
+            Err(TlsError::Read(_))
+        ));
+    }
+}
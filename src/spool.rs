@@ -0,0 +1,284 @@
+/*!
+Keeps queued emails on disk so they survive a restart.
+
+Every email is written to its own file in the spool directory before it is queued. The file is removed once the email is delivered.
+While an email is retried after a transient error its file stays in the spool.
+If delivery fails with a permanent error, or the email runs out of attempts, the file is moved into the `failed` directory inside the spool so it can be inspected.
+Anything left in the spool when the service starts is delivered again. So an email may be sent twice if the process stops mid delivery.
+
+A spool file is the envelope followed by the formatted message. Times are milliseconds since the Unix epoch.
+```text
+priority <critical|normal|bulk>
+expires <time>
+tracking <tracking id>
+scheduled <id> <time>
+from <address>
+to <address>
+to <address>
+
+<formatted message>
+```
+*/
+use std::{
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use lettre::{address::Envelope, Address};
+use tracing::warn;
+
+use crate::{
+    email_types::MessageId,
+    worker::{QueueOptions, RawEmail, Schedule},
+    ScheduledId,
+};
+
+const EXTENSION: &str = "eml";
+/// Where emails that failed to deliver are moved to
+pub(crate) const FAILED_DIRECTORY: &str = "failed";
+/// The name of a file in the spool
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct SpoolId(String);
+/// An email read back from the spool
+#[derive(Debug)]
+pub(crate) struct Spooled {
+    pub id: SpoolId,
+    pub email: RawEmail,
+    pub options: QueueOptions,
+    pub schedule: Option<Schedule>,
+}
+#[derive(Debug)]
+pub(crate) struct Spool {
+    directory: PathBuf,
+    next: AtomicU64,
+}
+impl Spool {
+    /// Opens the spool creating the directory if needed.
+    ///
+    /// Returns the emails that were left in the spool. Oldest first
+    pub fn open(directory: impl Into<PathBuf>) -> io::Result<(Self, Vec<Spooled>)> {
+        let directory = directory.into();
+        fs::create_dir_all(directory.join(FAILED_DIRECTORY))?;
+        let mut pending = Vec::new();
+        for entry in fs::read_dir(&directory)? {
+            let path = entry?.path();
+            if path.extension() != Some(OsStr::new(EXTENSION)) {
+                continue;
+            }
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let id = SpoolId(name.to_owned());
+            match read(&path) {
+                Ok((email, options, schedule)) => pending.push(Spooled {
+                    id,
+                    email,
+                    options,
+                    schedule,
+                }),
+                Err(err) => {
+                    warn!("Unable to read spooled email {}: {}", path.display(), err);
+                    let _ = fs::rename(&path, directory.join(FAILED_DIRECTORY).join(name));
+                }
+            }
+        }
+        pending.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok((
+            Self {
+                directory,
+                next: AtomicU64::new(0),
+            },
+            pending,
+        ))
+    }
+    /// Writes the email to disk. The file is only visible once it is completely written
+    pub fn store(
+        &self,
+        email: &RawEmail,
+        options: &QueueOptions,
+        schedule: Option<Schedule>,
+    ) -> io::Result<SpoolId> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let sequence = self.next.fetch_add(1, Ordering::Relaxed);
+        let id = SpoolId(format!("{timestamp:024}-{sequence:010}.{EXTENSION}"));
+        let temporary = self.directory.join(format!(".{}.tmp", id.0));
+        let mut file = File::create(&temporary)?;
+        write(&mut file, email, options, schedule)?;
+        file.sync_all()?;
+        fs::rename(&temporary, self.directory.join(&id.0))?;
+        Ok(id)
+    }
+    /// The email was delivered
+    pub fn complete(&self, id: &SpoolId) {
+        if let Err(err) = fs::remove_file(self.directory.join(&id.0)) {
+            warn!("Unable to remove {} from the spool: {}", id.0, err);
+        }
+    }
+    /// The email failed to deliver. It is kept in the failed directory
+    pub fn fail(&self, id: &SpoolId) {
+        let failed = self.directory.join(FAILED_DIRECTORY).join(&id.0);
+        if let Err(err) = fs::rename(self.directory.join(&id.0), failed) {
+            warn!("Unable to move {} out of the spool: {}", id.0, err);
+        }
+    }
+}
+fn write(
+    file: &mut impl Write,
+    email: &RawEmail,
+    options: &QueueOptions,
+    schedule: Option<Schedule>,
+) -> io::Result<()> {
+    writeln!(file, "priority {}", options.priority)?;
+    if let Some(expires_at) = options.expires_at {
+        writeln!(file, "expires {}", to_millis(expires_at))?;
+    }
+    if let Some(tracking_id) = &options.tracking_id {
+        // The header is one line
+        writeln!(file, "tracking {}", tracking_id.replace(['\r', '\n'], " "))?;
+    }
+    if let Some(message_id) = &options.message_id {
+        writeln!(file, "message-id {}", message_id.as_str())?;
+    }
+    if let Some(Schedule { id, at }) = schedule {
+        writeln!(file, "scheduled {} {}", id.0, to_millis(at))?;
+    }
+    if let Some(from) = email.envelope.from() {
+        writeln!(file, "from {from}")?;
+    }
+    for to in email.envelope.to() {
+        writeln!(file, "to {to}")?;
+    }
+    writeln!(file)?;
+    file.write_all(&email.formatted)
+}
+fn to_millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+fn from_millis(millis: &str) -> Option<SystemTime> {
+    Some(UNIX_EPOCH + Duration::from_millis(millis.parse().ok()?))
+}
+fn read(path: &Path) -> io::Result<(RawEmail, QueueOptions, Option<Schedule>)> {
+    let contents = fs::read(path)?;
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_owned());
+    let mut from = None;
+    let mut to = Vec::new();
+    let mut options = QueueOptions::default();
+    let mut schedule = None;
+    let mut rest = contents.as_slice();
+    loop {
+        let end = rest
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .ok_or_else(|| invalid("The envelope is not terminated"))?;
+        let line = std::str::from_utf8(&rest[..end]).map_err(|_| invalid("Invalid envelope"))?;
+        rest = &rest[end + 1..];
+        if line.is_empty() {
+            break;
+        }
+        let (key, value) = line
+            .split_once(' ')
+            .ok_or_else(|| invalid("Invalid envelope"))?;
+        if key == "priority" {
+            options.priority = value.parse().map_err(|_| invalid("Invalid priority"))?;
+            continue;
+        }
+        if key == "expires" {
+            options.expires_at = Some(from_millis(value).ok_or_else(|| invalid("Invalid expiry"))?);
+            continue;
+        }
+        if key == "tracking" {
+            options.tracking_id = Some(value.to_owned());
+            continue;
+        }
+        if key == "message-id" {
+            options.message_id = Some(MessageId::new(value));
+            continue;
+        }
+        if key == "scheduled" {
+            let (id, due) = value
+                .split_once(' ')
+                .and_then(|(id, due)| Some((id.parse().ok()?, from_millis(due)?)))
+                .ok_or_else(|| invalid("Invalid schedule"))?;
+            schedule = Some(Schedule {
+                id: ScheduledId(id),
+                at: due,
+            });
+            continue;
+        }
+        let address = Address::from_str(value).map_err(|_| invalid("Invalid address"))?;
+        match key {
+            "from" => from = Some(address),
+            "to" => to.push(address),
+            _ => return Err(invalid("Invalid envelope")),
+        }
+    }
+    let envelope = Envelope::new(from, to).map_err(|_| invalid("Invalid envelope"))?;
+    Ok((
+        RawEmail {
+            envelope,
+            formatted: rest.to_vec(),
+        },
+        options,
+        schedule,
+    ))
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Priority;
+    #[test]
+    fn test_store_and_open() {
+        let directory = std::env::temp_dir().join(format!("any_mail_spool_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let envelope = Envelope::new(
+            Some("sender@example.com".parse().unwrap()),
+            vec![
+                "a@example.com".parse().unwrap(),
+                "b@example.com".parse().unwrap(),
+            ],
+        )
+        .unwrap();
+        let email = RawEmail {
+            envelope,
+            formatted: b"Subject: Test\r\n\r\nBody\r\n".to_vec(),
+        };
+        let (spool, pending) = Spool::open(&directory).unwrap();
+        assert!(pending.is_empty());
+        let schedule = Schedule {
+            id: ScheduledId(7),
+            at: UNIX_EPOCH + Duration::from_millis(1_700_000_000_000),
+        };
+        let options = QueueOptions {
+            priority: Priority::Bulk,
+            expires_at: Some(UNIX_EPOCH + Duration::from_millis(1_700_000_060_000)),
+            tracking_id: Some("order 42".to_owned()),
+            message_id: Some(MessageId::new("1@example.com")),
+        };
+        let first = spool.store(&email, &QueueOptions::default(), None).unwrap();
+        let second = spool.store(&email, &options, Some(schedule)).unwrap();
+        let third = spool.store(&email, &QueueOptions::default(), None).unwrap();
+        spool.complete(&first);
+        spool.fail(&third);
+
+        let (_, pending) = Spool::open(&directory).unwrap();
+        assert_eq!(pending.len(), 1);
+        let restored = &pending[0];
+        assert_eq!(restored.id, second);
+        assert_eq!(restored.options, options);
+        assert_eq!(restored.schedule, Some(schedule));
+        assert_eq!(restored.email.envelope, email.envelope);
+        assert_eq!(restored.email.formatted, email.formatted);
+        assert!(directory.join(FAILED_DIRECTORY).join(&third.0).exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,6 +1,8 @@
 use std::error::Error;
 
 use serde::Serialize;
+
+use crate::calendar::Calendar;
 pub trait EmailTemplate {
     fn template_txt() -> &'static str;
     fn template_html() -> &'static str;
@@ -19,4 +21,6 @@ pub trait TemplateSet<'a> {
 pub struct EmailBody {
     pub html_body: Option<String>,
     pub text_body: Option<String>,
+    /// Sent as a `text/calendar` alternative and an `invite.ics` attachment
+    pub calendar: Option<Calendar>,
 }
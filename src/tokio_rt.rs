@@ -1,11 +1,18 @@
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
+    atomic::{AtomicBool, AtomicUsize, Ordering},
     Arc,
 };
+#[cfg(feature = "lettre")]
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
 
 use tokio::sync::Notify;
 /// The Service State handles watching for a shutdown signal.
 ///
+/// One can be shared by every service with [MailService::init_with_state](crate::MailService::init_with_state).
+///
 /// This is the Tokio Implementation.
 ///
 /// Ordering is Relaxed because the only time it is changed is when the service is shutting down.
@@ -13,23 +20,44 @@ use tokio::sync::Notify;
 pub struct ServiceState {
     pub notify: Notify,
     pub running: AtomicBool,
+    /// Ctrl-C and SIGTERM shut the services down
+    handle_signals: bool,
+    watching: AtomicBool,
+    /// Workers that have not stopped yet
+    workers: AtomicUsize,
+    stopped: Notify,
 }
 impl ServiceState {
-    /// Creates a new Service State.
+    /// Creates a new Service State. Ctrl-C and SIGTERM shut it down
     pub fn new() -> Arc<Self> {
+        Self::create(true)
+    }
+    /// The application calls [ServiceState::shutdown] itself
+    pub fn without_signal_handling() -> Arc<Self> {
+        Self::create(false)
+    }
+    fn create(handle_signals: bool) -> Arc<Self> {
         Arc::new(Self {
             notify: Notify::new(),
             running: AtomicBool::new(true),
+            handle_signals,
+            watching: AtomicBool::new(false),
+            workers: AtomicUsize::new(0),
+            stopped: Notify::new(),
         })
     }
     /// Starts a task that watches for a shutdown signal.
+    ///
+    /// Does nothing if signal handling is disabled or it is already watching
     pub fn watch_for_shutdown(this: Arc<Self>) {
+        if !this.handle_signals || this.watching.swap(true, Ordering::Relaxed) {
+            return;
+        }
         tokio::spawn(async move {
-            if let Err(e) = tokio::signal::ctrl_c().await {
+            if let Err(e) = signal().await {
                 tracing::error!("Failed to watch for shutdown: {}", e);
             }
-            this.running.store(false, Ordering::Relaxed);
-            this.notify.notify_waiters();
+            this.shutdown();
         });
     }
     /// Checks if the service is running.
@@ -38,8 +66,93 @@ impl ServiceState {
     }
     /// Shuts down the service.
     pub fn shutdown(&self) {
-        self.notify.notify_waiters();
-
         self.running.store(false, Ordering::Relaxed);
+
+        self.notify.notify_waiters();
+        if self.workers.load(Ordering::Relaxed) == 0 {
+            self.stopped.notify_waiters();
+        }
+    }
+    /// Completes once the services are shut down and every email that was being sent is finished
+    pub async fn join(&self) {
+        loop {
+            let stopped = self.stopped.notified();
+            if !self.is_running() && self.workers.load(Ordering::Relaxed) == 0 {
+                return;
+            }
+            stopped.await;
+        }
+    }
+    /// Completes when the service is told to shut down. Create it before checking anything it should not miss
+    #[cfg(feature = "lettre")]
+    pub(crate) fn notified(&self) -> impl Future<Output = ()> + Send + '_ {
+        self.notify.notified()
+    }
+    /// Held by a worker until it stops
+    #[cfg(feature = "lettre")]
+    pub(crate) fn worker_started(self: &Arc<Self>) -> WorkerGuard {
+        self.workers.fetch_add(1, Ordering::Relaxed);
+        WorkerGuard(self.clone())
+    }
+}
+#[cfg(feature = "lettre")]
+pub(crate) struct WorkerGuard(Arc<ServiceState>);
+#[cfg(feature = "lettre")]
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        if self.0.workers.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.0.stopped.notify_waiters();
+        }
+    }
+}
+#[cfg(unix)]
+async fn signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+#[cfg(not(unix))]
+async fn signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+/// Runs the future in the background
+#[cfg(feature = "lettre")]
+pub(crate) fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+    tokio::spawn(future);
+}
+/// Runs blocking code, such as writing to disk, on a thread that does not run tasks
+#[cfg(feature = "lettre")]
+pub(crate) async fn spawn_blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+}
+#[cfg(feature = "lettre")]
+pub(crate) async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await
+}
+#[cfg(feature = "lettre")]
+pub(crate) async fn sleep_until(deadline: Instant) {
+    tokio::time::sleep_until(deadline.into()).await
+}
+#[cfg(all(test, feature = "lettre"))]
+mod tests {
+    use super::*;
+    #[tokio::test]
+    async fn test_join() {
+        let state = ServiceState::without_signal_handling();
+        let worker = state.worker_started();
+        state.shutdown();
+        let join = tokio::spawn({
+            let state = state.clone();
+            async move { state.join().await }
+        });
+        sleep(Duration::from_millis(10)).await;
+        assert!(!join.is_finished());
+        drop(worker);
+        join.await.unwrap();
     }
 }
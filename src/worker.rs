@@ -0,0 +1,1392 @@
+/*!
+The queue and background worker shared by the services that deliver from a channel.
+
+The [Access](crate::EmailAccess) builds a [Message] and pushes it into the [Queue]. The worker pulls it back out and hands it to its [Backend].
+
+Every [Priority] has its own channel. Higher priorities are always taken first, but every [BULK_EVERY] emails one bulk email is let through so it is never starved.
+
+Scheduled emails wait in a task until they are due and are then pushed into the queue.
+
+An email that fails with a transient error is queued again after [RETRY_BACKOFF], which doubles for every retry. It is given up on after [MAX_ATTEMPTS].
+
+Emails that expire while waiting are not sent. They are answered with [QueueError::Expired] and moved to the failed directory of the spool.
+
+Every queue keeps [Metrics] for its backend and reports every step to its [Events].
+
+If the service has a spool directory every queued email is also kept on disk until it is delivered. See [spool](crate::spool)
+*/
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Debug,
+    future::{ready, Future},
+    io,
+    path::Path,
+    pin::pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use futures_util::{
+    future::{abortable, AbortHandle},
+    select_biased, FutureExt,
+};
+use lettre::{
+    address::Envelope,
+    message::{dkim::DkimConfig, MessageBuilder},
+    Message,
+};
+use serde::Serialize;
+use tracing::{debug, error, warn};
+
+use crate::{
+    email_types::{Address, MessageId},
+    events::{DropReason, EmailEvent, EventHandler, EventKind, EventReceiver, Events},
+    health::{Health, HealthTracker, PoolStatus},
+    merge::{self, Merge, MergeOutcome, MergeSummary},
+    metrics::{Metrics, MetricsSnapshot},
+    rate_limit::{RateLimitStatus, RateLimiter},
+    rt,
+    shared::{bounded_channel, unbdounded_channel, Mutex, Receiver, Sender, WeakSender},
+    smtp::{email_to_message, MessageSecurity, PublicKeyLookup, SMTPError},
+    spool::{Spool, SpoolId, Spooled},
+    template::{EmailTemplate, TemplateSet},
+    Email, EmailAccess, EmailSettingsType, MailService, Priority, QueueDepth, Scheduled,
+    ScheduledId, ServiceState,
+};
+/// While bulk mail is waiting, one bulk email is sent after this many higher priority emails
+pub(crate) const BULK_EVERY: u32 = 8;
+/// How many times an email is sent before it is given up on. Only transient errors are retried
+pub(crate) const MAX_ATTEMPTS: u32 = 5;
+/// The wait before the first retry. It doubles for every retry after that
+pub(crate) const RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The transport a worker delivers queued messages with.
+///
+/// Public so it can bound [WorkerAccess]. It can not be named outside of the crate
+pub trait Backend: Send + Sync + 'static {
+    /// The name used to label metrics
+    const NAME: &'static str;
+    /// What the server answered for a successful delivery.
+    type Response: Debug + Send + 'static;
+    type Error: Error + From<QueueError> + Send + 'static;
+
+    /// The email is queued again if sending it later might succeed
+    fn is_transient(error: &Self::Error) -> bool;
+    fn deliver(
+        &self,
+        email: &RawEmail,
+    ) -> impl Future<Output = Result<Self::Response, Self::Error>> + Send;
+    /// How many emails the worker delivers at the same time
+    fn concurrency(&self) -> usize {
+        1
+    }
+    /// The connection pool of the backend. None if it does not pool connections
+    fn pool_status(&self) -> Option<PoolStatus> {
+        None
+    }
+    /// Recipients a successful delivery did not reach. Such as the ones an LMTP server rejected after the data
+    fn undelivered(_response: &Self::Response) -> Vec<Undelivered> {
+        Vec::new()
+    }
+    /// Sends a merge without going through the queue.
+    ///
+    /// The merge is given back if every email should be rendered and queued instead
+    fn send_merge<'a, T: EmailTemplate, D: Serialize>(
+        &self,
+        _templates: &impl TemplateSet<'a>,
+        merge: Merge<D>,
+    ) -> impl Future<Output = Result<MergeSummary, Merge<D>>> {
+        ready(Err(merge))
+    }
+}
+/// Lets the [WorkerAccess] share the backend with its worker
+impl<B: Backend> Backend for Arc<B> {
+    const NAME: &'static str = B::NAME;
+    type Response = B::Response;
+    type Error = B::Error;
+
+    fn is_transient(error: &Self::Error) -> bool {
+        B::is_transient(error)
+    }
+    fn deliver(
+        &self,
+        email: &RawEmail,
+    ) -> impl Future<Output = Result<Self::Response, Self::Error>> + Send {
+        self.as_ref().deliver(email)
+    }
+    fn concurrency(&self) -> usize {
+        self.as_ref().concurrency()
+    }
+    fn pool_status(&self) -> Option<PoolStatus> {
+        self.as_ref().pool_status()
+    }
+    fn undelivered(response: &Self::Response) -> Vec<Undelivered> {
+        B::undelivered(response)
+    }
+    fn send_merge<'a, T: EmailTemplate, D: Serialize>(
+        &self,
+        templates: &impl TemplateSet<'a>,
+        merge: Merge<D>,
+    ) -> impl Future<Output = Result<MergeSummary, Merge<D>>> {
+        self.as_ref().send_merge::<T, D>(templates, merge)
+    }
+}
+/// A recipient that a delivery did not reach
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Undelivered {
+    pub recipient: Address,
+    pub error: String,
+    /// Sending to it again later might succeed
+    pub transient: bool,
+}
+
+/// An email as it is sent over the wire.
+#[derive(Debug, Clone)]
+pub struct RawEmail {
+    pub envelope: Envelope,
+    pub formatted: Vec<u8>,
+}
+impl From<Message> for RawEmail {
+    fn from(message: Message) -> Self {
+        Self {
+            envelope: message.envelope().clone(),
+            formatted: message.formatted(),
+        }
+    }
+}
+fn message_id(message: &Message) -> Option<MessageId> {
+    message.headers().get_raw("Message-ID").map(MessageId::new)
+}
+/// How an email is queued. Taken from the [Email]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub(crate) struct QueueOptions {
+    pub priority: Priority,
+    pub expires_at: Option<SystemTime>,
+    pub tracking_id: Option<String>,
+    /// Read from the message when it is queued
+    pub message_id: Option<MessageId>,
+}
+impl QueueOptions {
+    pub fn of(email: &impl Email) -> Self {
+        Self {
+            priority: email.priority(),
+            expires_at: email.expires_at(),
+            tracking_id: email.tracking_id(),
+            message_id: None,
+        }
+    }
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= SystemTime::now())
+    }
+}
+/// An email waiting in the queue.
+pub(crate) struct QueuedEmail<B: Backend> {
+    pub email: RawEmail,
+    pub options: QueueOptions,
+    /// Where the email is kept in the spool
+    pub spool_id: Option<SpoolId>,
+    /// Receives the result of the delivery if the sender is waiting on it.
+    pub reply: Option<Sender<Result<B::Response, B::Error>>>,
+    /// How many times it has been sent
+    pub attempts: u32,
+}
+impl<B: Backend> QueuedEmail<B> {
+    fn event(&self, kind: EventKind) -> EmailEvent {
+        EmailEvent {
+            backend: B::NAME,
+            tracking_id: self.options.tracking_id.clone(),
+            message_id: self.options.message_id.clone(),
+            recipients: self
+                .email
+                .envelope
+                .to()
+                .iter()
+                .cloned()
+                .map(Address::from)
+                .collect(),
+            kind,
+            at: SystemTime::now(),
+        }
+    }
+    /// A copy for some of the recipients. It is not in the spool and nobody waits on it
+    fn for_recipients(&self, recipients: &[Undelivered]) -> Option<Self> {
+        let to = self
+            .email
+            .envelope
+            .to()
+            .iter()
+            .filter(|to| {
+                let to: &str = to.as_ref();
+                recipients.iter().any(|r| r.recipient.as_ref() == to)
+            })
+            .cloned()
+            .collect();
+        let envelope = Envelope::new(self.email.envelope.from().cloned(), to).ok()?;
+        Some(Self {
+            email: RawEmail {
+                envelope,
+                formatted: self.email.formatted.clone(),
+            },
+            options: self.options.clone(),
+            spool_id: None,
+            reply: None,
+            attempts: self.attempts,
+        })
+    }
+}
+/// When a scheduled email is due
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Schedule {
+    pub id: ScheduledId,
+    pub at: SystemTime,
+}
+/// A scheduled email waiting to be queued
+#[derive(Debug)]
+struct Held {
+    task: AbortHandle,
+    spool_id: Option<SpoolId>,
+    /// Emitted if it is cancelled
+    cancelled: EmailEvent,
+}
+/// The scheduled emails that have not been queued yet
+#[derive(Debug, Default)]
+struct Scheduler {
+    pending: Mutex<HashMap<ScheduledId, Held>>,
+    next: AtomicU64,
+}
+/// Why an email could not be queued or was not sent
+#[derive(Debug, thiserror::Error)]
+pub enum QueueError {
+    #[error("The email queue is closed")]
+    Closed,
+    #[error("Unable to write the email to the spool: {0}")]
+    Spool(io::Error),
+    #[error("The email expired before it was sent")]
+    Expired,
+}
+/// The sending side of the queue. Held by the Access
+pub(crate) struct Queue<B: Backend> {
+    /// One for each [Priority]
+    senders: [Sender<QueuedEmail<B>>; 3],
+    spool: Option<Arc<Spool>>,
+    scheduler: Arc<Scheduler>,
+    metrics: Arc<Metrics>,
+    health: Arc<HealthTracker>,
+    events: Arc<Events>,
+}
+impl<B: Backend> Clone for Queue<B> {
+    fn clone(&self) -> Self {
+        Self {
+            senders: self.senders.clone(),
+            spool: self.spool.clone(),
+            scheduler: self.scheduler.clone(),
+            metrics: self.metrics.clone(),
+            health: self.health.clone(),
+            events: self.events.clone(),
+        }
+    }
+}
+impl<B: Backend> Debug for Queue<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Queue")
+            .field("senders", &self.senders)
+            .field("spool", &self.spool)
+            .field("scheduler", &self.scheduler)
+            .field("metrics", &self.metrics)
+            .field("health", &self.health)
+            .field("events", &self.events)
+            .finish()
+    }
+}
+impl<B: Backend> Queue<B> {
+    fn queue(
+        &self,
+        email: RawEmail,
+        options: QueueOptions,
+        spool_id: Option<SpoolId>,
+        reply: Option<Sender<Result<B::Response, B::Error>>>,
+    ) -> Result<(), QueueError> {
+        let queued = QueuedEmail {
+            email,
+            options,
+            spool_id,
+            reply,
+            attempts: 0,
+        };
+        let event = queued.event(EventKind::Queued);
+        self.senders[queued.options.priority as usize]
+            .send(queued)
+            .map_err(|_| QueueError::Closed)?;
+        self.events.emit(event);
+        self.metrics.queued();
+        self.metrics.record_depth(self.depth());
+        Ok(())
+    }
+    /// Pushes an email to the queue.
+    ///
+    /// It is written to the spool on the calling thread
+    pub fn send(&self, message: Message, mut options: QueueOptions) -> Result<(), QueueError> {
+        options.message_id = message_id(&message);
+        let email = RawEmail::from(message);
+        let spool_id = match &self.spool {
+            Some(spool) => Some(
+                spool
+                    .store(&email, &options, None)
+                    .map_err(QueueError::Spool)?,
+            ),
+            None => None,
+        };
+        self.queue(email, options, spool_id, None)
+    }
+    /// Pushes an email to the queue and waits for the worker to deliver it.
+    ///
+    /// It is written to the spool on a blocking thread so the runtime is not held up
+    pub async fn send_and_wait(
+        &self,
+        message: Message,
+        mut options: QueueOptions,
+    ) -> Result<Result<B::Response, B::Error>, QueueError> {
+        options.message_id = message_id(&message);
+        let email = RawEmail::from(message);
+        let (email, options, spool_id) = match &self.spool {
+            Some(spool) => {
+                let spool = spool.clone();
+                let (email, options, stored) = rt::spawn_blocking(move || {
+                    let stored = spool.store(&email, &options, None);
+                    (email, options, stored)
+                })
+                .await;
+                (email, options, Some(stored.map_err(QueueError::Spool)?))
+            }
+            None => (email, options, None),
+        };
+        let (reply, result) = bounded_channel(1);
+        self.queue(email, options, spool_id, Some(reply))?;
+        result.recv_async().await.map_err(|_| QueueError::Closed)
+    }
+    /// How many emails are waiting in each channel. Scheduled emails are not counted until they are due
+    pub fn depth(&self) -> QueueDepth {
+        let [critical, normal, bulk] = &self.senders;
+        QueueDepth {
+            critical: critical.len(),
+            normal: normal.len(),
+            bulk: bulk.len(),
+        }
+    }
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot(self.depth())
+    }
+    pub fn health(&self) -> Health {
+        self.health.snapshot(self.depth())
+    }
+    /// Reports the recipients of a merge the backend sent without the queue
+    fn merged(&self, summary: &MergeSummary) {
+        for result in &summary.results {
+            let event = |message_id: Option<&MessageId>, kind| EmailEvent {
+                backend: B::NAME,
+                tracking_id: result.tracking_id.clone(),
+                message_id: message_id.cloned(),
+                recipients: vec![result.to.email.clone()],
+                kind,
+                at: SystemTime::now(),
+            };
+            self.metrics.queued();
+            match &result.outcome {
+                MergeOutcome::Queued(message_id) => {
+                    self.events.emit(event(Some(message_id), EventKind::Queued));
+                    self.metrics.counted(true);
+                    self.health.success();
+                    self.events.emit(event(
+                        Some(message_id),
+                        EventKind::Delivered {
+                            response: message_id.to_string(),
+                        },
+                    ));
+                }
+                MergeOutcome::Failed(error) => {
+                    self.events.emit(event(None, EventKind::Queued));
+                    self.metrics.counted(false);
+                    self.events.emit(event(
+                        None,
+                        EventKind::Failed {
+                            error: error.clone(),
+                        },
+                    ));
+                }
+            }
+        }
+    }
+    /// For backends that count their own retries
+    pub fn metrics_handle(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+    pub fn on_event(&self, handler: Arc<dyn EventHandler>) {
+        self.events.add_handler(handler);
+    }
+    pub fn subscribe(&self) -> EventReceiver {
+        self.events.subscribe()
+    }
+    /// Holds the email until `at` and then pushes it to the queue.
+    pub fn schedule(
+        &self,
+        message: Message,
+        mut options: QueueOptions,
+        at: SystemTime,
+    ) -> Result<ScheduledId, QueueError> {
+        options.message_id = message_id(&message);
+        let email = RawEmail::from(message);
+        let schedule = Schedule {
+            id: ScheduledId(self.scheduler.next.fetch_add(1, Ordering::Relaxed)),
+            at,
+        };
+        let spool_id = match &self.spool {
+            Some(spool) => Some(
+                spool
+                    .store(&email, &options, Some(schedule))
+                    .map_err(QueueError::Spool)?,
+            ),
+            None => None,
+        };
+        let queued = QueuedEmail {
+            email,
+            options,
+            spool_id,
+            reply: None,
+            attempts: 0,
+        };
+        self.events.emit(queued.event(EventKind::Queued));
+        self.hold(schedule, queued);
+        self.metrics.queued();
+        Ok(schedule.id)
+    }
+    fn hold(&self, schedule: Schedule, queued: QueuedEmail<B>) {
+        let sender = self.senders[queued.options.priority as usize].clone();
+        let scheduler = self.scheduler.clone();
+        let spool_id = queued.spool_id.clone();
+        let cancelled = queued.event(EventKind::Dropped {
+            reason: DropReason::Cancelled,
+        });
+        // Locked until the task is registered so it can not finish before it is inserted
+        let mut pending = self.scheduler.pending.lock();
+        let (task, abort) = abortable(async move {
+            if let Ok(wait) = schedule.at.duration_since(SystemTime::now()) {
+                rt::sleep(wait).await;
+            }
+            if scheduler.pending.lock().remove(&schedule.id).is_none() {
+                return;
+            }
+            if sender.send_async(queued).await.is_err() {
+                debug!(
+                    "The queue closed before scheduled email {} was due",
+                    schedule.id
+                );
+            }
+        });
+        rt::spawn(async move {
+            let _ = task.await;
+        });
+        pending.insert(
+            schedule.id,
+            Held {
+                task: abort,
+                spool_id,
+                cancelled,
+            },
+        );
+    }
+    /// Cancels a scheduled email. False if it is already queued or does not exist
+    pub fn cancel(&self, id: ScheduledId) -> bool {
+        let Some(held) = self.scheduler.pending.lock().remove(&id) else {
+            return false;
+        };
+        held.task.abort();
+        if let (Some(spool), Some(spool_id)) = (&self.spool, held.spool_id) {
+            spool.complete(&spool_id);
+        }
+        self.events.emit(EmailEvent {
+            at: SystemTime::now(),
+            ..held.cancelled
+        });
+        true
+    }
+}
+/// The [Access](EmailAccess) of every service that delivers with a worker.
+///
+/// Emails are built with the current settings of the service and pushed to its queue
+pub struct WorkerAccess<S: Backend> {
+    service: Arc<S>,
+    queue: Queue<Arc<S>>,
+    message_builder: MessageBuilder,
+    dkim: Option<Arc<DkimConfig>>,
+    security: Option<Arc<MessageSecurity>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+impl<S: Backend> Clone for WorkerAccess<S> {
+    fn clone(&self) -> Self {
+        Self {
+            service: self.service.clone(),
+            queue: self.queue.clone(),
+            message_builder: self.message_builder.clone(),
+            dkim: self.dkim.clone(),
+            security: self.security.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+        }
+    }
+}
+impl<S: Backend + Debug> Debug for WorkerAccess<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WorkerAccess")
+            .field("service", &self.service)
+            .field("queue", &self.queue)
+            .field("message_builder", &self.message_builder)
+            .field("dkim", &self.dkim)
+            .field("security", &self.security)
+            .field("rate_limiter", &self.rate_limiter)
+            .finish()
+    }
+}
+impl<S: Backend> WorkerAccess<S> {
+    pub(crate) fn new(
+        service: Arc<S>,
+        queue: Queue<Arc<S>>,
+        dkim: Option<Arc<DkimConfig>>,
+        security: Option<Arc<MessageSecurity>>,
+        rate_limiter: Option<Arc<RateLimiter>>,
+    ) -> Self {
+        Self {
+            service,
+            queue,
+            message_builder: MessageBuilder::new(),
+            dkim,
+            security,
+            rate_limiter,
+        }
+    }
+    pub(crate) fn service(&self) -> &S {
+        &self.service
+    }
+}
+impl<S> WorkerAccess<S>
+where
+    S: Backend + MailService,
+    S::Settings: EmailSettingsType,
+    <S as Backend>::Error: From<SMTPError>,
+{
+    fn message(&self, email: impl Email) -> Result<(Message, MessageId), SMTPError> {
+        email_to_message(
+            self.message_builder.clone(),
+            self.service.get_settings().as_ref(),
+            self.dkim.as_deref(),
+            self.security.as_deref(),
+            email,
+        )
+    }
+    /// Pushes an email to the queue and waits for the worker to deliver it.
+    ///
+    /// Returns what the service answered. Such as the status of every recipient for LMTP or which service delivered it for failover
+    pub async fn send_and_wait(
+        &self,
+        email: impl Email,
+    ) -> Result<S::Response, <S as Backend>::Error> {
+        let options = QueueOptions::of(&email);
+        let (message, _) = self.message(email)?;
+        self.queue.send_and_wait(message, options).await?
+    }
+}
+impl<S> EmailAccess for WorkerAccess<S>
+where
+    S: Backend + MailService,
+    S::Settings: EmailSettingsType,
+    <S as Backend>::Error: From<SMTPError> + Sync,
+{
+    type Error = <S as Backend>::Error;
+
+    type Settings = S::Settings;
+    type ConnectionState = S::ConnectionState;
+
+    fn send(&self, email: impl Email) -> Result<MessageId, Self::Error> {
+        let options = QueueOptions::of(&email);
+        let (message, message_id) = self.message(email)?;
+        self.queue.send(message, options)?;
+        Ok(message_id)
+    }
+
+    fn get_settings(&self) -> Arc<Self::Settings> {
+        self.service.get_settings()
+    }
+
+    fn get_state(&self) -> Arc<Mutex<Self::ConnectionState>> {
+        self.service.get_state()
+    }
+
+    fn get_app_state(&self) -> Arc<ServiceState> {
+        self.service.get_app_state()
+    }
+
+    fn send_at(&self, email: impl Email, at: SystemTime) -> Result<Scheduled, Self::Error> {
+        let options = QueueOptions::of(&email);
+        let (message, message_id) = self.message(email)?;
+        Ok(Scheduled {
+            id: self.queue.schedule(message, options, at)?,
+            message_id,
+        })
+    }
+
+    fn cancel_scheduled(&self, id: ScheduledId) -> bool {
+        self.queue.cancel(id)
+    }
+
+    fn health(&self) -> Health {
+        Health {
+            rate_limit: self.rate_limit_status(),
+            pool: self.service.pool_status(),
+            ..self.queue.health()
+        }
+    }
+
+    fn on_event(&self, handler: impl EventHandler + 'static) {
+        self.queue.on_event(Arc::new(handler));
+    }
+
+    fn subscribe(&self) -> EventReceiver {
+        self.queue.subscribe()
+    }
+
+    fn set_public_keys(&self, keys: impl PublicKeyLookup + 'static) {
+        if let Some(security) = &self.security {
+            security.set_public_keys(Arc::new(keys));
+        }
+    }
+
+    fn queue_depth(&self) -> QueueDepth {
+        self.queue.depth()
+    }
+
+    fn metrics(&self) -> Option<MetricsSnapshot> {
+        Some(self.queue.metrics())
+    }
+
+    fn rate_limit_status(&self) -> Option<RateLimitStatus> {
+        self.rate_limiter.as_ref().map(|limiter| limiter.status())
+    }
+
+    async fn send_merge<'a, T: EmailTemplate, D: Serialize>(
+        &self,
+        templates: &impl TemplateSet<'a>,
+        merge: Merge<D>,
+    ) -> MergeSummary {
+        let sent = self.service.send_merge::<T, D>(templates, merge).await;
+        match sent {
+            Ok(summary) => {
+                self.queue.merged(&summary);
+                summary
+            }
+            Err(merge) => merge::send_each::<T, D>(self, templates, merge),
+        }
+    }
+}
+/// Takes emails out of the channels in order of priority
+struct Receivers<B: Backend> {
+    /// One for each [Priority]
+    receivers: [Receiver<QueuedEmail<B>>; 3],
+    /// Emails sent while bulk mail was waiting
+    since_bulk: u32,
+}
+impl<B: Backend> Receivers<B> {
+    fn try_recv(&mut self) -> Option<QueuedEmail<B>> {
+        let order = if self.since_bulk >= BULK_EVERY {
+            [Priority::Critical, Priority::Bulk, Priority::Normal]
+        } else {
+            Priority::ALL
+        };
+        for priority in order {
+            if let Ok(queued) = self.receivers[priority as usize].try_recv() {
+                if priority == Priority::Bulk {
+                    self.since_bulk = 0;
+                } else if !self.receivers[Priority::Bulk as usize].is_empty() {
+                    self.since_bulk += 1;
+                }
+                return Some(queued);
+            }
+        }
+        None
+    }
+    fn depth(&self) -> QueueDepth {
+        let [critical, normal, bulk] = &self.receivers;
+        QueueDepth {
+            critical: critical.len(),
+            normal: normal.len(),
+            bulk: bulk.len(),
+        }
+    }
+    /// None once all the senders are dropped
+    async fn recv(&mut self) -> Option<QueuedEmail<B>> {
+        if let Some(queued) = self.try_recv() {
+            return Some(queued);
+        }
+        let [critical, normal, bulk] = &self.receivers;
+        let result = select_biased! {
+            v = critical.recv_async() => v,
+            v = normal.recv_async() => v,
+            v = bulk.recv_async() => v,
+        };
+        result.ok()
+    }
+}
+/// The receiving side of the queue. Waiting to be started
+pub(crate) struct Worker<B: Backend> {
+    receivers: Receivers<B>,
+    spool: Option<Arc<Spool>>,
+    metrics: Arc<Metrics>,
+    health: Arc<HealthTracker>,
+    events: Arc<Events>,
+    /// Emails left in the spool from the last run
+    recovered: Vec<QueuedEmail<B>>,
+    /// Weak so the queue still closes once every Access is dropped
+    retry_senders: [WeakSender<QueuedEmail<B>>; 3],
+    /// See [RETRY_BACKOFF]
+    backoff: Duration,
+}
+/// Queues emails that failed with a transient error again once their backoff has passed
+struct Retry<B: Backend> {
+    senders: [WeakSender<QueuedEmail<B>>; 3],
+    backoff: Duration,
+    service_state: Arc<ServiceState>,
+    events: Arc<Events>,
+}
+impl<B: Backend> Retry<B> {
+    /// How long to wait before the next attempt
+    fn backoff(&self, attempts: u32) -> Duration {
+        self.backoff * 2u32.saturating_pow(attempts.saturating_sub(1))
+    }
+    fn later(self: &Arc<Self>, queued: QueuedEmail<B>) {
+        let retry = self.clone();
+        let wait = self.backoff(queued.attempts);
+        rt::spawn(async move {
+            let mut notified = pin!(retry.service_state.notified().fuse());
+            if retry.service_state.is_running() {
+                select_biased! {
+                    _ = notified => {}
+                    _ = pin!(rt::sleep(wait).fuse()) => {}
+                }
+            }
+            let sender = retry.senders[queued.options.priority as usize]
+                .upgrade()
+                .filter(|_| retry.service_state.is_running());
+            let Some(sender) = sender else {
+                unsent(&retry.events, queued);
+                return;
+            };
+            if let Err(err) = sender.send_async(queued).await {
+                unsent(&retry.events, err.into_inner());
+            }
+        });
+    }
+}
+/// Creates the queue. 0 for an unbounded queue.
+///
+/// If a spool directory is given the emails left in it are delivered first once the worker starts. Scheduled ones are held until they are due.
+pub(crate) fn queue<B: Backend>(
+    size: usize,
+    spool_directory: Option<&Path>,
+) -> io::Result<(Queue<B>, Worker<B>)> {
+    let channel = || {
+        if size == 0 {
+            unbdounded_channel()
+        } else {
+            bounded_channel(size)
+        }
+    };
+    let [(critical, critical_receiver), (normal, normal_receiver), (bulk, bulk_receiver)] =
+        [channel(), channel(), channel()];
+    let spool = spool_directory
+        .map(Spool::open)
+        .transpose()?
+        .map(|(spool, pending)| (Arc::new(spool), pending));
+    let queue = Queue {
+        senders: [critical, normal, bulk],
+        spool: spool.as_ref().map(|(spool, _)| spool.clone()),
+        scheduler: Arc::default(),
+        metrics: Arc::new(Metrics::new(B::NAME)),
+        health: Arc::default(),
+        events: Arc::default(),
+    };
+    let mut recovered = Vec::new();
+    let spool = spool.map(|(spool, pending)| {
+        if !pending.is_empty() {
+            debug!("Recovered {} emails from the spool", pending.len());
+        }
+        for Spooled {
+            id,
+            email,
+            options,
+            schedule,
+        } in pending
+        {
+            let queued = QueuedEmail {
+                email,
+                options,
+                spool_id: Some(id),
+                reply: None,
+                attempts: 0,
+            };
+            match schedule {
+                Some(schedule) => {
+                    queue
+                        .scheduler
+                        .next
+                        .fetch_max(schedule.id.0 + 1, Ordering::Relaxed);
+                    queue.hold(schedule, queued);
+                }
+                None => recovered.push(queued),
+            }
+        }
+        spool
+    });
+    let [critical, normal, bulk] = &queue.senders;
+    let retry_senders = [critical.downgrade(), normal.downgrade(), bulk.downgrade()];
+    let worker = Worker {
+        retry_senders,
+        backoff: RETRY_BACKOFF,
+        receivers: Receivers {
+            receivers: [critical_receiver, normal_receiver, bulk_receiver],
+            since_bulk: 0,
+        },
+        spool,
+        metrics: queue.metrics.clone(),
+        health: queue.health.clone(),
+        events: queue.events.clone(),
+        recovered,
+    };
+    Ok((queue, worker))
+}
+
+/// Starts the worker for the backend and the shutdown watcher.
+pub(crate) fn start<B: Backend>(
+    backend: B,
+    worker: Worker<B>,
+    service_state: Arc<ServiceState>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+) {
+    ServiceState::watch_for_shutdown(service_state.clone());
+    // Registered before the task starts so a join can not complete early
+    let running = service_state.worker_started();
+    rt::spawn(async move {
+        run(backend, worker, service_state, rate_limiter).await;
+        drop(running);
+    });
+}
+
+async fn run<B: Backend>(
+    backend: B,
+    worker: Worker<B>,
+    service_state: Arc<ServiceState>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+) {
+    use async_lock::Semaphore;
+    let Worker {
+        mut receivers,
+        spool,
+        metrics,
+        health,
+        events,
+        recovered,
+        retry_senders,
+        backoff,
+    } = worker;
+    let retry = Arc::new(Retry {
+        senders: retry_senders,
+        backoff,
+        service_state: service_state.clone(),
+        events: events.clone(),
+    });
+    let _alive = HealthTracker::alive(health.clone());
+    let concurrency = backend.concurrency().max(1);
+    let permits = Arc::new(Semaphore::new(concurrency));
+    let backend = Arc::new(backend);
+    let mut recovered = recovered.into_iter();
+    loop {
+        // Wait for a free slot before taking the next email out of the queue
+        let permit = permits.acquire_arc().await;
+        let queued = if let Some(queued) = recovered.next() {
+            queued
+        } else {
+            let mut receiver = pin!(receivers.recv().fuse());
+            let mut notified = pin!(service_state.notified().fuse());
+            // It might have shut down while waiting for a free slot
+            if !service_state.is_running() {
+                debug!("Notified to shutdown");
+                break;
+            }
+            select_biased! {
+                _ = notified => {
+                    debug!("Notified to shutdown");
+                    break
+                }
+                v = receiver => {
+                    if let Some(value) = v {
+                        value
+                    } else {
+                        debug!("All Senders Dropped. Closing Email Service");
+                        break;
+                    }
+                }
+            }
+        };
+        metrics.record_depth(receivers.depth());
+        // Checked before the rate limiter so an expired email does not use up the limit
+        if queued.options.is_expired() {
+            expire(spool.as_deref(), &metrics, &events, queued);
+            continue;
+        }
+        if let Some(rate_limiter) = rate_limiter.as_ref().filter(|r| !r.try_acquire()) {
+            events.emit(queued.event(EventKind::Deferred));
+            let mut notified = pin!(service_state.notified().fuse());
+            let mut acquired = pin!(rate_limiter.acquire().fuse());
+            if !service_state.is_running() {
+                debug!("Notified to shutdown while waiting on the rate limit");
+                unsent(&events, queued);
+                break;
+            }
+            select_biased! {
+                _ = notified => {
+                    debug!("Notified to shutdown while waiting on the rate limit");
+                    unsent(&events, queued);
+                    break
+                }
+                _ = acquired => {}
+            }
+        }
+        let backend = backend.clone();
+        let spool = spool.clone();
+        let metrics = metrics.clone();
+        let health = health.clone();
+        let events = events.clone();
+        let retry = retry.clone();
+        rt::spawn(async move {
+            deliver(
+                backend.as_ref(),
+                spool.as_deref(),
+                &metrics,
+                &health,
+                &events,
+                &retry,
+                queued,
+            )
+            .await;
+            drop(permit);
+        });
+    }
+    // Waits for the emails that are being sent
+    let mut finished = Vec::with_capacity(concurrency);
+    for _ in 0..concurrency {
+        finished.push(permits.acquire_arc().await);
+    }
+}
+
+/// An email taken out of the queue that the worker stopped before sending.
+///
+/// A spooled email stays in the spool and is sent after a restart. Any other one is dropped and its sender is answered
+fn unsent<B: Backend>(events: &Events, queued: QueuedEmail<B>) {
+    if queued.spool_id.is_some() {
+        debug!("Email stays in the spool until the next start");
+        return;
+    }
+    warn!(
+        "Email to {:?} was not sent before the service shut down",
+        queued.email.envelope.to()
+    );
+    events.emit(queued.event(EventKind::Dropped {
+        reason: DropReason::Shutdown,
+    }));
+    if let Some(reply) = queued.reply {
+        let _ = reply.send(Err(QueueError::Closed.into()));
+    }
+}
+/// Drops an email that expired before it was sent
+fn expire<B: Backend>(
+    spool: Option<&Spool>,
+    metrics: &Metrics,
+    events: &Events,
+    queued: QueuedEmail<B>,
+) {
+    metrics.expired();
+    events.emit(queued.event(EventKind::Dropped {
+        reason: DropReason::Expired,
+    }));
+    warn!(
+        "Email to {:?} expired before it was sent",
+        queued.email.envelope.to()
+    );
+    if let (Some(spool), Some(spool_id)) = (spool, &queued.spool_id) {
+        spool.fail(spool_id);
+    }
+    if let Some(reply) = queued.reply {
+        let _ = reply.send(Err(QueueError::Expired.into()));
+    }
+}
+
+async fn deliver<B: Backend>(
+    backend: &B,
+    spool: Option<&Spool>,
+    metrics: &Metrics,
+    health: &HealthTracker,
+    events: &Events,
+    retry: &Arc<Retry<B>>,
+    mut queued: QueuedEmail<B>,
+) {
+    // It may have expired waiting on the rate limit
+    if queued.options.is_expired() {
+        expire(spool, metrics, events, queued);
+        return;
+    }
+    events.emit(queued.event(EventKind::Sending));
+    let started = Instant::now();
+    let result = backend.deliver(&queued.email).await;
+    metrics.delivered(result.is_ok(), started.elapsed());
+    queued.attempts += 1;
+    match result {
+        Err(err) if B::is_transient(&err) && queued.attempts < MAX_ATTEMPTS => {
+            let wait = retry.backoff(queued.attempts);
+            warn!("Error Sending Email. Trying again in {:?}: {}", wait, err);
+            health.failure(&err);
+            metrics.retried();
+            events.emit(queued.event(EventKind::Retrying {
+                error: err.to_string(),
+                attempt: queued.attempts,
+            }));
+            // A spooled email stays in the spool until it is delivered or given up on
+            retry.later(queued);
+        }
+        Ok(response) => {
+            retry_undelivered(metrics, events, retry, &queued, B::undelivered(&response));
+            finish(spool, health, events, queued, Ok(response))
+        }
+        result => finish(spool, health, events, queued, result),
+    }
+}
+/// Sends again to the recipients a delivery did not reach with a transient error. The others are reported as failed
+fn retry_undelivered<B: Backend>(
+    metrics: &Metrics,
+    events: &Events,
+    retry: &Arc<Retry<B>>,
+    queued: &QueuedEmail<B>,
+    undelivered: Vec<Undelivered>,
+) {
+    let mut transient = Vec::new();
+    for undelivered in undelivered {
+        if undelivered.transient && queued.attempts < MAX_ATTEMPTS {
+            transient.push(undelivered);
+            continue;
+        }
+        warn!(
+            "Email was not delivered to {}: {}",
+            undelivered.recipient, undelivered.error
+        );
+        let mut event = queued.event(EventKind::Failed {
+            error: undelivered.error,
+        });
+        event.recipients = vec![undelivered.recipient];
+        events.emit(event);
+    }
+    let Some(partial) = queued.for_recipients(&transient) else {
+        return;
+    };
+    let error = transient
+        .iter()
+        .map(|undelivered| format!("{}: {}", undelivered.recipient, undelivered.error))
+        .collect::<Vec<_>>()
+        .join(", ");
+    warn!(
+        "Email was not delivered to every recipient. Trying again in {:?}: {}",
+        retry.backoff(partial.attempts),
+        error
+    );
+    metrics.retried();
+    events.emit(partial.event(EventKind::Retrying {
+        error,
+        attempt: partial.attempts,
+    }));
+    retry.later(partial);
+}
+/// Answers an email that was delivered or given up on
+fn finish<B: Backend>(
+    spool: Option<&Spool>,
+    health: &HealthTracker,
+    events: &Events,
+    queued: QueuedEmail<B>,
+    result: Result<B::Response, B::Error>,
+) {
+    match &result {
+        Ok(response) => {
+            health.success();
+            events.emit(queued.event(EventKind::Delivered {
+                response: format!("{response:?}"),
+            }));
+        }
+        Err(err) => {
+            error!("Error Sending Email: {}", err);
+            health.failure(err);
+            events.emit(queued.event(EventKind::Failed {
+                error: err.to_string(),
+            }));
+        }
+    }
+    if let (Some(spool), Some(spool_id)) = (spool, &queued.spool_id) {
+        if result.is_ok() {
+            spool.complete(spool_id);
+        } else {
+            spool.fail(spool_id);
+        }
+    }
+    if let Some(reply) = queued.reply {
+        // The sender may have stopped waiting
+        let _ = reply.send(result);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spool::FAILED_DIRECTORY;
+    struct NoBackend;
+    impl Backend for NoBackend {
+        const NAME: &'static str = "test";
+        type Response = ();
+        type Error = io::Error;
+
+        fn is_transient(_: &io::Error) -> bool {
+            false
+        }
+        async fn deliver(&self, _: &RawEmail) -> Result<(), io::Error> {
+            Ok(())
+        }
+    }
+    impl From<QueueError> for io::Error {
+        fn from(_: QueueError) -> Self {
+            io::Error::other("queue error")
+        }
+    }
+    fn email() -> RawEmail {
+        RawEmail {
+            envelope: Envelope::new(None, vec!["user@example.com".parse().unwrap()]).unwrap(),
+            formatted: Vec::new(),
+        }
+    }
+    #[test]
+    fn test_priority_order() {
+        let (queue, mut worker) = queue::<NoBackend>(0, None).unwrap();
+        let push = |priority| {
+            queue.senders[priority as usize]
+                .send(QueuedEmail {
+                    email: email(),
+                    options: QueueOptions {
+                        priority,
+                        ..Default::default()
+                    },
+                    spool_id: None,
+                    reply: None,
+                    attempts: 0,
+                })
+                .unwrap();
+        };
+        for _ in 0..2 {
+            push(Priority::Bulk);
+        }
+        for _ in 0..10 {
+            push(Priority::Normal);
+        }
+        push(Priority::Critical);
+        assert_eq!(
+            queue.depth(),
+            QueueDepth {
+                critical: 1,
+                normal: 10,
+                bulk: 2
+            }
+        );
+        let order: Vec<_> = std::iter::from_fn(|| worker.receivers.try_recv())
+            .map(|queued| queued.options.priority)
+            .collect();
+        let mut expected = vec![Priority::Critical];
+        expected.extend([Priority::Normal; 7]);
+        // Bulk gets a turn after BULK_EVERY emails
+        expected.push(Priority::Bulk);
+        expected.extend([Priority::Normal; 3]);
+        expected.push(Priority::Bulk);
+        assert_eq!(order, expected);
+    }
+    /// Fails with a transient error until `failures` runs out
+    struct FlakyBackend {
+        failures: AtomicU64,
+        permanent: bool,
+        attempts: AtomicU64,
+    }
+    impl Backend for FlakyBackend {
+        const NAME: &'static str = "test";
+        type Response = ();
+        type Error = io::Error;
+
+        fn is_transient(error: &io::Error) -> bool {
+            error.kind() == io::ErrorKind::TimedOut
+        }
+        async fn deliver(&self, _: &RawEmail) -> Result<(), io::Error> {
+            self.attempts.fetch_add(1, Ordering::Relaxed);
+            if self.permanent {
+                return Err(io::Error::other("rejected"));
+            }
+            let failures = self.failures.load(Ordering::Relaxed);
+            if failures == 0 {
+                return Ok(());
+            }
+            self.failures.store(failures - 1, Ordering::Relaxed);
+            Err(io::ErrorKind::TimedOut.into())
+        }
+    }
+    fn message() -> Message {
+        Message::builder()
+            .from("sender@example.com".parse().unwrap())
+            .to("user@example.com".parse().unwrap())
+            .body(String::new())
+            .unwrap()
+    }
+    async fn send_through(
+        backend: FlakyBackend,
+        spool: &Path,
+    ) -> (Arc<FlakyBackend>, Result<(), io::Error>) {
+        let _ = std::fs::remove_dir_all(spool);
+        let (queue, mut worker) = queue::<Arc<FlakyBackend>>(0, Some(spool)).unwrap();
+        worker.backoff = Duration::from_millis(10);
+        let backend = Arc::new(backend);
+        let state = ServiceState::without_signal_handling();
+        start(backend.clone(), worker, state.clone(), None);
+        let result = queue
+            .send_and_wait(message(), QueueOptions::default())
+            .await
+            .unwrap();
+        state.shutdown();
+        (backend, result)
+    }
+    fn spooled(directory: &Path) -> usize {
+        std::fs::read_dir(directory)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().is_file())
+            .count()
+    }
+    #[tokio::test]
+    async fn test_transient_errors_are_retried() {
+        let directory = std::env::temp_dir().join(format!("any_mail_retry_{}", std::process::id()));
+        let backend = FlakyBackend {
+            failures: AtomicU64::new(2),
+            permanent: false,
+            attempts: AtomicU64::new(0),
+        };
+        let (backend, result) = send_through(backend, &directory).await;
+        assert!(result.is_ok());
+        assert_eq!(backend.attempts.load(Ordering::Relaxed), 3);
+        assert_eq!(spooled(&directory), 0);
+        assert_eq!(spooled(&directory.join(FAILED_DIRECTORY)), 0);
+
+        let backend = FlakyBackend {
+            failures: AtomicU64::new(u64::MAX),
+            permanent: false,
+            attempts: AtomicU64::new(0),
+        };
+        let (backend, result) = send_through(backend, &directory).await;
+        assert!(result.is_err());
+        assert_eq!(
+            backend.attempts.load(Ordering::Relaxed),
+            u64::from(MAX_ATTEMPTS)
+        );
+        assert_eq!(spooled(&directory.join(FAILED_DIRECTORY)), 1);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+    #[tokio::test]
+    async fn test_permanent_errors_are_not_retried() {
+        let directory =
+            std::env::temp_dir().join(format!("any_mail_permanent_{}", std::process::id()));
+        let backend = FlakyBackend {
+            failures: AtomicU64::new(0),
+            permanent: true,
+            attempts: AtomicU64::new(0),
+        };
+        let (backend, result) = send_through(backend, &directory).await;
+        assert!(result.is_err());
+        assert_eq!(backend.attempts.load(Ordering::Relaxed), 1);
+        assert_eq!(spooled(&directory), 0);
+        assert_eq!(spooled(&directory.join(FAILED_DIRECTORY)), 1);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+    /// Does not reach `busy@example.com` the first time
+    #[derive(Default)]
+    struct PartialBackend {
+        deliveries: Mutex<Vec<Vec<String>>>,
+    }
+    impl Backend for PartialBackend {
+        const NAME: &'static str = "test";
+        type Response = Vec<Undelivered>;
+        type Error = io::Error;
+
+        fn is_transient(_: &io::Error) -> bool {
+            false
+        }
+        async fn deliver(&self, email: &RawEmail) -> Result<Vec<Undelivered>, io::Error> {
+            let to: Vec<String> = email
+                .envelope
+                .to()
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+            let mut deliveries = self.deliveries.lock();
+            deliveries.push(to);
+            if deliveries.len() > 1 {
+                return Ok(Vec::new());
+            }
+            Ok(vec![Undelivered {
+                recipient: "busy@example.com".try_into().unwrap(),
+                error: "451 Mailbox busy".to_owned(),
+                transient: true,
+            }])
+        }
+        fn undelivered(response: &Vec<Undelivered>) -> Vec<Undelivered> {
+            response.clone()
+        }
+    }
+    #[tokio::test]
+    async fn test_undelivered_recipients_are_retried() {
+        let (queue, mut worker) = queue::<Arc<PartialBackend>>(0, None).unwrap();
+        worker.backoff = Duration::from_millis(10);
+        let backend = Arc::new(PartialBackend::default());
+        let state = ServiceState::without_signal_handling();
+        start(backend.clone(), worker, state.clone(), None);
+        let message = Message::builder()
+            .from("sender@example.com".parse().unwrap())
+            .to("user@example.com".parse().unwrap())
+            .to("busy@example.com".parse().unwrap())
+            .body(String::new())
+            .unwrap();
+        let result = queue
+            .send_and_wait(message, QueueOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(result.unwrap().len(), 1);
+        for _ in 0..100 {
+            if backend.deliveries.lock().len() > 1 {
+                break;
+            }
+            rt::sleep(Duration::from_millis(10)).await;
+        }
+        state.shutdown();
+        assert_eq!(
+            *backend.deliveries.lock(),
+            [
+                vec!["user@example.com", "busy@example.com"],
+                vec!["busy@example.com"]
+            ]
+        );
+    }
+}
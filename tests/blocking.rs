@@ -0,0 +1,48 @@
+#![cfg(all(feature = "smtp", feature = "tokio"))]
+mod common;
+use any_mail::{
+    blocking::BlockingMailer,
+    no_op::NoOpService,
+    smtp::{SMTPService, SMTPServiceEncryption, SMTPServiceSettings},
+};
+use common::{smtp_stand_in, TestEmail};
+use tokio::net::TcpListener;
+
+#[test]
+fn send_without_a_runtime() -> anyhow::Result<()> {
+    // The stand-in gets its own runtime. The mailer is used from plain threads
+    let stand_in = tokio::runtime::Runtime::new()?;
+    let (port, mut received) = stand_in.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        anyhow::Ok((port, smtp_stand_in(listener)))
+    })?;
+    let settings = SMTPServiceSettings {
+        port: Some(port),
+        encryption: SMTPServiceEncryption::NONE,
+        ..Default::default()
+    };
+    let mailer = BlockingMailer::<SMTPService>::init(settings)?;
+
+    let response = mailer.send_and_wait(TestEmail::new(&["a@example.com"]))?;
+    assert!(response.is_positive());
+    assert_eq!(
+        received.blocking_recv().unwrap().recipients,
+        ["a@example.com"]
+    );
+
+    mailer.send(TestEmail::new(&["b@example.com"]))?;
+    assert_eq!(
+        received.blocking_recv().unwrap().recipients,
+        ["b@example.com"]
+    );
+    mailer.shutdown();
+    Ok(())
+}
+#[test]
+fn no_op_sends_without_a_worker() -> anyhow::Result<()> {
+    let mailer = BlockingMailer::<NoOpService>::init(())?;
+    mailer.send(TestEmail::new(&["a@example.com"]))?;
+    mailer.shutdown();
+    Ok(())
+}
@@ -0,0 +1,79 @@
+#![cfg(feature = "lmtp")]
+mod common;
+use any_mail::{
+    calendar::{Calendar, CalendarEvent},
+    email_types::Mailbox,
+    lmtp::{LMTPServerAddress, LMTPService, LMTPServiceSettings},
+    template::EmailBody,
+    EmailAccess, MailService,
+};
+use chrono::{FixedOffset, TimeZone};
+use common::{lmtp_stand_in, TestEmail};
+use tokio::net::TcpListener;
+fn invitation(calendar: Calendar) -> TestEmail {
+    let mut email = TestEmail::new(&["user@example.com"]);
+    email.body = Some(EmailBody {
+        html_body: Some("<p>You are invited</p>".to_owned()),
+        text_body: Some("You are invited".to_owned()),
+        calendar: Some(calendar),
+    });
+    email
+}
+#[tokio::test]
+async fn invitation_and_cancellation() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let settings = LMTPServiceSettings {
+        server: LMTPServerAddress::TCP {
+            host: "127.0.0.1".to_owned(),
+            port: Some(listener.local_addr()?.port()),
+        },
+        ..Default::default()
+    };
+    let server = tokio::spawn(async move {
+        // The first connection is the test connection from init
+        let (stream, _) = listener.accept().await?;
+        lmtp_stand_in(stream, &[]).await?;
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            let (stream, _) = listener.accept().await?;
+            received.push(lmtp_stand_in(stream, &[]).await?);
+        }
+        anyhow::Ok(received)
+    });
+    let access = LMTPService::init(settings).await?;
+    let offset = FixedOffset::west_opt(5 * 3600).unwrap();
+    let mut event = CalendarEvent {
+        uid: "appointment-12@example.com".to_owned(),
+        sequence: 0,
+        organizer: Mailbox::try_from("clinic@example.com")?,
+        attendees: vec![Mailbox::try_from("user@example.com")?.into()],
+        start: offset.with_ymd_and_hms(2026, 11, 2, 9, 30, 0).unwrap(),
+        end: offset.with_ymd_and_hms(2026, 11, 2, 10, 0, 0).unwrap(),
+        summary: "Appointment".to_owned(),
+        description: None,
+        location: Some("Room 4".to_owned()),
+    };
+    access.send(invitation(Calendar::request(event.clone())))?;
+    event.sequence = 1;
+    access.send(invitation(Calendar::cancel(event)))?;
+
+    let received = server.await??;
+    let request = &received[0].data;
+    assert!(request.contains("Content-Type: multipart/mixed"));
+    assert!(request.contains("Content-Type: multipart/alternative"));
+    assert!(request.contains("Content-Type: text/calendar; method=REQUEST; charset=utf-8"));
+    assert!(request.contains("Content-Disposition: attachment; filename=\"invite.ics\""));
+    assert!(request.contains("METHOD:REQUEST"));
+    assert!(request.contains("DTSTART:20261102T143000Z"));
+    // Sent as is so the folded lines stay intact
+    assert!(request.contains(
+        "Content-Type: text/calendar; method=REQUEST; charset=utf-8\r\nContent-Transfer-Encoding: 7bit"
+    ));
+    assert!(request.contains("ATTENDEE;ROLE=REQ-PARTICIPANT"));
+
+    let cancel = &received[1].data;
+    assert!(cancel.contains("Content-Type: text/calendar; method=CANCEL; charset=utf-8"));
+    assert!(cancel.contains("SEQUENCE:1"));
+    assert!(cancel.contains("STATUS:CANCELLED"));
+    Ok(())
+}
@@ -0,0 +1,282 @@
+#![allow(dead_code)]
+use std::{borrow::Cow, path::PathBuf, sync::Arc, time::SystemTime};
+
+use any_mail::{
+    email_types::{Mailbox, MessageId},
+    template::EmailBody,
+    Email,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufStream},
+    net::TcpListener,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+};
+use tokio_rustls::{
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+/// What the LMTP stand-in received for a message
+#[derive(Debug, Default)]
+pub struct Received {
+    pub recipients: Vec<String>,
+    pub data: String,
+    /// The AUTH command of the connection
+    pub auth: Option<String>,
+}
+/// A tiny LMTP server that accepts everyone except the `rejected` addresses.
+///
+/// Handles a single connection and returns what it received.
+pub async fn lmtp_stand_in(
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    rejected: &[&str],
+) -> anyhow::Result<Received> {
+    lmtp_stand_in_replying(stream, rejected, &[]).await
+}
+/// [lmtp_stand_in] that answers with the reply in `after_data` for those recipients once the data is sent
+pub async fn lmtp_stand_in_replying(
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    rejected: &[&str],
+    after_data: &[(&str, &str)],
+) -> anyhow::Result<Received> {
+    let mut stream = BufStream::new(stream);
+    let mut received = Received::default();
+    stream.write_all(b"220 localhost LMTP ready\r\n").await?;
+    stream.flush().await?;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if stream.read_line(&mut line).await? == 0 {
+            break;
+        }
+        let command = line.trim_end();
+        let reply = if command.starts_with("LHLO") {
+            "250-localhost\r\n250 PIPELINING\r\n".to_owned()
+        } else if command.starts_with("MAIL FROM") || command == "RSET" {
+            "250 2.1.0 OK\r\n".to_owned()
+        } else if let Some(recipient) = command.strip_prefix("RCPT TO:") {
+            let recipient = recipient.trim_matches(|c| c == '<' || c == '>');
+            if rejected.contains(&recipient) {
+                "550 5.1.1 User doesn't exist\r\n".to_owned()
+            } else {
+                received.recipients.push(recipient.to_owned());
+                "250 2.1.5 OK\r\n".to_owned()
+            }
+        } else if command == "DATA" {
+            stream.write_all(b"354 OK\r\n").await?;
+            stream.flush().await?;
+            loop {
+                line.clear();
+                stream.read_line(&mut line).await?;
+                if line == ".\r\n" {
+                    break;
+                }
+                received.data.push_str(&line);
+            }
+            received
+                .recipients
+                .iter()
+                .map(|recipient| {
+                    match after_data.iter().find(|(address, _)| address == recipient) {
+                        Some((_, reply)) => format!("{reply}\r\n"),
+                        None => format!("250 2.0.0 <{}> Saved\r\n", recipient),
+                    }
+                })
+                .collect()
+        } else if command == "QUIT" {
+            stream.write_all(b"221 2.0.0 Bye\r\n").await?;
+            stream.flush().await?;
+            break;
+        } else {
+            "500 5.5.1 Unknown command\r\n".to_owned()
+        };
+        stream.write_all(reply.as_bytes()).await?;
+        stream.flush().await?;
+    }
+    Ok(received)
+}
+#[derive(Debug)]
+pub struct TestEmail {
+    to: Vec<Mailbox>,
+    pub body: Option<EmailBody>,
+    pub expires_at: Option<SystemTime>,
+    pub tracking_id: Option<String>,
+    pub message_id: Option<MessageId>,
+    pub in_reply_to: Option<MessageId>,
+    pub references: Vec<MessageId>,
+}
+impl TestEmail {
+    pub fn new(to: &[&str]) -> Self {
+        Self {
+            to: to.iter().map(|v| Mailbox::try_from(*v).unwrap()).collect(),
+            body: Some(EmailBody {
+                html_body: None,
+                text_body: Some("Hello\r\n.Starts with a dot".to_owned()),
+                calendar: None,
+            }),
+            expires_at: None,
+            tracking_id: None,
+            message_id: None,
+            in_reply_to: None,
+            references: Vec::new(),
+        }
+    }
+}
+impl Email for TestEmail {
+    fn subject(&self) -> Cow<'static, str> {
+        Cow::Borrowed("LMTP Test")
+    }
+
+    fn body(&mut self) -> Option<EmailBody> {
+        self.body.take()
+    }
+
+    fn to(&self) -> impl ExactSizeIterator<Item = &Mailbox> + '_ {
+        self.to.iter()
+    }
+
+    fn from(&self) -> Option<&Mailbox> {
+        None
+    }
+
+    fn expires_at(&self) -> Option<SystemTime> {
+        self.expires_at
+    }
+
+    fn tracking_id(&self) -> Option<String> {
+        self.tracking_id.clone()
+    }
+
+    fn message_id(&self) -> Option<MessageId> {
+        self.message_id.clone()
+    }
+
+    fn in_reply_to(&self) -> Option<MessageId> {
+        self.in_reply_to.clone()
+    }
+
+    fn references(&self) -> Vec<MessageId> {
+        self.references.clone()
+    }
+}
+/// A tiny SMTP server that accepts every message.
+///
+/// Every message it receives is sent to the returned receiver.
+pub fn smtp_stand_in(listener: TcpListener) -> UnboundedReceiver<Received> {
+    let (sender, receiver) = unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                if let Err(err) = smtp_connection(stream, sender).await {
+                    eprintln!("SMTP stand-in error: {}", err);
+                }
+            });
+        }
+    });
+    receiver
+}
+/// The certificates in `tests/tls`. Signed by `ca.pem` for `relay.internal`
+pub fn tls_file(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("tls")
+        .join(name)
+}
+/// [smtp_stand_in] behind implicit TLS. Clients must present a certificate signed by `ca.pem` if `client_auth` is true
+pub fn smtp_tls_stand_in(listener: TcpListener, client_auth: bool) -> UnboundedReceiver<Received> {
+    let certificates = CertificateDer::pem_file_iter(tls_file("server.pem"))
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let key = PrivateKeyDer::from_pem_file(tls_file("server.key")).unwrap();
+    let builder = ServerConfig::builder();
+    let config = if client_auth {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(tls_file("ca.pem")).unwrap())
+            .unwrap();
+        let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+            .build()
+            .unwrap();
+        builder.with_client_cert_verifier(verifier)
+    } else {
+        builder.with_no_client_auth()
+    }
+    .with_single_cert(certificates, key)
+    .unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let (sender, receiver) = unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let sender = sender.clone();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        eprintln!("SMTP stand-in TLS error: {}", err);
+                        return;
+                    }
+                };
+                if let Err(err) = smtp_connection(stream, sender).await {
+                    eprintln!("SMTP stand-in error: {}", err);
+                }
+            });
+        }
+    });
+    receiver
+}
+async fn smtp_connection(
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    sender: UnboundedSender<Received>,
+) -> anyhow::Result<()> {
+    let mut stream = BufStream::new(stream);
+    let mut received = Received::default();
+    stream.write_all(b"220 localhost ESMTP ready\r\n").await?;
+    stream.flush().await?;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if stream.read_line(&mut line).await? == 0 {
+            break;
+        }
+        let command = line.trim_end();
+        let reply = if command.starts_with("EHLO") || command.starts_with("HELO") {
+            "250-localhost\r\n250-AUTH PLAIN LOGIN XOAUTH2\r\n250 8BITMIME\r\n"
+        } else if command.starts_with("AUTH") {
+            received.auth = Some(command.to_owned());
+            "235 2.7.0 Authentication successful\r\n"
+        } else if let Some(recipient) = command.strip_prefix("RCPT TO:") {
+            let recipient = recipient.trim_matches(|c| c == '<' || c == '>');
+            received.recipients.push(recipient.to_owned());
+            "250 2.1.5 OK\r\n"
+        } else if command == "DATA" {
+            stream.write_all(b"354 OK\r\n").await?;
+            stream.flush().await?;
+            loop {
+                line.clear();
+                stream.read_line(&mut line).await?;
+                if line == ".\r\n" {
+                    break;
+                }
+                received.data.push_str(&line);
+            }
+            let _ = sender.send(std::mem::take(&mut received));
+            "250 2.0.0 Queued\r\n"
+        } else if command == "QUIT" {
+            stream.write_all(b"221 2.0.0 Bye\r\n").await?;
+            stream.flush().await?;
+            break;
+        } else {
+            // MAIL, RSET and NOOP
+            "250 2.0.0 OK\r\n"
+        };
+        stream.write_all(reply.as_bytes()).await?;
+        stream.flush().await?;
+    }
+    Ok(())
+}
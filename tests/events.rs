@@ -0,0 +1,69 @@
+#![cfg(feature = "lmtp")]
+mod common;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use any_mail::{
+    events::{DropReason, EmailEvent, EventKind},
+    lmtp::{LMTPServerAddress, LMTPService, LMTPServiceSettings},
+    EmailAccess, MailService,
+};
+use common::{lmtp_stand_in, TestEmail};
+use tokio::net::TcpListener;
+#[tokio::test]
+async fn lifecycle_events() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let settings = LMTPServiceSettings {
+        server: LMTPServerAddress::TCP {
+            host: "127.0.0.1".to_owned(),
+            port: Some(listener.local_addr()?.port()),
+        },
+        ..Default::default()
+    };
+    let server = tokio::spawn(async move {
+        // The first connection is the test connection from init
+        let (stream, _) = listener.accept().await?;
+        lmtp_stand_in(stream, &[]).await?;
+        let (stream, _) = listener.accept().await?;
+        lmtp_stand_in(stream, &[]).await
+    });
+    let access = LMTPService::init(settings).await?;
+    let handled = Arc::new(Mutex::new(Vec::new()));
+    let handler_events = handled.clone();
+    access.on_event(move |event: &EmailEvent| {
+        handler_events.lock().unwrap().push(event.kind.clone());
+    });
+    let events = access.subscribe();
+
+    let mut email = TestEmail::new(&["user@example.com"]);
+    email.tracking_id = Some("order-42".to_owned());
+    access.send_and_wait(email).await?;
+    let mut cancelled = TestEmail::new(&["cancelled@example.com"]);
+    cancelled.tracking_id = Some("order-43".to_owned());
+    let id = access.send_after(cancelled, Duration::from_secs(60))?.id;
+    assert!(access.cancel_scheduled(id));
+    server.await??;
+
+    let received: Vec<_> = events.drain().collect();
+    let kinds: Vec<_> = received.iter().map(|event| &event.kind).collect();
+    assert_eq!(kinds[0], &EventKind::Queued);
+    assert_eq!(kinds[1], &EventKind::Sending);
+    assert!(matches!(kinds[2], EventKind::Delivered { .. }));
+    assert_eq!(kinds[3], &EventKind::Queued);
+    assert_eq!(
+        kinds[4],
+        &EventKind::Dropped {
+            reason: DropReason::Cancelled
+        }
+    );
+    assert_eq!(received[0].tracking_id.as_deref(), Some("order-42"));
+    assert_eq!(received[0].backend, "lmtp");
+    assert_eq!(received[0].recipients[0].to_string(), "user@example.com");
+    assert_eq!(received[4].tracking_id.as_deref(), Some("order-43"));
+
+    let handled = handled.lock().unwrap();
+    assert_eq!(handled.len(), received.len());
+    Ok(())
+}
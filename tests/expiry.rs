@@ -0,0 +1,62 @@
+#![cfg(feature = "lmtp")]
+mod common;
+use std::time::{Duration, SystemTime};
+
+use any_mail::{
+    lmtp::{LMTPError, LMTPServerAddress, LMTPService, LMTPServiceSettings},
+    rate_limit::RateLimitSettings,
+    EmailAccess, MailService, QueueError,
+};
+use common::{lmtp_stand_in, TestEmail};
+use tokio::net::TcpListener;
+#[tokio::test]
+async fn expired_emails_are_not_sent() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let settings = LMTPServiceSettings {
+        server: LMTPServerAddress::TCP {
+            host: "127.0.0.1".to_owned(),
+            port: Some(listener.local_addr()?.port()),
+        },
+        rate_limit: RateLimitSettings {
+            max_emails: 1,
+            period: 1000,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let server = tokio::spawn(async move {
+        // The first connection is the test connection from init
+        let (stream, _) = listener.accept().await?;
+        lmtp_stand_in(stream, &[]).await?;
+        let (stream, _) = listener.accept().await?;
+        let received = lmtp_stand_in(stream, &[]).await?;
+        // The expired emails never arrive
+        let extra = tokio::time::timeout(Duration::from_millis(500), listener.accept()).await;
+        anyhow::Ok((received, extra.is_err()))
+    });
+    let access = LMTPService::init(settings).await?;
+
+    let mut already_expired = TestEmail::new(&["expired@example.com"]);
+    already_expired.expires_at = Some(SystemTime::now() - Duration::from_secs(1));
+    let result = access.send_and_wait(already_expired).await;
+    assert!(matches!(
+        result,
+        Err(LMTPError::QueueError(QueueError::Expired))
+    ));
+
+    // Uses up the rate limit
+    access.send(TestEmail::new(&["user@example.com"]))?;
+    // Expires while waiting on the rate limit
+    let mut expires_soon = TestEmail::new(&["soon@example.com"]);
+    expires_soon.expires_at = Some(SystemTime::now() + Duration::from_millis(200));
+    let result = access.send_and_wait(expires_soon).await;
+    assert!(matches!(
+        result,
+        Err(LMTPError::QueueError(QueueError::Expired))
+    ));
+
+    let (received, nothing_else) = server.await??;
+    assert_eq!(received.recipients, vec!["user@example.com"]);
+    assert!(nothing_else);
+    Ok(())
+}
@@ -0,0 +1,105 @@
+#![cfg(feature = "lmtp")]
+mod common;
+use any_mail::{
+    failover::{BackendResponse, FailoverError, FailoverService, FailoverSettings},
+    health::HealthStatus,
+    lmtp::{LMTPServerAddress, LMTPServiceSettings},
+    smtp::{SMTPServiceEncryption, SMTPServiceSettings},
+    EmailAccess, MailService, MailServiceSettings, MailServiceTypes,
+};
+use common::{lmtp_stand_in, TestEmail};
+use tokio::net::TcpListener;
+
+async fn lmtp_settings(listener: &TcpListener) -> anyhow::Result<MailServiceSettings> {
+    Ok(MailServiceSettings::LMTP(Box::new(LMTPServiceSettings {
+        server: LMTPServerAddress::TCP {
+            host: "127.0.0.1".to_owned(),
+            port: Some(listener.local_addr()?.port()),
+        },
+        ..Default::default()
+    })))
+}
+#[tokio::test]
+async fn fails_over_to_next_service() -> anyhow::Result<()> {
+    // Nothing is listening on this port
+    let closed_port = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let settings = FailoverSettings {
+        services: vec![
+            MailServiceSettings::SMTP(Box::new(SMTPServiceSettings {
+                port: Some(closed_port),
+                encryption: SMTPServiceEncryption::NONE,
+                ..Default::default()
+            })),
+            lmtp_settings(&listener).await?,
+        ],
+        ..Default::default()
+    };
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        lmtp_stand_in(stream, &[]).await
+    });
+    let access = FailoverService::init(settings).await?;
+
+    let delivery = access
+        .send_and_wait(TestEmail::new(&["user@example.com"]))
+        .await?;
+    assert_eq!(delivery.index, 1);
+    assert_eq!(delivery.service, MailServiceTypes::LMTP);
+    assert!(matches!(delivery.response, BackendResponse::LMTP(_)));
+
+    let state = access.get_state().lock().clone();
+    assert_eq!(state.last_delivered_by, Some(1));
+    assert_eq!(state.delivered, vec![0, 1]);
+    assert_eq!(state.failures, vec![1, 0]);
+
+    let metrics = access.metrics().unwrap();
+    assert_eq!(metrics.backend, "failover");
+    assert_eq!(metrics.queued, 1);
+    assert_eq!(metrics.sent, 1);
+    assert_eq!(metrics.failed_over, 1);
+    assert_eq!(metrics.retried, 0);
+    assert_eq!(metrics.latency.count, 1);
+    assert!(metrics
+        .to_prometheus()
+        .contains("any_mail_failed_over_total{backend=\"failover\"} 1\n"));
+
+    let received = server.await??;
+    assert_eq!(received.recipients, vec!["user@example.com"]);
+    Ok(())
+}
+#[tokio::test]
+async fn permanent_failure_is_not_retried() -> anyhow::Result<()> {
+    let primary = TcpListener::bind("127.0.0.1:0").await?;
+    let secondary = TcpListener::bind("127.0.0.1:0").await?;
+    let settings = FailoverSettings {
+        services: vec![
+            lmtp_settings(&primary).await?,
+            lmtp_settings(&secondary).await?,
+        ],
+        ..Default::default()
+    };
+    tokio::spawn(async move {
+        let (stream, _) = primary.accept().await?;
+        lmtp_stand_in(stream, &["unknown@example.com"]).await
+    });
+    let access = FailoverService::init(settings).await?;
+
+    let result = access
+        .send_and_wait(TestEmail::new(&["unknown@example.com"]))
+        .await;
+    assert!(matches!(
+        result,
+        Err(FailoverError::PermanentFailure { index: 0, .. })
+    ));
+    let state = access.get_state().lock().clone();
+    assert_eq!(state.last_delivered_by, None);
+    assert_eq!(state.failures, vec![1, 0]);
+
+    let health = access.health();
+    assert!(health.worker_alive);
+    assert_eq!(health.consecutive_failures, 1);
+    assert!(health.last_error.is_some());
+    assert_eq!(health.status(), HealthStatus::Degraded);
+    Ok(())
+}
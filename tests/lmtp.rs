@@ -0,0 +1,148 @@
+#![cfg(feature = "lmtp")]
+mod common;
+use any_mail::{
+    events::EventKind,
+    lmtp::{LMTPError, LMTPServerAddress, LMTPService, LMTPServiceSettings},
+    EmailAccess, MailService,
+};
+use common::{lmtp_stand_in, lmtp_stand_in_replying, TestEmail};
+use tokio::net::TcpListener;
+async fn settings(listener: &TcpListener) -> anyhow::Result<LMTPServiceSettings> {
+    Ok(LMTPServiceSettings {
+        server: LMTPServerAddress::TCP {
+            host: "127.0.0.1".to_owned(),
+            port: Some(listener.local_addr()?.port()),
+        },
+        ..Default::default()
+    })
+}
+#[tokio::test]
+async fn per_recipient_status() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let settings = LMTPServiceSettings {
+        server: LMTPServerAddress::TCP {
+            host: "127.0.0.1".to_owned(),
+            port: Some(listener.local_addr()?.port()),
+        },
+        ..Default::default()
+    };
+    let server = tokio::spawn(async move {
+        // The first connection is the test connection from init
+        let (stream, _) = listener.accept().await?;
+        lmtp_stand_in(stream, &[]).await?;
+        let (stream, _) = listener.accept().await?;
+        lmtp_stand_in(stream, &["unknown@example.com"]).await
+    });
+    let access = LMTPService::init(settings).await?;
+    assert!(access.get_state().lock().connected);
+
+    let statuses = access
+        .send_and_wait(TestEmail::new(&["user@example.com", "unknown@example.com"]))
+        .await?;
+    assert_eq!(statuses.len(), 2);
+    let rejected = statuses
+        .iter()
+        .find(|status| status.recipient.as_ref() == "unknown@example.com")
+        .unwrap();
+    assert!(!rejected.is_delivered());
+    assert!(!rejected.is_temporary_failure());
+    let delivered = statuses
+        .iter()
+        .find(|status| status.recipient.as_ref() == "user@example.com")
+        .unwrap();
+    assert!(delivered.is_delivered());
+
+    let received = server.await??;
+    assert_eq!(received.recipients, vec!["user@example.com"]);
+    assert!(received.data.contains("Subject: LMTP Test"));
+    assert!(received.data.contains("\r\n..Starts with a dot"));
+    Ok(())
+}
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_socket() -> anyhow::Result<()> {
+    use tokio::net::UnixListener;
+    let path = std::env::temp_dir().join(format!("any_mail_lmtp_{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    let settings = LMTPServiceSettings {
+        server: LMTPServerAddress::Unix(path.clone()),
+        ..Default::default()
+    };
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        lmtp_stand_in(stream, &[]).await?;
+        let (stream, _) = listener.accept().await?;
+        lmtp_stand_in(stream, &[]).await
+    });
+    let access = LMTPService::init(settings).await?;
+    access.send(TestEmail::new(&["user@example.com"]))?;
+
+    let received = server.await??;
+    assert_eq!(received.recipients, vec!["user@example.com"]);
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+#[tokio::test]
+async fn temporary_failure_after_data_is_retried() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let settings = settings(&listener).await?;
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        lmtp_stand_in(stream, &[]).await?;
+        let (stream, _) = listener.accept().await?;
+        lmtp_stand_in_replying(
+            stream,
+            &[],
+            &[("busy@example.com", "451 4.2.0 Mailbox busy")],
+        )
+        .await
+    });
+    let access = LMTPService::init(settings).await?;
+    let events = access.subscribe();
+
+    let statuses = access
+        .send_and_wait(TestEmail::new(&["user@example.com", "busy@example.com"]))
+        .await?;
+    let busy = statuses
+        .iter()
+        .find(|status| status.recipient.as_ref() == "busy@example.com")
+        .unwrap();
+    assert!(busy.is_temporary_failure());
+    let retrying = events
+        .drain()
+        .find(|event| matches!(event.kind, EventKind::Retrying { .. }))
+        .unwrap();
+    assert_eq!(retrying.recipients.len(), 1);
+    assert_eq!(retrying.recipients[0].as_ref(), "busy@example.com");
+    server.await??;
+    Ok(())
+}
+#[tokio::test]
+async fn no_recipient_delivered_after_data_fails() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let settings = settings(&listener).await?;
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        lmtp_stand_in(stream, &[]).await?;
+        let (stream, _) = listener.accept().await?;
+        lmtp_stand_in_replying(
+            stream,
+            &[],
+            &[("full@example.com", "552 5.2.2 Mailbox full")],
+        )
+        .await
+    });
+    let access = LMTPService::init(settings).await?;
+
+    let result = access
+        .send_and_wait(TestEmail::new(&["full@example.com"]))
+        .await;
+    let Err(LMTPError::AllRecipientsRejected(statuses)) = result else {
+        panic!("Expected every recipient to be rejected: {result:?}");
+    };
+    assert!(!statuses[0].is_delivered());
+    assert!(!statuses[0].is_temporary_failure());
+    server.await??;
+    Ok(())
+}
@@ -0,0 +1,105 @@
+#![cfg(feature = "smtp")]
+mod common;
+use any_mail::{
+    load_balancer::{
+        BalanceStrategy, LoadBalancerError, LoadBalancerService, LoadBalancerSettings,
+    },
+    smtp::{SMTPAuthentication, SMTPServiceEncryption, SMTPServiceSettings},
+    EmailAccess, MailService,
+};
+use common::{smtp_stand_in, TestEmail};
+use tokio::net::TcpListener;
+
+fn relay(port: u16) -> SMTPServiceSettings {
+    SMTPServiceSettings {
+        port: Some(port),
+        encryption: SMTPServiceEncryption::NONE,
+        ..Default::default()
+    }
+}
+#[tokio::test]
+async fn round_robin() -> anyhow::Result<()> {
+    let first = TcpListener::bind("127.0.0.1:0").await?;
+    let second = TcpListener::bind("127.0.0.1:0").await?;
+    let settings = LoadBalancerSettings {
+        relays: vec![
+            relay(first.local_addr()?.port()).into(),
+            relay(second.local_addr()?.port()).into(),
+        ],
+        strategy: BalanceStrategy::RoundRobin,
+        ..Default::default()
+    };
+    let mut first = smtp_stand_in(first);
+    let mut second = smtp_stand_in(second);
+    let access = LoadBalancerService::init(settings).await?;
+    assert!(access.get_state().lock().is_connected());
+
+    let delivery = access
+        .send_and_wait(TestEmail::new(&["one@example.com"]))
+        .await?;
+    assert_eq!(delivery.index, 0);
+    let delivery = access
+        .send_and_wait(TestEmail::new(&["two@example.com"]))
+        .await?;
+    assert_eq!(delivery.index, 1);
+
+    assert_eq!(
+        first.recv().await.unwrap().recipients,
+        vec!["one@example.com"]
+    );
+    assert_eq!(
+        second.recv().await.unwrap().recipients,
+        vec!["two@example.com"]
+    );
+    Ok(())
+}
+#[tokio::test]
+async fn unhealthy_relay_is_skipped() -> anyhow::Result<()> {
+    // Nothing is listening on this port
+    let closed_port = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let settings = LoadBalancerSettings {
+        relays: vec![
+            relay(closed_port).into(),
+            relay(listener.local_addr()?.port()).into(),
+        ],
+        ..Default::default()
+    };
+    let mut received = smtp_stand_in(listener);
+    let access = LoadBalancerService::init(settings).await?;
+    let state = access.get_state().lock().clone();
+    assert!(!state.relays[0].healthy);
+    assert!(state.relays[1].healthy);
+
+    for _ in 0..2 {
+        let delivery = access
+            .send_and_wait(TestEmail::new(&["user@example.com"]))
+            .await?;
+        assert_eq!(delivery.index, 1);
+        received.recv().await.unwrap();
+    }
+    assert_eq!(access.get_state().lock().relays[1].delivered, 2);
+    Ok(())
+}
+#[tokio::test]
+async fn xoauth2_relays_are_rejected() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let settings = LoadBalancerSettings {
+        relays: vec![
+            relay(port).into(),
+            SMTPServiceSettings {
+                authentication: SMTPAuthentication::XOAuth2,
+                ..relay(port)
+            }
+            .into(),
+        ],
+        ..Default::default()
+    };
+    let result = LoadBalancerService::init(settings).await;
+    assert!(matches!(
+        result,
+        Err(LoadBalancerError::UnsupportedAuthentication { index: 1 })
+    ));
+    Ok(())
+}
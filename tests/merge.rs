@@ -0,0 +1,196 @@
+#![cfg(feature = "mail-gun")]
+mod common;
+use std::convert::Infallible;
+
+use any_mail::{
+    events::EventKind,
+    mail_gun::{MailGunService, MailGunSettings},
+    merge::{Merge, MergeOutcome},
+    rate_limit::RateLimitSettings,
+    smtp::{SMTPService, SMTPServiceEncryption, SMTPServiceSettings},
+    template::{EmailBody, EmailTemplate, TemplateSet},
+    EmailAccess, MailService,
+};
+use common::smtp_stand_in;
+use serde::Serialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver},
+};
+
+struct Welcome;
+impl EmailTemplate for Welcome {
+    fn template_txt() -> &'static str {
+        "Hello {name}"
+    }
+    fn template_html() -> &'static str {
+        "<p>Hello {name}</p>"
+    }
+}
+/// Replaces `{key}` with the value of the key
+struct Replace;
+impl TemplateSet<'_> for Replace {
+    type Error = Infallible;
+
+    fn build_email<T: EmailTemplate>(
+        &self,
+        data: &impl Serialize,
+    ) -> Result<EmailBody, Self::Error> {
+        let data = serde_json::to_value(data).unwrap();
+        let render = |template: &str| {
+            let mut rendered = template.to_owned();
+            for (key, value) in data.as_object().unwrap() {
+                rendered = rendered.replace(&format!("{{{key}}}"), value.as_str().unwrap());
+            }
+            rendered
+        };
+        Ok(EmailBody {
+            html_body: Some(render(T::template_html())),
+            text_body: Some(render(T::template_txt())),
+            calendar: None,
+        })
+    }
+}
+#[derive(Serialize)]
+struct Data {
+    name: &'static str,
+}
+fn merge() -> Merge<Data> {
+    Merge::new("Welcome")
+        .recipient("a@example.com".try_into().unwrap(), Data { name: "Alice" })
+        .recipient("b@example.com".try_into().unwrap(), Data { name: "Bob" })
+}
+#[tokio::test]
+async fn queues_every_email() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let mut received = smtp_stand_in(listener);
+    let settings = SMTPServiceSettings {
+        port: Some(port),
+        encryption: SMTPServiceEncryption::NONE,
+        ..Default::default()
+    };
+    let access = SMTPService::init(settings).await?;
+
+    let summary = access.send_merge::<Welcome, _>(&Replace, merge()).await;
+    assert_eq!(summary.queued(), 2);
+    let mut delivered = Vec::new();
+    for _ in 0..2 {
+        let received = received.recv().await.unwrap();
+        assert!(received.data.contains("Hello Alice") ^ received.data.contains("Hello Bob"));
+        delivered.extend(received.recipients);
+    }
+    delivered.sort();
+    assert_eq!(delivered, ["a@example.com", "b@example.com"]);
+    Ok(())
+}
+/// A tiny HTTP server that answers every request like Mailgun. Returns the path and body of every request
+fn mailgun_stand_in(listener: TcpListener) -> UnboundedReceiver<(String, String)> {
+    let (sender, receiver) = unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                loop {
+                    let mut request = String::new();
+                    if stream.read_line(&mut request).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    let path = request.split(' ').nth(1).unwrap_or_default().to_owned();
+                    let mut length = 0;
+                    loop {
+                        let mut header = String::new();
+                        stream.read_line(&mut header).await.unwrap();
+                        if header.trim().is_empty() {
+                            break;
+                        }
+                        if let Some((name, value)) = header.split_once(':') {
+                            if name.eq_ignore_ascii_case("content-length") {
+                                length = value.trim().parse().unwrap();
+                            }
+                        }
+                    }
+                    let mut body = vec![0; length];
+                    stream.read_exact(&mut body).await.unwrap();
+                    let _ = sender.send((path, String::from_utf8_lossy(&body).into_owned()));
+                    let response = r#"{"id":"<batch@example.com>","message":"Queued. Thank you."}"#;
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{response}",
+                        response.len()
+                    );
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                }
+            });
+        }
+    });
+    receiver
+}
+#[tokio::test]
+async fn mailgun_sends_batches() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let mut requests = mailgun_stand_in(listener);
+    let settings = MailGunSettings {
+        api_url: format!("http://127.0.0.1:{port}/v3"),
+        batch_size: 2,
+        rate_limit: RateLimitSettings {
+            daily_limit: 10,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let access = MailGunService::init(settings).await?;
+    // The test connection
+    assert_eq!(requests.recv().await.unwrap().0, "/v3/domains/example.com");
+    let events = access.subscribe();
+
+    let summary = access.send_merge::<Welcome, _>(&Replace, merge()).await;
+    assert_eq!(summary.queued(), 2);
+    // Every recipient counts. Not every batch
+    assert_eq!(access.rate_limit_status().unwrap().sent_last_day, 2);
+    let metrics = access.metrics().unwrap();
+    assert_eq!((metrics.queued, metrics.sent), (2, 2));
+    let delivered: Vec<_> = events
+        .drain()
+        .filter(|event| matches!(event.kind, EventKind::Delivered { .. }))
+        .flat_map(|event| event.recipients)
+        .map(|address| address.to_string())
+        .collect();
+    assert_eq!(delivered, ["a@example.com", "b@example.com"]);
+    assert!(summary
+        .results
+        .iter()
+        .all(|result| result.outcome == MergeOutcome::Queued("batch@example.com".into())));
+    let (path, body) = requests.recv().await.unwrap();
+    assert_eq!(path, "/v3/example.com/messages");
+    assert!(body.contains("Hello %recipient.name%"));
+    for name in ["Alice", "Bob"] {
+        assert!(body.contains(&format!(r#""name":"{name}""#)));
+    }
+    Ok(())
+}
+#[tokio::test]
+async fn mailgun_fails_duplicate_recipients() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let mut requests = mailgun_stand_in(listener);
+    let settings = MailGunSettings {
+        api_url: format!("http://127.0.0.1:{port}/v3"),
+        ..Default::default()
+    };
+    let access = MailGunService::init(settings).await?;
+    requests.recv().await.unwrap();
+
+    let merge = merge().recipient("A@example.com".try_into().unwrap(), Data { name: "Again" });
+    let summary = access.send_merge::<Welcome, _>(&Replace, merge).await;
+    assert_eq!(summary.queued(), 2);
+    assert!(matches!(
+        summary.results[2].outcome,
+        MergeOutcome::Failed(ref error) if error.contains("duplicate")
+    ));
+    let (_, body) = requests.recv().await.unwrap();
+    assert!(!body.contains("Again"));
+    Ok(())
+}
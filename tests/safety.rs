@@ -0,0 +1,66 @@
+#![cfg(feature = "smtp")]
+mod common;
+use any_mail::{
+    safety::SafetySettings,
+    smtp::{SMTPError, SMTPService, SMTPServiceEncryption, SMTPServiceSettings},
+    EmailAccess, MailService,
+};
+use common::{smtp_stand_in, TestEmail};
+use tokio::net::TcpListener;
+
+fn settings(port: u16, safety: SafetySettings) -> SMTPServiceSettings {
+    SMTPServiceSettings {
+        port: Some(port),
+        encryption: SMTPServiceEncryption::NONE,
+        safety: Some(safety),
+        ..Default::default()
+    }
+}
+#[tokio::test]
+async fn redirects_every_recipient() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let mut received = smtp_stand_in(listener);
+    let safety = SafetySettings::Redirect {
+        to: "qa@example.com".try_into()?,
+    };
+    let access = SMTPService::init(settings(port, safety)).await?;
+
+    access.send(TestEmail::new(&["a@customer.com", "b@customer.com"]))?;
+    let received = received.recv().await.unwrap();
+    assert_eq!(received.recipients, ["qa@example.com"]);
+    assert!(received
+        .data
+        .contains("X-Any-Mail-Original-To: a@customer.com, b@customer.com"));
+    assert!(received
+        .data
+        .contains("Subject: [To: a@customer.com, b@customer.com] LMTP Test"));
+    assert!(!received.data.contains("To: a@customer.com\r\n"));
+    Ok(())
+}
+#[tokio::test]
+async fn drops_recipients_outside_the_allowlist() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let mut received = smtp_stand_in(listener);
+    let safety = SafetySettings::Allowlist {
+        allowed: vec!["example.com".to_owned(), "someone@partner.com".to_owned()],
+    };
+    let access = SMTPService::init(settings(port, safety)).await?;
+
+    let result = access.send(TestEmail::new(&["a@customer.com"]));
+    assert!(matches!(result, Err(SMTPError::NoAllowedRecipients)));
+
+    access.send(TestEmail::new(&[
+        "a@customer.com",
+        "a@example.com",
+        "someone@partner.com",
+    ]))?;
+    let received = received.recv().await.unwrap();
+    assert_eq!(
+        received.recipients,
+        ["a@example.com", "someone@partner.com"]
+    );
+    assert!(!received.data.contains("customer.com"));
+    Ok(())
+}
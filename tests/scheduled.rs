@@ -0,0 +1,58 @@
+#![cfg(feature = "lmtp")]
+mod common;
+use std::time::Duration;
+
+use any_mail::{
+    lmtp::{LMTPServerAddress, LMTPService, LMTPServiceSettings},
+    EmailAccess, MailService,
+};
+use common::{lmtp_stand_in, TestEmail};
+use tokio::net::TcpListener;
+#[tokio::test]
+async fn delayed_and_cancelled() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let settings = LMTPServiceSettings {
+        server: LMTPServerAddress::TCP {
+            host: "127.0.0.1".to_owned(),
+            port: Some(listener.local_addr()?.port()),
+        },
+        ..Default::default()
+    };
+    let server = tokio::spawn(async move {
+        // The first connection is the test connection from init
+        let (stream, _) = listener.accept().await?;
+        lmtp_stand_in(stream, &[]).await?;
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            let (stream, _) = listener.accept().await?;
+            received.push(lmtp_stand_in(stream, &[]).await?);
+        }
+        // Nothing else should arrive
+        let extra = tokio::time::timeout(Duration::from_millis(500), listener.accept()).await;
+        anyhow::Ok((received, extra.is_err()))
+    });
+    let access = LMTPService::init(settings).await?;
+    let later = access
+        .send_after(
+            TestEmail::new(&["later@example.com"]),
+            Duration::from_millis(300),
+        )?
+        .id;
+    let cancelled = access
+        .send_after(
+            TestEmail::new(&["cancelled@example.com"]),
+            Duration::from_millis(100),
+        )?
+        .id;
+    assert!(access.cancel_scheduled(cancelled));
+    assert!(!access.cancel_scheduled(cancelled));
+    access.send(TestEmail::new(&["now@example.com"]))?;
+
+    let (received, nothing_else) = server.await??;
+    assert_eq!(received[0].recipients, vec!["now@example.com"]);
+    assert_eq!(received[1].recipients, vec!["later@example.com"]);
+    assert!(nothing_else);
+    // Already sent
+    assert!(!access.cancel_scheduled(later));
+    Ok(())
+}
@@ -0,0 +1,103 @@
+#![cfg(feature = "smtp")]
+mod common;
+use std::{sync::Arc, time::Duration};
+
+use any_mail::{
+    events::{DropReason, EventKind},
+    load_balancer::{LoadBalancerError, LoadBalancerService, LoadBalancerSettings},
+    no_op::NoOpService,
+    rate_limit::RateLimitSettings,
+    smtp::{SMTPService, SMTPServiceEncryption, SMTPServiceSettings},
+    EmailAccess, MailService, QueueError, ServiceState,
+};
+use common::{smtp_stand_in, TestEmail};
+use tokio::{net::TcpListener, time::timeout};
+
+fn settings(port: u16) -> SMTPServiceSettings {
+    SMTPServiceSettings {
+        port: Some(port),
+        encryption: SMTPServiceEncryption::NONE,
+        ..Default::default()
+    }
+}
+#[tokio::test]
+async fn shared_state() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let mut received = smtp_stand_in(listener);
+
+    let service_state = ServiceState::without_signal_handling();
+    let first = SMTPService::init_with_state(settings(port), service_state.clone()).await?;
+    let second = SMTPService::init_with_state(settings(port), service_state.clone()).await?;
+    let no_op = NoOpService::init_with_state((), service_state.clone()).await?;
+    for access in [
+        first.get_app_state(),
+        second.get_app_state(),
+        no_op.get_app_state(),
+    ] {
+        assert!(Arc::ptr_eq(&access, &service_state));
+    }
+
+    first.send(TestEmail::new(&["a@example.com"]))?;
+    second.send(TestEmail::new(&["b@example.com"]))?;
+    for _ in 0..2 {
+        received.recv().await.unwrap();
+    }
+    // Both workers are still running
+    assert!(timeout(Duration::from_millis(50), service_state.join())
+        .await
+        .is_err());
+
+    service_state.shutdown();
+    timeout(Duration::from_secs(5), service_state.join()).await?;
+    assert!(!first.get_app_state().is_running());
+    Ok(())
+}
+#[tokio::test]
+async fn rate_limited_email_is_answered_on_shutdown() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let mut received = smtp_stand_in(listener);
+    let service_state = ServiceState::without_signal_handling();
+    let settings = LoadBalancerSettings {
+        relays: vec![settings(port).into()],
+        rate_limit: RateLimitSettings {
+            max_emails: 1,
+            period: 3_600_000,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let access = LoadBalancerService::init_with_state(settings, service_state.clone()).await?;
+    let events = access.subscribe();
+
+    access
+        .send_and_wait(TestEmail::new(&["a@example.com"]))
+        .await?;
+    received.recv().await.unwrap();
+    let waiting = {
+        let access = access.clone();
+        tokio::spawn(async move {
+            access
+                .send_and_wait(TestEmail::new(&["b@example.com"]))
+                .await
+        })
+    };
+    while events.recv_async().await?.kind != EventKind::Deferred {}
+
+    service_state.shutdown();
+    let result = timeout(Duration::from_secs(5), waiting).await??;
+    assert!(matches!(
+        result,
+        Err(LoadBalancerError::QueueError(QueueError::Closed))
+    ));
+    let dropped = events.recv_async().await?;
+    assert_eq!(
+        dropped.kind,
+        EventKind::Dropped {
+            reason: DropReason::Shutdown
+        }
+    );
+    timeout(Duration::from_secs(5), service_state.join()).await?;
+    Ok(())
+}
@@ -0,0 +1,32 @@
+//! Run with `cargo test --no-default-features --features smtp,smol_rustls --test smol`
+#![cfg(all(feature = "smol", not(feature = "tokio")))]
+mod common;
+use any_mail::{
+    smtp::{SMTPService, SMTPServiceEncryption, SMTPServiceSettings},
+    EmailAccess, MailService,
+};
+use common::{smtp_stand_in, TestEmail};
+use tokio::net::TcpListener;
+
+#[test]
+fn smtp_on_smol() -> anyhow::Result<()> {
+    // The stand-in is written with Tokio. The service itself only runs on smol
+    let stand_in = tokio::runtime::Runtime::new()?;
+    let (port, mut received) = stand_in.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        anyhow::Ok((port, smtp_stand_in(listener)))
+    })?;
+
+    let settings = SMTPServiceSettings {
+        port: Some(port),
+        encryption: SMTPServiceEncryption::NONE,
+        ..Default::default()
+    };
+    let access = smol::block_on(SMTPService::init(settings))?;
+    access.send(TestEmail::new(&["user@example.com"]))?;
+    // The worker is on smol's global executor so it keeps running outside of block_on
+    let received = received.blocking_recv().unwrap();
+    assert_eq!(received.recipients, vec!["user@example.com"]);
+    Ok(())
+}
@@ -1,3 +1,4 @@
+#![cfg(feature = "smtp")]
 use std::path::PathBuf;
 
 use any_mail::{smtp::SMTPServiceSettings, MailService};
@@ -0,0 +1,106 @@
+#![cfg(feature = "smtp")]
+mod common;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use any_mail::{
+    smtp::{
+        AccessToken, SMTPAuthentication, SMTPService, SMTPServiceEncryption, SMTPServiceSettings,
+    },
+    EmailAccess, MailService, ServiceState,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use common::{smtp_stand_in, TestEmail};
+use tokio::net::TcpListener;
+
+fn settings(port: u16, authentication: SMTPAuthentication) -> SMTPServiceSettings {
+    SMTPServiceSettings {
+        username: "user@example.com".to_owned(),
+        password: "password".into(),
+        authentication,
+        port: Some(port),
+        encryption: SMTPServiceEncryption::NONE,
+        ..Default::default()
+    }
+}
+#[tokio::test]
+async fn no_authentication() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let mut received = smtp_stand_in(listener);
+
+    let access = SMTPService::init(settings(port, SMTPAuthentication::NONE)).await?;
+    access.send(TestEmail::new(&["user@example.com"]))?;
+    assert_eq!(received.recv().await.unwrap().auth, None);
+
+    // Auto does not authenticate without a username
+    let settings = SMTPServiceSettings {
+        username: String::new(),
+        ..settings(port, SMTPAuthentication::Auto)
+    };
+    let access = SMTPService::init(settings).await?;
+    access.send(TestEmail::new(&["user@example.com"]))?;
+    assert_eq!(received.recv().await.unwrap().auth, None);
+    Ok(())
+}
+#[tokio::test]
+async fn plain() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let mut received = smtp_stand_in(listener);
+
+    let access = SMTPService::init(settings(port, SMTPAuthentication::Plain)).await?;
+    access.send(TestEmail::new(&["user@example.com"]))?;
+    let auth = received.recv().await.unwrap().auth.unwrap();
+    let credentials = auth.strip_prefix("AUTH PLAIN ").unwrap();
+    assert_eq!(
+        STANDARD.decode(credentials)?,
+        b"\0user@example.com\0password"
+    );
+    Ok(())
+}
+#[tokio::test]
+async fn xoauth2_refreshes_tokens() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let mut received = smtp_stand_in(listener);
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let provider = {
+        let calls = calls.clone();
+        move || {
+            let call = calls.fetch_add(1, Ordering::SeqCst);
+            // Already expired so every delivery needs a new one
+            async move {
+                Ok(AccessToken {
+                    token: format!("token-{call}"),
+                    expires_at: Some(SystemTime::now()),
+                })
+            }
+        }
+    };
+    let access = SMTPService::init_with_token_provider(
+        settings(port, SMTPAuthentication::XOAuth2),
+        provider,
+        ServiceState::new(),
+    )
+    .await?;
+    assert!(access.get_state().lock().connected);
+
+    for token in ["token-1", "token-2"] {
+        access.send(TestEmail::new(&["user@example.com"]))?;
+        let auth = received.recv().await.unwrap().auth.unwrap();
+        let credentials = auth.strip_prefix("AUTH XOAUTH2 ").unwrap();
+        assert_eq!(
+            String::from_utf8(STANDARD.decode(credentials)?)?,
+            format!("user=user@example.com\x01auth=Bearer {token}\x01\x01")
+        );
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+    Ok(())
+}
@@ -0,0 +1,99 @@
+#![cfg(feature = "smtp")]
+mod common;
+use std::time::Duration;
+
+use any_mail::{
+    smtp::{PoolSettings, SMTPService, SMTPServiceEncryption, SMTPServiceSettings},
+    EmailAccess, MailService,
+};
+use common::{smtp_stand_in, TestEmail};
+use tokio::net::TcpListener;
+
+fn settings(port: u16, pool: PoolSettings) -> SMTPServiceSettings {
+    SMTPServiceSettings {
+        port: Some(port),
+        encryption: SMTPServiceEncryption::NONE,
+        pool,
+        ..Default::default()
+    }
+}
+#[tokio::test]
+async fn concurrent_deliveries() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let mut received = smtp_stand_in(listener);
+    let pool = PoolSettings {
+        max_size: 3,
+        min_idle: 1,
+        ..Default::default()
+    };
+    let access = SMTPService::init(settings(port, pool)).await?;
+
+    let recipients = ["a@example.com", "b@example.com", "c@example.com"];
+    for recipient in recipients {
+        access.send(TestEmail::new(&[recipient]))?;
+    }
+    let mut delivered = Vec::new();
+    for _ in recipients {
+        delivered.extend(received.recv().await.unwrap().recipients);
+    }
+    delivered.sort();
+    assert_eq!(delivered, recipients);
+
+    let pool = access.health().pool.unwrap();
+    assert_eq!(pool.max_size, 3);
+    assert_eq!(pool.min_idle, 1);
+    assert_eq!(pool.replaced, 0);
+    assert!(pool.created_at.is_some());
+    Ok(())
+}
+#[tokio::test]
+async fn max_lifetime_replaces_the_pool() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let mut received = smtp_stand_in(listener);
+    let pool = PoolSettings {
+        max_lifetime: 50,
+        ..Default::default()
+    };
+    let access = SMTPService::init(settings(port, pool)).await?;
+
+    for _ in 0..2 {
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        access.send(TestEmail::new(&["user@example.com"]))?;
+        received.recv().await.unwrap();
+    }
+    let pool = access.health().pool.unwrap();
+    assert_eq!(pool.max_size, 10);
+    assert_eq!(pool.replaced, 2);
+    Ok(())
+}
+#[tokio::test]
+async fn single_connection_keeps_the_order() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let mut received = smtp_stand_in(listener);
+    let access = SMTPService::init(settings(port, PoolSettings::default())).await?;
+    assert_eq!(access.health().pool.unwrap().max_size, 10);
+
+    let pool = PoolSettings {
+        max_size: 1,
+        ..Default::default()
+    };
+    let access = SMTPService::init(settings(port, pool)).await?;
+    let recipients = [
+        "a@example.com",
+        "b@example.com",
+        "c@example.com",
+        "d@example.com",
+        "e@example.com",
+    ];
+    for recipient in recipients {
+        access.send(TestEmail::new(&[recipient]))?;
+    }
+    for recipient in recipients {
+        assert_eq!(received.recv().await.unwrap().recipients, [recipient]);
+    }
+    assert_eq!(access.health().pool.unwrap().max_size, 1);
+    Ok(())
+}
@@ -0,0 +1,95 @@
+#![cfg(feature = "smtp")]
+mod common;
+use any_mail::{
+    smtp::{
+        DkimAlgorithm, DkimSettings, KeySource, SMTPAuthentication, SMTPError, SMTPService,
+        SMTPServiceEncryption, SMTPServiceSettings,
+    },
+    EmailAccess, MailService,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use common::{smtp_stand_in, TestEmail};
+use tokio::net::TcpListener;
+
+fn settings(port: u16, password: &str) -> SMTPServiceSettings {
+    SMTPServiceSettings {
+        username: "user@example.com".to_owned(),
+        password: password.into(),
+        authentication: SMTPAuthentication::Plain,
+        port: Some(port),
+        encryption: SMTPServiceEncryption::NONE,
+        ..Default::default()
+    }
+}
+fn password(auth: Option<String>) -> anyhow::Result<String> {
+    let auth = auth.unwrap();
+    let credentials = STANDARD.decode(auth.strip_prefix("AUTH PLAIN ").unwrap())?;
+    Ok(String::from_utf8(credentials)?
+        .rsplit('\0')
+        .next()
+        .unwrap()
+        .to_owned())
+}
+#[tokio::test]
+async fn reload_switches_servers_and_credentials() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let old_port = listener.local_addr()?.port();
+    let mut old = smtp_stand_in(listener);
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let new_port = listener.local_addr()?.port();
+    let mut new = smtp_stand_in(listener);
+
+    let access = SMTPService::init(settings(old_port, "old-password")).await?;
+    access.send(TestEmail::new(&["a@example.com"]))?;
+    assert_eq!(password(old.recv().await.unwrap().auth)?, "old-password");
+
+    access.reload(settings(new_port, "new-password")).await?;
+    assert_eq!(access.get_settings().port, Some(new_port));
+    access.send(TestEmail::new(&["b@example.com"]))?;
+    let received = new.recv().await.unwrap();
+    assert_eq!(received.recipients, ["b@example.com"]);
+    assert_eq!(password(received.auth)?, "new-password");
+
+    // Nothing listens on the port so the current settings are kept
+    let closed = TcpListener::bind("127.0.0.1:0").await?;
+    let closed_port = closed.local_addr()?.port();
+    drop(closed);
+    assert!(access
+        .reload(settings(closed_port, "other-password"))
+        .await
+        .is_err());
+    assert_eq!(access.get_settings().port, Some(new_port));
+    access.send(TestEmail::new(&["c@example.com"]))?;
+    assert_eq!(new.recv().await.unwrap().recipients, ["c@example.com"]);
+    Ok(())
+}
+#[tokio::test]
+async fn reload_rejects_dkim_changes() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let mut received = smtp_stand_in(listener);
+    let access = SMTPService::init(settings(port, "password")).await?;
+
+    let signed = SMTPServiceSettings {
+        dkim: Some(DkimSettings {
+            selector: "mail".to_owned(),
+            domain: "example.com".to_owned(),
+            algorithm: DkimAlgorithm::Ed25519,
+            private_key: KeySource::Inline("nWGxne/9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2A=".into()),
+            headers: vec!["From".to_owned()],
+            canonicalization: Default::default(),
+        }),
+        ..settings(port, "password")
+    };
+    let result = access.reload(signed).await;
+    assert!(matches!(result, Err(SMTPError::NotReloadable("DKIM"))));
+    assert!(access.get_settings().dkim.is_none());
+    access.send(TestEmail::new(&["a@example.com"]))?;
+    assert!(!received
+        .recv()
+        .await
+        .unwrap()
+        .data
+        .contains("DKIM-Signature"));
+    Ok(())
+}
@@ -0,0 +1,92 @@
+#![cfg(feature = "smtp")]
+mod common;
+use any_mail::{
+    smtp::{
+        ClientCertificate, KeySource, SMTPService, SMTPServiceEncryption, SMTPServiceSettings,
+        TlsSettings,
+    },
+    EmailAccess, MailService,
+};
+use common::{smtp_tls_stand_in, tls_file, TestEmail};
+use tokio::net::TcpListener;
+
+fn settings(port: u16, tls: TlsSettings) -> SMTPServiceSettings {
+    SMTPServiceSettings {
+        port: Some(port),
+        encryption: SMTPServiceEncryption::TLS,
+        tls,
+        ..Default::default()
+    }
+}
+fn private_ca() -> TlsSettings {
+    TlsSettings {
+        root_certificates: Some(KeySource::File(tls_file("ca.pem"))),
+        disable_system_roots: true,
+        server_name: Some("relay.internal".to_owned()),
+        ..Default::default()
+    }
+}
+#[tokio::test]
+async fn private_ca_and_server_name() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let mut received = smtp_tls_stand_in(listener, false);
+
+    // The certificate is not trusted without the CA
+    let untrusted = TlsSettings {
+        root_certificates: None,
+        ..private_ca()
+    };
+    let access = SMTPService::init(settings(port, untrusted)).await?;
+    assert!(!access.get_state().lock().connected);
+
+    let access = SMTPService::init(settings(port, private_ca())).await?;
+    assert!(access.get_state().lock().connected);
+    access.send(TestEmail::new(&["user@example.com"]))?;
+    assert_eq!(
+        received.recv().await.unwrap().recipients,
+        vec!["user@example.com"]
+    );
+    Ok(())
+}
+#[tokio::test]
+async fn client_certificate() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let mut received = smtp_tls_stand_in(listener, true);
+
+    let access = SMTPService::init(settings(port, private_ca())).await?;
+    assert!(!access.get_state().lock().connected);
+
+    let tls = TlsSettings {
+        client_certificate: Some(ClientCertificate {
+            certificate: KeySource::File(tls_file("client.pem")),
+            private_key: KeySource::File(tls_file("client.key")),
+        }),
+        ..private_ca()
+    };
+    let access = SMTPService::init(settings(port, tls)).await?;
+    assert!(access.get_state().lock().connected);
+    access.send(TestEmail::new(&["user@example.com"]))?;
+    assert_eq!(
+        received.recv().await.unwrap().recipients,
+        vec!["user@example.com"]
+    );
+    Ok(())
+}
+#[tokio::test]
+async fn accept_invalid_certs() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let mut received = smtp_tls_stand_in(listener, false);
+
+    let tls = TlsSettings {
+        dangerous_accept_invalid_certs: true,
+        ..Default::default()
+    };
+    let access = SMTPService::init(settings(port, tls)).await?;
+    assert!(access.get_state().lock().connected);
+    access.send(TestEmail::new(&["user@example.com"]))?;
+    received.recv().await.unwrap();
+    Ok(())
+}
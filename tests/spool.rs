@@ -0,0 +1,59 @@
+#![cfg(feature = "lmtp")]
+mod common;
+use std::time::Duration;
+
+use any_mail::{
+    lmtp::{LMTPServerAddress, LMTPService, LMTPServiceSettings},
+    MailService,
+};
+use common::{lmtp_stand_in, TestEmail};
+use tokio::net::TcpListener;
+/// An email left behind by a previous run is delivered on startup and removed once delivered.
+#[tokio::test]
+async fn resumes_after_restart() -> anyhow::Result<()> {
+    let spool = std::env::temp_dir().join(format!("any_mail_spool_test_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&spool);
+    std::fs::create_dir_all(&spool)?;
+    std::fs::write(
+        spool.join("000000000000000000000001-0000000000.eml"),
+        "from sender@example.com\nto left@example.com\n\nSubject: Left Behind\r\n\r\nHello\r\n",
+    )?;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let settings = LMTPServiceSettings {
+        server: LMTPServerAddress::TCP {
+            host: "127.0.0.1".to_owned(),
+            port: Some(listener.local_addr()?.port()),
+        },
+        spool_directory: Some(spool.clone()),
+        ..Default::default()
+    };
+    let server = tokio::spawn(async move {
+        // The first connection is the test connection from init
+        let (stream, _) = listener.accept().await?;
+        lmtp_stand_in(stream, &[]).await?;
+        let (stream, _) = listener.accept().await?;
+        let recovered = lmtp_stand_in(stream, &[]).await?;
+        let (stream, _) = listener.accept().await?;
+        let sent = lmtp_stand_in(stream, &[]).await?;
+        anyhow::Ok((recovered, sent))
+    });
+    let access = LMTPService::init(settings).await?;
+    access
+        .send_and_wait(TestEmail::new(&["user@example.com"]))
+        .await?;
+
+    let (recovered, sent) = server.await??;
+    assert_eq!(recovered.recipients, vec!["left@example.com"]);
+    assert!(recovered.data.contains("Subject: Left Behind"));
+    assert_eq!(sent.recipients, vec!["user@example.com"]);
+    // The recovered email is marked done after its reply is read
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let remaining = std::fs::read_dir(&spool)?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().is_file())
+        .count();
+    assert_eq!(remaining, 0);
+    std::fs::remove_dir_all(&spool)?;
+    Ok(())
+}
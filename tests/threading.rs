@@ -0,0 +1,68 @@
+#![cfg(feature = "lmtp")]
+mod common;
+use any_mail::{
+    email_types::MessageId,
+    lmtp::{LMTPServerAddress, LMTPService, LMTPServiceSettings},
+    EmailAccess, MailService,
+};
+use common::{lmtp_stand_in, TestEmail};
+use tokio::net::TcpListener;
+/// Finds the value of a header in the raw message
+fn header<'a>(data: &'a str, name: &str) -> Option<&'a str> {
+    data.split("\r\n")
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+}
+#[tokio::test]
+async fn message_id_and_threading() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let settings = LMTPServiceSettings {
+        server: LMTPServerAddress::TCP {
+            host: "127.0.0.1".to_owned(),
+            port: Some(listener.local_addr()?.port()),
+        },
+        message_id_domain: Some("tickets.example.com".to_owned()),
+        ..Default::default()
+    };
+    let server = tokio::spawn(async move {
+        // The first connection is the test connection from init
+        let (stream, _) = listener.accept().await?;
+        lmtp_stand_in(stream, &[]).await?;
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            let (stream, _) = listener.accept().await?;
+            received.push(lmtp_stand_in(stream, &[]).await?);
+        }
+        anyhow::Ok(received)
+    });
+    let access = LMTPService::init(settings).await?;
+
+    let original = access.send(TestEmail::new(&["user@example.com"]))?;
+    assert_eq!(original.domain(), Some("tickets.example.com"));
+
+    let mut reply = TestEmail::new(&["user@example.com"]);
+    reply.message_id = Some(MessageId::new("ticket-7.reply-1@tickets.example.com"));
+    reply.in_reply_to = Some(original.clone());
+    reply.references = vec![original.clone()];
+    let reply_id = access.send(reply)?;
+    assert_eq!(reply_id.as_str(), "<ticket-7.reply-1@tickets.example.com>");
+
+    let received = server.await??;
+    assert_eq!(
+        header(&received[0].data, "Message-ID"),
+        Some(original.as_str())
+    );
+    assert_eq!(header(&received[0].data, "In-Reply-To"), None);
+    assert_eq!(
+        header(&received[1].data, "Message-ID"),
+        Some(reply_id.as_str())
+    );
+    assert_eq!(
+        header(&received[1].data, "In-Reply-To"),
+        Some(original.as_str())
+    );
+    assert_eq!(
+        header(&received[1].data, "References"),
+        Some(original.as_str())
+    );
+    Ok(())
+}